@@ -0,0 +1,36 @@
+-- Users, their passkeys, and polls with their options and votes
+CREATE TABLE IF NOT EXISTS users (
+    unique_id UUID PRIMARY KEY,
+    username TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE IF NOT EXISTS passkeys_data (
+    user_id UUID NOT NULL REFERENCES users (unique_id) ON DELETE CASCADE,
+    passkey_data JSONB NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS polls (
+    id SERIAL PRIMARY KEY,
+    title TEXT NOT NULL,
+    creator_id UUID NOT NULL REFERENCES users (unique_id),
+    created_at TEXT NOT NULL,
+    closed BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE TABLE IF NOT EXISTS poll_options (
+    id SERIAL PRIMARY KEY,
+    poll_id INTEGER NOT NULL REFERENCES polls (id) ON DELETE CASCADE,
+    option_text TEXT NOT NULL,
+    votes INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE IF NOT EXISTS votes (
+    user_id UUID NOT NULL REFERENCES users (unique_id),
+    poll_id INTEGER NOT NULL REFERENCES polls (id) ON DELETE CASCADE,
+    option_id INTEGER NOT NULL REFERENCES poll_options (id) ON DELETE CASCADE,
+    voted_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS passkeys_data_user_id_idx ON passkeys_data (user_id);
+CREATE INDEX IF NOT EXISTS poll_options_poll_id_idx ON poll_options (poll_id);
+CREATE INDEX IF NOT EXISTS votes_poll_id_idx ON votes (poll_id);
@@ -0,0 +1,11 @@
+-- Cross-device logins approved from a phone
+CREATE TABLE IF NOT EXISTS qr_login_requests (
+    id UUID PRIMARY KEY,
+    browser_secret_hash TEXT NOT NULL,
+    requester_user_agent TEXT,
+    status TEXT NOT NULL,
+    user_id UUID REFERENCES users (unique_id) ON DELETE CASCADE,
+    approved_at TIMESTAMPTZ,
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
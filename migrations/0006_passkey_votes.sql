@@ -0,0 +1,9 @@
+-- Passkey-confirmed votes and polls restricted to attested security keys
+ALTER TABLE polls ADD COLUMN IF NOT EXISTS require_passkey_vote BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE polls ADD COLUMN IF NOT EXISTS allowed_aaguids UUID[] NOT NULL DEFAULT '{}';
+ALTER TABLE polls ADD COLUMN IF NOT EXISTS allowed_attestation_cas TEXT[] NOT NULL DEFAULT '{}';
+
+ALTER TABLE votes ADD COLUMN IF NOT EXISTS assertion JSONB;
+
+ALTER TABLE passkeys_data ADD COLUMN IF NOT EXISTS attestation_aaguid UUID;
+ALTER TABLE passkeys_data ADD COLUMN IF NOT EXISTS attestation_ca TEXT;
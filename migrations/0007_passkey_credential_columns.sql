@@ -0,0 +1,11 @@
+-- Credential fields kept in typed columns next to the serialized passkey. They stay
+-- nullable so rows from before them can be backfilled from passkey_data at startup.
+ALTER TABLE passkeys_data ADD COLUMN IF NOT EXISTS credential_id TEXT;
+ALTER TABLE passkeys_data ADD COLUMN IF NOT EXISTS counter BIGINT;
+ALTER TABLE passkeys_data ADD COLUMN IF NOT EXISTS backup_eligible BOOLEAN;
+ALTER TABLE passkeys_data ADD COLUMN IF NOT EXISTS backup_state BOOLEAN;
+ALTER TABLE passkeys_data ADD COLUMN IF NOT EXISTS aaguid UUID;
+ALTER TABLE passkeys_data ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ DEFAULT now();
+ALTER TABLE passkeys_data ADD COLUMN IF NOT EXISTS last_used_at TIMESTAMPTZ;
+
+CREATE UNIQUE INDEX IF NOT EXISTS passkeys_data_credential_id_idx ON passkeys_data (credential_id);
@@ -0,0 +1,5 @@
+-- Free-form labels polls can be listed by
+ALTER TABLE polls ADD COLUMN IF NOT EXISTS tags TEXT[] NOT NULL DEFAULT '{}';
+
+CREATE INDEX IF NOT EXISTS polls_tags_idx ON polls USING GIN (tags);
+CREATE INDEX IF NOT EXISTS polls_created_at_idx ON polls (created_at);
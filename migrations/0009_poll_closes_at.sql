@@ -0,0 +1,4 @@
+-- When a poll is scheduled to close, as the text of a UTC timestamp like `created_at`
+ALTER TABLE polls ADD COLUMN IF NOT EXISTS closes_at TEXT;
+
+CREATE INDEX IF NOT EXISTS polls_closes_at_idx ON polls (closes_at);
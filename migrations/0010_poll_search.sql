@@ -0,0 +1,8 @@
+-- Full-text search over poll titles and option texts
+ALTER TABLE polls ADD COLUMN IF NOT EXISTS search_vector tsvector
+    GENERATED ALWAYS AS (to_tsvector('english', title)) STORED;
+ALTER TABLE poll_options ADD COLUMN IF NOT EXISTS search_vector tsvector
+    GENERATED ALWAYS AS (to_tsvector('english', option_text)) STORED;
+
+CREATE INDEX IF NOT EXISTS polls_search_vector_idx ON polls USING GIN (search_vector);
+CREATE INDEX IF NOT EXISTS poll_options_search_vector_idx ON poll_options USING GIN (search_vector);
@@ -0,0 +1,18 @@
+-- One vote per user and poll, and polls that let voters change their vote
+ALTER TABLE polls ADD COLUMN IF NOT EXISTS allow_vote_change BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Keep each user's first vote on a poll and uncount the others
+WITH extra AS (
+    DELETE FROM votes v
+    USING votes earlier
+    WHERE earlier.user_id = v.user_id
+      AND earlier.poll_id = v.poll_id
+      AND (earlier.voted_at, earlier.ctid) < (v.voted_at, v.ctid)
+    RETURNING v.option_id
+)
+UPDATE poll_options o
+SET votes = GREATEST(o.votes - extra_votes.n, 0)
+FROM (SELECT option_id, COUNT(*) AS n FROM extra GROUP BY option_id) extra_votes
+WHERE o.id = extra_votes.option_id;
+
+CREATE UNIQUE INDEX IF NOT EXISTS votes_user_poll_idx ON votes (user_id, poll_id);
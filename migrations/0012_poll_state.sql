@@ -0,0 +1,7 @@
+-- Poll lifecycle: draft, open, closed and archived, replacing the closed flag
+ALTER TABLE polls ADD COLUMN IF NOT EXISTS state TEXT NOT NULL DEFAULT 'open'
+    CHECK (state IN ('draft', 'open', 'closed', 'archived'));
+UPDATE polls SET state = 'closed' WHERE closed;
+ALTER TABLE polls DROP COLUMN closed;
+
+CREATE INDEX IF NOT EXISTS polls_state_idx ON polls (state);
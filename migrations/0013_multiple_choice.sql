@@ -0,0 +1,22 @@
+-- Multiple-choice polls: each vote selects between min_selections and max_selections
+-- options. `votes` keeps one ballot per user and poll; its options move to
+-- vote_selections.
+ALTER TABLE polls ADD COLUMN IF NOT EXISTS min_selections INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE polls ADD COLUMN IF NOT EXISTS max_selections INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE polls ADD CONSTRAINT polls_selections_check
+    CHECK (min_selections >= 1 AND max_selections >= min_selections);
+
+CREATE TABLE IF NOT EXISTS vote_selections (
+    user_id UUID NOT NULL,
+    poll_id INTEGER NOT NULL,
+    option_id INTEGER NOT NULL REFERENCES poll_options (id) ON DELETE CASCADE,
+    PRIMARY KEY (user_id, poll_id, option_id),
+    FOREIGN KEY (user_id, poll_id) REFERENCES votes (user_id, poll_id) ON DELETE CASCADE
+);
+
+INSERT INTO vote_selections (user_id, poll_id, option_id)
+SELECT user_id, poll_id, option_id FROM votes;
+
+ALTER TABLE votes DROP COLUMN option_id;
+
+CREATE INDEX IF NOT EXISTS vote_selections_option_id_idx ON vote_selections (option_id);
@@ -0,0 +1,71 @@
+-- Users, passkeys, polls, votes, roles and sessions for the SQLite backend. UUIDs are
+-- stored as text and list columns as JSON arrays.
+CREATE TABLE IF NOT EXISTS users (
+    unique_id TEXT PRIMARY KEY,
+    username TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE IF NOT EXISTS passkeys_data (
+    user_id TEXT NOT NULL REFERENCES users (unique_id) ON DELETE CASCADE,
+    passkey_data TEXT NOT NULL,
+    credential_id TEXT NOT NULL UNIQUE,
+    counter INTEGER NOT NULL,
+    backup_eligible INTEGER NOT NULL,
+    backup_state INTEGER NOT NULL,
+    aaguid TEXT,
+    attestation_aaguid TEXT,
+    attestation_ca TEXT,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used_at TEXT
+);
+
+CREATE INDEX IF NOT EXISTS passkeys_data_user_id_idx ON passkeys_data (user_id);
+
+CREATE TABLE IF NOT EXISTS polls (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    title TEXT NOT NULL,
+    creator_id TEXT NOT NULL REFERENCES users (unique_id),
+    created_at TEXT NOT NULL,
+    closed INTEGER NOT NULL DEFAULT 0,
+    require_passkey_vote INTEGER NOT NULL DEFAULT 0,
+    allowed_aaguids TEXT NOT NULL DEFAULT '[]',
+    allowed_attestation_cas TEXT NOT NULL DEFAULT '[]'
+);
+
+CREATE TABLE IF NOT EXISTS poll_options (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    poll_id INTEGER NOT NULL REFERENCES polls (id) ON DELETE CASCADE,
+    option_text TEXT NOT NULL,
+    votes INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE INDEX IF NOT EXISTS poll_options_poll_id_idx ON poll_options (poll_id);
+
+CREATE TABLE IF NOT EXISTS votes (
+    user_id TEXT NOT NULL REFERENCES users (unique_id),
+    poll_id INTEGER NOT NULL REFERENCES polls (id) ON DELETE CASCADE,
+    option_id INTEGER NOT NULL REFERENCES poll_options (id) ON DELETE CASCADE,
+    voted_at TEXT NOT NULL,
+    assertion TEXT
+);
+
+CREATE INDEX IF NOT EXISTS votes_poll_id_idx ON votes (poll_id);
+
+CREATE TABLE IF NOT EXISTS role_permissions (
+    role TEXT NOT NULL,
+    permission TEXT NOT NULL,
+    PRIMARY KEY (role, permission)
+);
+
+CREATE TABLE IF NOT EXISTS user_roles (
+    user_id TEXT NOT NULL REFERENCES users (unique_id) ON DELETE CASCADE,
+    role TEXT NOT NULL,
+    PRIMARY KEY (user_id, role)
+);
+
+-- Server-side session state for the session cookie, expiring at `valid_until` (unix seconds)
+CREATE TABLE IF NOT EXISTS sessions (
+    session_key TEXT PRIMARY KEY,
+    session_state TEXT NOT NULL,
+    valid_until INTEGER NOT NULL
+);
@@ -0,0 +1,4 @@
+-- Free-form labels polls can be listed by, as a JSON array
+ALTER TABLE polls ADD COLUMN tags TEXT NOT NULL DEFAULT '[]';
+
+CREATE INDEX IF NOT EXISTS polls_created_at_idx ON polls (created_at);
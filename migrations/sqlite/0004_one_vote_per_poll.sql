@@ -0,0 +1,24 @@
+-- One vote per user and poll, and polls that let voters change their vote
+ALTER TABLE polls ADD COLUMN allow_vote_change INTEGER NOT NULL DEFAULT 0;
+
+-- Keep each user's first vote on a poll and uncount the others
+UPDATE poll_options SET votes = MAX(votes - (
+    SELECT COUNT(*) FROM votes v
+    WHERE v.option_id = poll_options.id
+      AND EXISTS (
+          SELECT 1 FROM votes earlier
+          WHERE earlier.user_id = v.user_id
+            AND earlier.poll_id = v.poll_id
+            AND (earlier.voted_at, earlier.rowid) < (v.voted_at, v.rowid)
+      )
+), 0);
+
+DELETE FROM votes
+WHERE EXISTS (
+    SELECT 1 FROM votes earlier
+    WHERE earlier.user_id = votes.user_id
+      AND earlier.poll_id = votes.poll_id
+      AND (earlier.voted_at, earlier.rowid) < (votes.voted_at, votes.rowid)
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS votes_user_poll_idx ON votes (user_id, poll_id);
@@ -0,0 +1,35 @@
+-- Multiple-choice polls: each vote selects between min_selections and max_selections
+-- options. `votes` keeps one ballot per user and poll; its options move to
+-- vote_selections. SQLite cannot drop a referencing column, so `votes` is rebuilt.
+ALTER TABLE polls ADD COLUMN min_selections INTEGER NOT NULL DEFAULT 1 CHECK (min_selections >= 1);
+ALTER TABLE polls ADD COLUMN max_selections INTEGER NOT NULL DEFAULT 1 CHECK (max_selections >= min_selections);
+
+ALTER TABLE votes RENAME TO single_choice_votes;
+DROP INDEX votes_poll_id_idx;
+DROP INDEX votes_user_poll_idx;
+
+CREATE TABLE votes (
+    user_id TEXT NOT NULL REFERENCES users (unique_id),
+    poll_id INTEGER NOT NULL REFERENCES polls (id) ON DELETE CASCADE,
+    voted_at TEXT NOT NULL,
+    assertion TEXT
+);
+INSERT INTO votes (user_id, poll_id, voted_at, assertion)
+SELECT user_id, poll_id, voted_at, assertion FROM single_choice_votes;
+
+CREATE INDEX votes_poll_id_idx ON votes (poll_id);
+CREATE UNIQUE INDEX votes_user_poll_idx ON votes (user_id, poll_id);
+
+CREATE TABLE vote_selections (
+    user_id TEXT NOT NULL,
+    poll_id INTEGER NOT NULL,
+    option_id INTEGER NOT NULL REFERENCES poll_options (id) ON DELETE CASCADE,
+    PRIMARY KEY (user_id, poll_id, option_id),
+    FOREIGN KEY (user_id, poll_id) REFERENCES votes (user_id, poll_id) ON DELETE CASCADE
+);
+INSERT INTO vote_selections (user_id, poll_id, option_id)
+SELECT user_id, poll_id, option_id FROM single_choice_votes;
+
+DROP TABLE single_choice_votes;
+
+CREATE INDEX IF NOT EXISTS vote_selections_option_id_idx ON vote_selections (option_id);
@@ -0,0 +1,505 @@
+/*!
+The API driven in process against [MemoryStore](crate::db_operations_repo::memory_store::MemoryStore),
+with a software passkey standing in for the browser and authenticator.
+*/
+
+use std::collections::BTreeMap;
+
+use actix_http::Request;
+use actix_session::SessionMiddleware;
+use actix_web::{
+    cookie::{Cookie, Key},
+    dev::{Service, ServiceResponse},
+    http::{Method, StatusCode},
+    test, web, App,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, Utc};
+use openssl::{
+    bn::{BigNum, BigNumContext},
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
+    sign::Signer,
+};
+use rand::RngCore;
+use serde_cbor::Value as Cbor;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    attestation::AttestationTrust,
+    db::db::ReconnectBackoff,
+    db_operations_repo::{poll_repo::PollState, store::NewPoll},
+    health::Readiness,
+    handlers::polls_handlers::vote_challenge,
+    pow::ProofOfWork,
+    rbac::Role,
+    routes,
+    startup::{memory_stores, webauthn, Stores, UserData},
+    tokens::TokenSigner,
+    web_socket_handlers::start_connection::Chat,
+};
+
+const ORIGIN: &str = "http://localhost:3000";
+const SESSION_COOKIE: &str = "webauthnrs";
+
+/**
+A platform authenticator in software: one ES256 credential, "none" attestation and
+user verification on every ceremony.
+*/
+struct SoftPasskey {
+    key: PKey<Private>,
+    credential_id: Vec<u8>,
+    counter: u32,
+}
+
+impl SoftPasskey {
+    fn new() -> Self {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let mut credential_id = vec![0u8; 32];
+        rand::thread_rng().fill_bytes(&mut credential_id);
+        SoftPasskey {
+            key: PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap(),
+            credential_id,
+            counter: 0,
+        }
+    }
+
+    // The public key as a COSE EC2 key
+    fn cose_key(&self) -> Vec<u8> {
+        let ec = self.key.ec_key().unwrap();
+        let (mut x, mut y) = (BigNum::new().unwrap(), BigNum::new().unwrap());
+        let mut ctx = BigNumContext::new().unwrap();
+        ec.public_key().affine_coordinates(ec.group(), &mut x, &mut y, &mut ctx).unwrap();
+        let key = BTreeMap::from([
+            (Cbor::Integer(1), Cbor::Integer(2)),
+            (Cbor::Integer(3), Cbor::Integer(-7)),
+            (Cbor::Integer(-1), Cbor::Integer(1)),
+            (Cbor::Integer(-2), Cbor::Bytes(x.to_vec_padded(32).unwrap())),
+            (Cbor::Integer(-3), Cbor::Bytes(y.to_vec_padded(32).unwrap())),
+        ]);
+        serde_cbor::to_vec(&Cbor::Map(key)).unwrap()
+    }
+
+    fn authenticator_data(&self, flags: u8, attested: bool) -> Vec<u8> {
+        let mut data = Sha256::digest(b"localhost").to_vec();
+        data.push(flags);
+        data.extend_from_slice(&self.counter.to_be_bytes());
+        if attested {
+            data.extend_from_slice(&[0u8; 16]);
+            data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+            data.extend_from_slice(&self.credential_id);
+            data.extend_from_slice(&self.cose_key());
+        }
+        data
+    }
+
+    fn client_data(kind: &str, options: &Value) -> Vec<u8> {
+        let challenge = options["publicKey"]["challenge"].as_str().unwrap();
+        json!({ "type": kind, "challenge": challenge, "origin": ORIGIN, "crossOrigin": false })
+            .to_string()
+            .into_bytes()
+    }
+
+    // Answer a `CreationChallengeResponse`
+    fn register(&self, options: &Value) -> Value {
+        let client_data = Self::client_data("webauthn.create", options);
+        // User present, user verified, attested credential data included
+        let auth_data = self.authenticator_data(0x45, true);
+        let attestation = Cbor::Map(BTreeMap::from([
+            (Cbor::Text("fmt".into()), Cbor::Text("none".into())),
+            (Cbor::Text("attStmt".into()), Cbor::Map(BTreeMap::new())),
+            (Cbor::Text("authData".into()), Cbor::Bytes(auth_data)),
+        ]));
+        let id = URL_SAFE_NO_PAD.encode(&self.credential_id);
+        json!({
+            "id": id,
+            "rawId": id,
+            "type": "public-key",
+            "extensions": {},
+            "response": {
+                "attestationObject": URL_SAFE_NO_PAD.encode(serde_cbor::to_vec(&attestation).unwrap()),
+                "clientDataJSON": URL_SAFE_NO_PAD.encode(client_data),
+            },
+        })
+    }
+
+    // Answer a `RequestChallengeResponse`
+    fn assert(&mut self, options: &Value) -> Value {
+        self.counter += 1;
+        let client_data = Self::client_data("webauthn.get", options);
+        let auth_data = self.authenticator_data(0x05, false);
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key).unwrap();
+        signer.update(&auth_data).unwrap();
+        signer.update(&Sha256::digest(&client_data)).unwrap();
+        let id = URL_SAFE_NO_PAD.encode(&self.credential_id);
+        json!({
+            "id": id,
+            "rawId": id,
+            "type": "public-key",
+            "extensions": {},
+            "response": {
+                "authenticatorData": URL_SAFE_NO_PAD.encode(auth_data),
+                "clientDataJSON": URL_SAFE_NO_PAD.encode(client_data),
+                "signature": URL_SAFE_NO_PAD.encode(signer.sign_to_vec().unwrap()),
+            },
+        })
+    }
+}
+
+// A browser's view of the API: keeps the session cookie between requests
+struct Browser<S> {
+    app: S,
+    cookie: Option<Cookie<'static>>,
+    stores: Stores,
+}
+
+impl<S> Browser<S>
+where
+    S: Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    async fn send(&mut self, method: Method, path: &str, body: Option<Value>) -> (StatusCode, Value) {
+        let mut req = test::TestRequest::default().method(method).uri(path);
+        if let Some(cookie) = &self.cookie {
+            req = req.cookie(cookie.clone());
+        }
+        if let Some(body) = body {
+            req = req.set_json(body);
+        }
+        let resp = test::call_service(&self.app, req.to_request()).await;
+        if let Some(cookie) = resp.response().cookies().find(|c| c.name() == SESSION_COOKIE) {
+            self.cookie = Some(cookie.into_owned());
+        }
+        let status = resp.status();
+        let body = test::read_body(resp).await;
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    async fn post(&mut self, path: &str, body: Value) -> (StatusCode, Value) {
+        self.send(Method::POST, path, Some(body)).await
+    }
+
+    async fn get(&mut self, path: &str) -> (StatusCode, Value) {
+        self.send(Method::GET, path, None).await
+    }
+
+    async fn register(&mut self, username: &str, passkey: &SoftPasskey) {
+        let (status, options) = self.post(&format!("/register/start/{}", username), json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = self.post("/register/finish", passkey.register(&options)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    async fn login(&mut self, username: &str, passkey: &mut SoftPasskey) -> StatusCode {
+        let (status, options) = self.post(&format!("/login/start/{}", username), json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+        self.post("/login/finish", passkey.assert(&options)).await.0
+    }
+
+    // Register `username` and log in with the new passkey
+    async fn sign_up(&mut self, username: &str) -> SoftPasskey {
+        let mut passkey = SoftPasskey::new();
+        self.register(username, &passkey).await;
+        assert_eq!(self.login(username, &mut passkey).await, StatusCode::OK);
+        passkey
+    }
+
+    // Create a poll as the logged-in user, returning its id
+    async fn create_poll(&mut self, poll: Value) -> i64 {
+        let (status, poll_id) = self.post("/poll/new", poll).await;
+        assert_eq!(status, StatusCode::CREATED);
+        poll_id.as_i64().unwrap()
+    }
+}
+
+// The server's app on fresh in-memory stores, with no database behind it
+async fn browser() -> Browser<impl Service<Request, Response = ServiceResponse, Error = actix_web::Error>> {
+    let stores = memory_stores();
+    let app = test::init_service(
+        App::new()
+            .wrap(SessionMiddleware::builder(stores.session.clone(), Key::generate()).cookie_name(SESSION_COOKIE.to_string()).cookie_secure(false).build())
+            .app_data(webauthn())
+            .app_data(web::Data::new(UserData { pool: None, reconnect: ReconnectBackoff::from_env() }))
+            .app_data(stores.users.clone())
+            .app_data(stores.polls.clone())
+            .app_data(stores.roles.clone())
+            .app_data(stores.health.clone())
+            .app_data(web::Data::new(stores.session.clone()))
+            .app_data(web::Data::new(Readiness::default()))
+            .app_data(web::Data::new(Chat::new()))
+            .app_data(web::Data::new(ProofOfWork::from_env()))
+            .app_data(web::Data::new(TokenSigner::from_env()))
+            .app_data(web::Data::new(AttestationTrust::from_env()))
+            .configure(routes),
+    )
+    .await;
+    Browser { app, cookie: None, stores }
+}
+
+#[actix_web::test]
+async fn registers_logs_in_creates_a_poll_and_votes() {
+    let mut browser = browser().await;
+    let mut passkey = SoftPasskey::new();
+    browser.register("alice", &passkey).await;
+    assert_eq!(browser.login("alice", &mut passkey).await, StatusCode::OK);
+
+    let (status, poll_id) = browser.post("/poll/new", json!({ "title": "Lunch", "options": ["Pizza", "Sushi"] })).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let poll_id = poll_id.as_i64().unwrap();
+
+    let (status, _) = browser.post(&format!("/polls/{}/vote", poll_id), json!({ "option_text": "Sushi" })).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = browser.post(&format!("/polls/{}/vote", poll_id), json!({ "option_text": "Pizza" })).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    let (status, poll) = browser.get(&format!("/polls/{}", poll_id)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(poll["voters"], 1);
+    assert_eq!(poll["options"], json!([{ "option_text": "Pizza", "votes": 0 }, { "option_text": "Sushi", "votes": 1 }]));
+}
+
+#[actix_web::test]
+async fn refuses_a_login_signed_by_another_key() {
+    let mut browser = browser().await;
+    browser.register("bob", &SoftPasskey::new()).await;
+    assert!(!browser.login("bob", &mut SoftPasskey::new()).await.is_success());
+
+    let (status, _) = browser.post("/poll/new", json!({ "title": "Lunch", "options": ["Pizza"] })).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn answers_503_for_the_postgres_only_endpoints() {
+    let mut browser = browser().await;
+    let (status, _) = browser.post("/auth/token/refresh", json!({ "refresh_token": "unknown" })).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    let (status, _) = browser.get("/readyz").await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn lets_a_moderator_close_but_not_reopen_or_archive_another_users_poll() {
+    let mut browser = browser().await;
+    let (mut alice, mut bob) = (SoftPasskey::new(), SoftPasskey::new());
+    browser.register("alice", &alice).await;
+    browser.register("bob", &bob).await;
+    let bob_id = browser.stores.users.find_unique_id_by_username("bob").await.unwrap().unwrap();
+    browser.stores.roles.assign_role(&bob_id, Role::Moderator).await.unwrap();
+
+    assert_eq!(browser.login("alice", &mut alice).await, StatusCode::OK);
+    let (_, poll_id) = browser.post("/poll/new", json!({ "title": "Lunch", "options": ["Pizza", "Sushi"] })).await;
+    let poll_id = poll_id.as_i64().unwrap();
+
+    assert_eq!(browser.login("bob", &mut bob).await, StatusCode::OK);
+    let (status, _) = browser.post(&format!("/polls/{}/close", poll_id), json!({ "reason": "Off topic" })).await;
+    assert_eq!(status, StatusCode::OK);
+    for transition in ["reopen", "archive"] {
+        let (status, _) = browser.post(&format!("/polls/{}/{}", poll_id, transition), json!({})).await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "{}", transition);
+    }
+
+    assert_eq!(browser.login("alice", &mut alice).await, StatusCode::OK);
+    let (status, _) = browser.post(&format!("/polls/{}/reopen", poll_id), json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn refuses_a_poll_with_the_same_option_twice() {
+    let mut browser = browser().await;
+    let mut passkey = SoftPasskey::new();
+    browser.register("carol", &passkey).await;
+    assert_eq!(browser.login("carol", &mut passkey).await, StatusCode::OK);
+
+    let poll = json!({ "title": "Lunch", "options": ["Pizza", "Sushi", "Pizza"], "max_selections": 3 });
+    let (status, _) = browser.post("/poll/new", poll).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let poll = json!({ "title": "Lunch", "options": ["Pizza", "Sushi", "pizza"], "max_selections": 3 });
+    let (status, _) = browser.post("/poll/new", poll).await;
+    assert_eq!(status, StatusCode::CREATED);
+}
+
+#[actix_web::test]
+async fn binds_a_confirmed_vote_to_the_poll_and_options_signed() {
+    let mut browser = browser().await;
+    let mut passkey = browser.sign_up("dave").await;
+    let poll_id = browser.create_poll(json!({ "title": "Lunch", "options": ["Pizza", "Sushi"], "require_passkey_vote": true })).await;
+    let vote = format!("/polls/{}/vote", poll_id);
+
+    let (status, body) = browser.post(&vote, json!({ "option_text": "Sushi" })).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["error"], "vote_confirmation_required");
+
+    let (status, confirmation) = browser.post(&format!("/polls/{}/vote/challenge", poll_id), json!({ "option_text": "Sushi" })).await;
+    assert_eq!(status, StatusCode::OK);
+    let sushi = browser.stores.polls.get_option_id_by_text_and_poll_id("Sushi", poll_id as i32).await.unwrap().unwrap();
+    let expected = vote_challenge(poll_id as i32, &[sushi], confirmation["nonce"].as_str().unwrap());
+    assert_eq!(confirmation["challenge"]["publicKey"]["challenge"], URL_SAFE_NO_PAD.encode(expected));
+    let assertion = passkey.assert(&confirmation["challenge"]);
+
+    let (status, _) = browser.post(&vote, json!({ "option_text": "Pizza", "assertion": assertion })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = browser.post(&vote, json!({ "option_text": "Sushi", "assertion": assertion })).await;
+    assert_eq!(status, StatusCode::OK);
+    // The confirmation is used up with the vote
+    let (status, _) = browser.post(&vote, json!({ "option_text": "Sushi", "assertion": assertion })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (_, poll) = browser.get(&format!("/polls/{}", poll_id)).await;
+    assert_eq!(poll["options"], json!([{ "option_text": "Pizza", "votes": 0 }, { "option_text": "Sushi", "votes": 1 }]));
+}
+
+#[actix_web::test]
+async fn refuses_a_confirmed_vote_with_no_confirmation_started() {
+    let mut browser = browser().await;
+    let mut passkey = browser.sign_up("erin").await;
+    let poll_id = browser.create_poll(json!({ "title": "Lunch", "options": ["Pizza", "Sushi"], "require_passkey_vote": true })).await;
+
+    // An assertion over a login challenge, never bound to a vote
+    let (_, options) = browser.post("/reauth/start", json!({})).await;
+    let assertion = passkey.assert(&options);
+    let (status, _) = browser.post(&format!("/polls/{}/vote", poll_id), json!({ "option_text": "Sushi", "assertion": assertion })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (_, poll) = browser.get(&format!("/polls/{}", poll_id)).await;
+    assert_eq!(poll["voters"], 0);
+}
+
+#[actix_web::test]
+async fn takes_votes_only_while_a_poll_is_open() {
+    let mut browser = browser().await;
+    browser.sign_up("frank").await;
+    let poll_id = browser.create_poll(json!({ "title": "Lunch", "options": ["Pizza", "Sushi"], "allow_vote_change": true })).await;
+    let vote = format!("/polls/{}/vote", poll_id);
+    let transition = |name: &str| format!("/polls/{}/{}", poll_id, name);
+
+    let (status, _) = browser.post(&vote, json!({ "option_text": "Pizza" })).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = browser.post(&transition("close"), json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, body) = browser.post(&vote, json!({ "option_text": "Sushi" })).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["error"], "poll_not_open");
+    assert_eq!(body["state"], "closed");
+    let (status, _) = browser.send(Method::DELETE, &vote, None).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    let (status, _) = browser.post(&transition("reopen"), json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = browser.post(&vote, json!({ "option_text": "Sushi" })).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = browser.post(&transition("archive"), json!({})).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    browser.post(&transition("close"), json!({})).await;
+    let (status, _) = browser.post(&transition("archive"), json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, body) = browser.post(&vote, json!({ "option_text": "Pizza" })).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["state"], "archived");
+    let (status, body) = browser.post(&transition("reset"), json!({})).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["error"], "invalid_poll_state");
+
+    // The archived results are left as they were
+    let (_, poll) = browser.get(&format!("/polls/{}", poll_id)).await;
+    assert_eq!(poll["state"], "archived");
+    assert_eq!(poll["options"], json!([{ "option_text": "Pizza", "votes": 0 }, { "option_text": "Sushi", "votes": 1 }]));
+}
+
+#[actix_web::test]
+async fn retracts_a_vote_so_it_can_be_cast_again() {
+    let mut browser = browser().await;
+    browser.sign_up("grace").await;
+    let poll_id = browser.create_poll(json!({ "title": "Lunch", "options": ["Pizza", "Sushi"] })).await;
+    let vote = format!("/polls/{}/vote", poll_id);
+
+    let (status, _) = browser.send(Method::DELETE, &vote, None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    browser.post(&vote, json!({ "option_text": "Pizza" })).await;
+    let (status, _) = browser.send(Method::DELETE, &vote, None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, poll) = browser.get(&format!("/polls/{}", poll_id)).await;
+    assert_eq!(poll["voters"], 0);
+    assert_eq!(poll["options"], json!([{ "option_text": "Pizza", "votes": 0 }, { "option_text": "Sushi", "votes": 0 }]));
+
+    // Without vote changes allowed, retracting is the way to vote differently
+    let (status, _) = browser.post(&vote, json!({ "option_text": "Sushi" })).await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, poll) = browser.get(&format!("/polls/{}", poll_id)).await;
+    assert_eq!(poll["voters"], 1);
+    assert_eq!(poll["options"], json!([{ "option_text": "Pizza", "votes": 0 }, { "option_text": "Sushi", "votes": 1 }]));
+}
+
+#[actix_web::test]
+async fn counts_each_option_and_voter_of_a_multiple_choice_vote() {
+    let mut browser = browser().await;
+    browser.sign_up("heidi").await;
+    let poll_id = browser.create_poll(json!({
+        "title": "Toppings",
+        "options": ["Cheese", "Ham", "Olives", "Pineapple"],
+        "max_selections": 3,
+    }))
+    .await;
+    let vote = format!("/polls/{}/vote", poll_id);
+
+    for selection in [json!([]), json!(["Cheese", "Ham", "Olives", "Pineapple"])] {
+        let (status, body) = browser.post(&vote, json!({ "option_texts": selection })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", selection);
+        assert_eq!(body["error"], "invalid_selection");
+        assert_eq!((body["min_selections"].as_i64(), body["max_selections"].as_i64()), (Some(1), Some(3)));
+    }
+
+    let (status, _) = browser.post(&vote, json!({ "option_texts": ["Olives", "Cheese"] })).await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, poll) = browser.get(&format!("/polls/{}", poll_id)).await;
+    assert_eq!(poll["voters"], 1);
+    assert_eq!(
+        poll["options"],
+        json!([
+            { "option_text": "Cheese", "votes": 1 },
+            { "option_text": "Ham", "votes": 0 },
+            { "option_text": "Olives", "votes": 1 },
+            { "option_text": "Pineapple", "votes": 0 },
+        ])
+    );
+}
+
+#[actix_web::test]
+async fn stops_taking_votes_at_the_closing_time() {
+    let mut browser = browser().await;
+    browser.sign_up("ivan").await;
+    let (status, _) = browser
+        .post("/poll/new", json!({ "title": "Late", "options": ["Yes", "No"], "closes_at": "2020-01-01T00:00:00Z" }))
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // A poll whose closing time has passed since it was created
+    let ivan = browser.stores.users.find_unique_id_by_username("ivan").await.unwrap().unwrap();
+    let options = ["Yes".to_string(), "No".to_string()];
+    let poll_id = browser.stores.polls.create_poll(NewPoll {
+        title: "Lunch",
+        creator_id: ivan,
+        options: &options,
+        require_passkey_vote: false,
+        allowed_aaguids: &[],
+        allowed_attestation_cas: &[],
+        tags: &[],
+        closes_at: Some(Utc::now().naive_utc() - Duration::minutes(1)),
+        allow_vote_change: false,
+        min_selections: 1,
+        max_selections: 1,
+        state: PollState::Open,
+    })
+    .await
+    .unwrap();
+    let (status, body) = browser.post(&format!("/polls/{}/vote", poll_id), json!({ "option_text": "Yes" })).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!((&body["error"], &body["state"]), (&json!("poll_not_open"), &json!("closed")));
+
+    let poll_id = browser
+        .create_poll(json!({ "title": "Dinner", "options": ["Yes", "No"], "closes_at": Utc::now() + Duration::hours(1) }))
+        .await;
+    let (status, _) = browser.post(&format!("/polls/{}/vote", poll_id), json!({ "option_text": "Yes" })).await;
+    assert_eq!(status, StatusCode::OK);
+}
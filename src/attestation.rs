@@ -0,0 +1,55 @@
+use std::env;
+use std::fs;
+
+use uuid::Uuid;
+use webauthn_rs::prelude::{AttestationCaList, AttestedPasskey};
+use webauthn_rs_core::{attestation::verify_attestation_ca_chain, proto::AttestationMetadata};
+
+// What a verified attestation says about the authenticator behind a credential
+pub(crate) struct VerifiedAttestation {
+    pub(crate) aaguid: Option<Uuid>,
+    // Hex SHA-256 of the DER of the root CA the attestation chained to
+    pub(crate) ca_fingerprint: String,
+}
+
+/**
+Attestation roots trusted when registering security keys. Every PEM certificate in
+the comma-separated `ATTESTATION_CA_PATHS` is trusted for any device it signs; polls
+then restrict voting to particular CAs or AAGUIDs. Without any roots configured,
+security key registration is disabled.
+*/
+pub(crate) struct AttestationTrust {
+    pub(crate) ca_list: AttestationCaList,
+}
+
+impl AttestationTrust {
+    pub(crate) fn from_env() -> Self {
+        let mut ca_list = AttestationCaList::default();
+        if let Ok(paths) = env::var("ATTESTATION_CA_PATHS") {
+            for path in paths.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+                let pem = fs::read(path).expect("Failed to read attestation CA");
+                let ca = AttestationCaList::try_from(pem.as_slice()).expect("Invalid attestation CA");
+                ca_list.union(&ca);
+            }
+        }
+        AttestationTrust { ca_list }
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        !self.ca_list.is_empty()
+    }
+
+    // Find the trusted root and device model of a credential registered with attestation
+    pub(crate) fn verify(&self, passkey: &AttestedPasskey) -> Option<VerifiedAttestation> {
+        let attestation = passkey.attestation();
+        let ca = verify_attestation_ca_chain(&attestation.data, &self.ca_list, false).ok()??;
+        let aaguid = match attestation.metadata {
+            AttestationMetadata::Packed { aaguid } | AttestationMetadata::Tpm { aaguid, .. } => Some(aaguid),
+            _ => None,
+        };
+        Some(VerifiedAttestation {
+            aaguid,
+            ca_fingerprint: hex::encode(ca.get_kid().ok()?),
+        })
+    }
+}
@@ -1,19 +1,189 @@
-use tokio_postgres::{Client,  Error};
+use actix_web::{HttpResponse, ResponseError};
+use deadpool_postgres::{Manager, ManagerConfig, Object, Pool, PoolError, RecyclingMethod, Runtime, TimeoutType};
+use tokio_postgres::{config::SslMode, Client};
 use postgres_native_tls::MakeTlsConnector;
-use native_tls::TlsConnector;
+use native_tls::{Certificate, Identity, TlsConnector};
 use std::env;
+use std::fs;
+use std::ops::{Deref, DerefMut};
+use std::time::Duration;
 use dotenv::dotenv;
+use thiserror::Error;
 
-pub async fn connect_db() -> Result<Client, Error> {
+#[derive(Debug, Error)]
+#[error("Database unavailable")]
+pub struct DatabaseUnavailable;
+
+impl ResponseError for DatabaseUnavailable {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::ServiceUnavailable().body(self.to_string())
+    }
+}
+
+/**
+A connection checked out of the pool for one operation. It derefs to the
+`tokio_postgres::Client` the repositories work with and goes back to the pool
+when dropped.
+*/
+pub struct PooledClient(pub(crate) Object);
+
+impl Deref for PooledClient {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        &self.0
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut Client {
+        &mut self.0
+    }
+}
+
+// How the connection to Postgres is secured, named after libpq's `sslmode`
+#[derive(Clone, Copy, PartialEq)]
+enum TlsMode {
+    // Plain TCP only
+    Disable,
+    // TLS when the server offers it, with a trusted certificate for the host
+    Prefer,
+    // TLS always, without verifying the server certificate
+    Require,
+    // TLS always, with a certificate signed by a trusted CA but for any host name
+    VerifyCa,
+    // TLS always, with a trusted certificate for the host connected to
+    VerifyFull,
+}
+
+impl TlsMode {
+    // `None` when `DB_SSLMODE` is unset, which leaves the `sslmode` of `DATABASE_URL` in charge
+    fn from_env() -> Option<Self> {
+        match env::var("DB_SSLMODE").as_deref() {
+            Err(_) => None,
+            Ok("disable") => Some(TlsMode::Disable),
+            Ok("prefer") => Some(TlsMode::Prefer),
+            Ok("require") => Some(TlsMode::Require),
+            Ok("verify-ca") => Some(TlsMode::VerifyCa),
+            Ok("verify-full") => Some(TlsMode::VerifyFull),
+            Ok(other) => panic!("Invalid DB_SSLMODE {:?}", other),
+        }
+    }
+
+    fn ssl_mode(self) -> SslMode {
+        match self {
+            TlsMode::Disable => SslMode::Disable,
+            TlsMode::Prefer => SslMode::Prefer,
+            TlsMode::Require | TlsMode::VerifyCa | TlsMode::VerifyFull => SslMode::Require,
+        }
+    }
+}
+
+/**
+TLS connector for the mode in `DB_SSLMODE`. Server certificates are verified unless
+the mode is `require`, which asks for encryption alone. `DB_SSLROOTCERT` pins the PEM
+CA that server certificates must chain to instead of the system roots, and
+`DB_SSLCERT` with `DB_SSLKEY` (PEM, PKCS#8 key) present a client certificate.
+*/
+fn tls_connector(mode: Option<TlsMode>) -> MakeTlsConnector {
+    let mut builder = TlsConnector::builder();
+    match mode {
+        Some(TlsMode::Require) => {
+            builder.danger_accept_invalid_certs(true);
+        }
+        Some(TlsMode::VerifyCa) => {
+            builder.danger_accept_invalid_hostnames(true);
+        }
+        _ => {}
+    }
+    if let Ok(path) = env::var("DB_SSLROOTCERT") {
+        let pem = fs::read(&path).expect("Failed to read DB_SSLROOTCERT");
+        builder.add_root_certificate(Certificate::from_pem(&pem).expect("Invalid DB_SSLROOTCERT"));
+        builder.disable_built_in_roots(true);
+    }
+    if let (Ok(cert_path), Ok(key_path)) = (env::var("DB_SSLCERT"), env::var("DB_SSLKEY")) {
+        let cert = fs::read(&cert_path).expect("Failed to read DB_SSLCERT");
+        let key = fs::read(&key_path).expect("Failed to read DB_SSLKEY");
+        builder.identity(Identity::from_pkcs8(&cert, &key).expect("Invalid DB_SSLCERT or DB_SSLKEY"));
+    }
+    MakeTlsConnector::new(builder.build().expect("Invalid database TLS configuration"))
+}
+
+/**
+Connection pool for `DATABASE_URL`, sized by `DB_POOL_MAX_SIZE` (defaults to four
+connections per CPU). Checking out a connection gives up after
+`DB_POOL_ACQUIRE_TIMEOUT_SECS` (default 5). Connections returned to the pool are
+health checked with a test query before reuse, unless `DB_POOL_HEALTH_CHECK=fast`;
+either way a connection whose background task has died is discarded and replaced.
+*/
+pub fn create_pool() -> Pool {
     dotenv().ok();
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let connector = TlsConnector::builder().build().unwrap();
-    let connector = MakeTlsConnector::new(connector);
-    let (client, connection) = tokio_postgres::connect(&database_url, connector).await?;
-    tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            println!("Connection error: {}", e);
+    let mut pg_config: tokio_postgres::Config = database_url.parse().expect("Invalid DATABASE_URL");
+    let tls_mode = TlsMode::from_env();
+    if let Some(mode) = tls_mode {
+        pg_config.ssl_mode(mode.ssl_mode());
+    }
+    let connector = tls_connector(tls_mode);
+
+    let recycling_method = match env::var("DB_POOL_HEALTH_CHECK").as_deref() {
+        Ok("fast") => RecyclingMethod::Fast,
+        _ => RecyclingMethod::Verified,
+    };
+    let manager = Manager::from_config(pg_config, connector, ManagerConfig { recycling_method });
+
+    let acquire_timeout = Duration::from_secs(
+        env::var("DB_POOL_ACQUIRE_TIMEOUT_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(5),
+    );
+    let mut builder = Pool::builder(manager)
+        .runtime(Runtime::Tokio1)
+        .wait_timeout(Some(acquire_timeout))
+        .create_timeout(Some(acquire_timeout));
+    if let Some(max_size) = env::var("DB_POOL_MAX_SIZE").ok().and_then(|v| v.parse().ok()) {
+        builder = builder.max_size(max_size);
+    }
+    builder.build().expect("Invalid database pool configuration")
+}
+
+/**
+How often, and how patiently, to retry opening a connection while the database is
+unreachable: up to `DB_RECONNECT_ATTEMPTS` retries (default 3), waiting
+`DB_RECONNECT_BACKOFF_MS` (default 100) before the first and doubling up to
+`DB_RECONNECT_MAX_BACKOFF_MS` (default 5000).
+*/
+pub struct ReconnectBackoff {
+    attempts: u32,
+    initial: Duration,
+    max: Duration,
+}
+
+impl ReconnectBackoff {
+    pub fn from_env() -> Self {
+        let var = |name: &str, default: u64| env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default);
+        ReconnectBackoff {
+            attempts: var("DB_RECONNECT_ATTEMPTS", 3) as u32,
+            initial: Duration::from_millis(var("DB_RECONNECT_BACKOFF_MS", 100)),
+            max: Duration::from_millis(var("DB_RECONNECT_MAX_BACKOFF_MS", 5000)),
+        }
+    }
+
+    /**
+    Check out a connection, retrying while new connections cannot be opened. A pool
+    that is merely busy is not retried; that already waited out the acquire timeout.
+    */
+    pub async fn get(&self, pool: &Pool) -> Result<Object, PoolError> {
+        let mut delay = self.initial;
+        let mut retries = 0;
+        loop {
+            match pool.get().await {
+                Err(e @ (PoolError::Backend(_) | PoolError::Timeout(TimeoutType::Create))) if retries < self.attempts => {
+                    log::warn!("Database connection failed ({}), retrying in {:?}", e, delay);
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(self.max);
+                    retries += 1;
+                }
+                result => return result,
+            }
         }
-    });
-    Ok(client)
+    }
 }
@@ -0,0 +1,192 @@
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio_postgres::Client;
+#[cfg(feature = "sqlite")]
+use rusqlite::Connection;
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    sql: &'static str,
+}
+
+impl Migration {
+    fn checksum(&self) -> String {
+        hex::encode(Sha256::digest(self.sql.as_bytes()))
+    }
+}
+
+/**
+The schema, as versioned SQL files from `migrations/` compiled into the binary. Every
+statement of `0001_initial_schema.sql` uses `IF NOT EXISTS`, so databases created before
+migrations existed adopt it without changes; later files alter and backfill existing
+data. Applied files must never be edited; add a new one instead.
+*/
+const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, name: "initial_schema", sql: include_str!("../../migrations/0001_initial_schema.sql") },
+    Migration { version: 2, name: "roles", sql: include_str!("../../migrations/0002_roles.sql") },
+    Migration { version: 3, name: "tokens", sql: include_str!("../../migrations/0003_tokens.sql") },
+    Migration { version: 4, name: "oidc", sql: include_str!("../../migrations/0004_oidc.sql") },
+    Migration { version: 5, name: "qr_login", sql: include_str!("../../migrations/0005_qr_login.sql") },
+    Migration { version: 6, name: "passkey_votes", sql: include_str!("../../migrations/0006_passkey_votes.sql") },
+    Migration {
+        version: 7,
+        name: "passkey_credential_columns",
+        sql: include_str!("../../migrations/0007_passkey_credential_columns.sql"),
+    },
+    Migration { version: 8, name: "poll_tags", sql: include_str!("../../migrations/0008_poll_tags.sql") },
+    Migration { version: 9, name: "poll_closes_at", sql: include_str!("../../migrations/0009_poll_closes_at.sql") },
+    Migration { version: 10, name: "poll_search", sql: include_str!("../../migrations/0010_poll_search.sql") },
+    Migration { version: 11, name: "one_vote_per_poll", sql: include_str!("../../migrations/0011_one_vote_per_poll.sql") },
+    Migration { version: 12, name: "poll_state", sql: include_str!("../../migrations/0012_poll_state.sql") },
+    Migration { version: 13, name: "multiple_choice", sql: include_str!("../../migrations/0013_multiple_choice.sql") },
+];
+
+// The SQLite schema, versioned separately from the Postgres one
+#[cfg(feature = "sqlite")]
+const SQLITE_MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, name: "initial_schema", sql: include_str!("../../migrations/sqlite/0001_initial_schema.sql") },
+    Migration { version: 2, name: "poll_tags", sql: include_str!("../../migrations/sqlite/0002_poll_tags.sql") },
+    Migration { version: 3, name: "poll_closes_at", sql: include_str!("../../migrations/sqlite/0003_poll_closes_at.sql") },
+    Migration { version: 4, name: "one_vote_per_poll", sql: include_str!("../../migrations/sqlite/0004_one_vote_per_poll.sql") },
+    Migration { version: 5, name: "poll_state", sql: include_str!("../../migrations/sqlite/0005_poll_state.sql") },
+    Migration { version: 6, name: "multiple_choice", sql: include_str!("../../migrations/sqlite/0006_multiple_choice.sql") },
+];
+
+// Held for the duration of a migration run so that instances starting together take turns
+const MIGRATION_LOCK_KEY: i64 = 0x7765_6261_7574_686e;
+
+#[derive(Debug, Error)]
+pub enum MigrationError {
+    #[error("Database error while migrating: {0}")]
+    Database(#[from] tokio_postgres::Error),
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite error while migrating: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("Database schema is at version {database} but this build only knows up to {known}; refusing to start")]
+    SchemaTooNew { database: i64, known: i64 },
+    #[error("Migration {version} ({name}) was changed after it was applied")]
+    ChecksumMismatch { version: i64, name: String },
+    #[error("{0} pending migrations and DB_AUTO_MIGRATE=false; run the `migrate` command first")]
+    Pending(usize),
+}
+
+/**
+Check the database against the migrations this binary knows and, if `apply` is set,
+run the pending ones. Returns the migrations that were pending. Everything happens in
+one transaction under an advisory lock, so a failed migration leaves the schema as it
+was and without `apply` nothing is changed.
+*/
+pub async fn migrate(client: &mut Client, apply: bool) -> Result<Vec<&'static Migration>, MigrationError> {
+    let tx = client.transaction().await?;
+    tx.execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK_KEY]).await?;
+    tx.batch_execute(
+        r#"CREATE TABLE IF NOT EXISTS schema_migrations (
+               version BIGINT PRIMARY KEY,
+               name TEXT NOT NULL,
+               checksum TEXT NOT NULL,
+               applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+           )"#,
+    )
+    .await?;
+
+    let applied: Vec<(i64, String, String)> = tx
+        .query("SELECT version, name, checksum FROM schema_migrations ORDER BY version", &[])
+        .await?
+        .iter()
+        .map(|r| (r.get(0), r.get(1), r.get(2)))
+        .collect();
+    let pending = pending_migrations(MIGRATIONS, &applied)?;
+    if !apply {
+        return Ok(pending);
+    }
+    for migration in &pending {
+        tx.batch_execute(migration.sql).await?;
+        tx.execute(
+            "INSERT INTO schema_migrations (version, name, checksum) VALUES ($1, $2, $3)",
+            &[&migration.version, &migration.name, &migration.checksum()],
+        )
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(pending)
+}
+
+/**
+The migrations the database is missing, read without taking the lock or writing
+anything, for readiness checks. Fails the same way [migrate] would refuse to start.
+*/
+pub async fn status(client: &Client) -> Result<Vec<&'static Migration>, MigrationError> {
+    let applied: Vec<(i64, String, String)> = client
+        .query("SELECT version, name, checksum FROM schema_migrations ORDER BY version", &[])
+        .await?
+        .iter()
+        .map(|r| (r.get(0), r.get(1), r.get(2)))
+        .collect();
+    pending_migrations(MIGRATIONS, &applied)
+}
+
+// Compare the (version, name, checksum) rows already applied with the known migrations
+fn pending_migrations(
+    known: &'static [Migration],
+    applied: &[(i64, String, String)],
+) -> Result<Vec<&'static Migration>, MigrationError> {
+    let latest = known.last().map_or(0, |m| m.version);
+    if let Some(database) = applied.iter().map(|(v, _, _)| *v).filter(|v| *v > latest).max() {
+        return Err(MigrationError::SchemaTooNew { database, known: latest });
+    }
+    for (version, name, checksum) in applied {
+        let migration = known.iter().find(|m| m.version == *version);
+        if migration.is_none_or(|m| m.checksum() != *checksum) {
+            return Err(MigrationError::ChecksumMismatch { version: *version, name: name.clone() });
+        }
+    }
+    Ok(known
+        .iter()
+        .filter(|m| !applied.iter().any(|(v, _, _)| *v == m.version))
+        .collect())
+}
+
+/**
+[migrate] for a SQLite database, with its own migrations. The exclusive transaction
+keeps other processes from migrating at the same time.
+*/
+#[cfg(feature = "sqlite")]
+pub fn migrate_sqlite(conn: &mut Connection, apply: bool) -> Result<Vec<&'static Migration>, MigrationError> {
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Exclusive)?;
+    tx.execute_batch(
+        r#"CREATE TABLE IF NOT EXISTS schema_migrations (
+               version INTEGER PRIMARY KEY,
+               name TEXT NOT NULL,
+               checksum TEXT NOT NULL,
+               applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+           )"#,
+    )?;
+    let applied = tx
+        .prepare("SELECT version, name, checksum FROM schema_migrations ORDER BY version")?
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+    let pending = pending_migrations(SQLITE_MIGRATIONS, &applied)?;
+    if !apply {
+        return Ok(pending);
+    }
+    for migration in &pending {
+        tx.execute_batch(migration.sql)?;
+        tx.execute(
+            "INSERT INTO schema_migrations (version, name, checksum) VALUES (?1, ?2, ?3)",
+            rusqlite::params![migration.version, migration.name, migration.checksum()],
+        )?;
+    }
+    tx.commit()?;
+    Ok(pending)
+}
+
+// [status] for a SQLite database
+#[cfg(feature = "sqlite")]
+pub fn status_sqlite(conn: &Connection) -> Result<Vec<&'static Migration>, MigrationError> {
+    let applied = conn
+        .prepare("SELECT version, name, checksum FROM schema_migrations ORDER BY version")?
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+    pending_migrations(SQLITE_MIGRATIONS, &applied)
+}
@@ -1 +1,3 @@
-pub mod db;
\ No newline at end of file
+#[allow(clippy::module_inception)]
+pub mod db;
+pub mod migrations;
@@ -0,0 +1,137 @@
+use chrono::{DateTime, Utc};
+use tokio_postgres::Client;
+use uuid::Uuid;
+
+use crate::db_operations_repo::user_passkey_repo::RepoError;
+
+pub(crate) struct DeviceAuthorization {
+    pub(crate) client_id: String,
+    pub(crate) scope: Vec<String>,
+    pub(crate) status: String,
+    pub(crate) expires_at: DateTime<Utc>,
+}
+
+pub(crate) struct DevicePoll {
+    pub(crate) authorization: DeviceAuthorization,
+    // The client polled faster than its interval, which has now been raised
+    pub(crate) slow_down: bool,
+}
+
+pub(crate) struct ApprovedDevice {
+    pub(crate) user_id: Uuid,
+    pub(crate) scope: Vec<String>,
+    pub(crate) auth_time: DateTime<Utc>,
+}
+
+pub(crate) struct DeviceRepo<'a> {
+    pub(crate) client: &'a Client,
+}
+
+impl<'a> DeviceRepo<'a> {
+    pub(crate) async fn insert(
+        &self,
+        device_code_hash: &str,
+        user_code: &str,
+        client_id: &str,
+        scope: &[String],
+        interval_secs: i32,
+        expires_at: &DateTime<Utc>,
+    ) -> Result<(), RepoError> {
+        self.client
+            .execute(
+                r#"INSERT INTO device_authorizations
+                   (device_code_hash, user_code, client_id, scope, status, interval_secs, expires_at, created_at)
+                   VALUES ($1, $2, $3, $4, 'pending', $5, $6, now())"#,
+                &[&device_code_hash, &user_code, &client_id, &scope, &interval_secs, expires_at],
+            )
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(())
+    }
+
+    pub(crate) async fn find_by_user_code(&self, user_code: &str) -> Result<Option<DeviceAuthorization>, RepoError> {
+        let row = self.client
+            .query_opt(
+                "SELECT client_id, scope, status, expires_at FROM device_authorizations WHERE user_code = $1",
+                &[&user_code],
+            )
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(row.map(|r| DeviceAuthorization {
+            client_id: r.get(0),
+            scope: r.get(1),
+            status: r.get(2),
+            expires_at: r.get(3),
+        }))
+    }
+
+    // Approve or deny a pending request on behalf of the signed-in user
+    pub(crate) async fn decide(
+        &self,
+        user_code: &str,
+        user_id: &Uuid,
+        auth_time: &DateTime<Utc>,
+        approve: bool,
+    ) -> Result<bool, RepoError> {
+        let status = if approve { "approved" } else { "denied" };
+        let updated = self.client
+            .execute(
+                r#"UPDATE device_authorizations SET status = $2, user_id = $3, auth_time = $4
+                   WHERE user_code = $1 AND status = 'pending' AND expires_at > now()"#,
+                &[&user_code, &status, user_id, auth_time],
+            )
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(updated > 0)
+    }
+
+    // Record a poll from the device, raising its interval by 5 seconds when it
+    // polls too quickly (RFC 8628 section 3.5)
+    pub(crate) async fn poll(&self, device_code_hash: &str) -> Result<Option<DevicePoll>, RepoError> {
+        let row = self.client
+            .query_opt(
+                r#"WITH prev AS (
+                       SELECT device_code_hash, last_polled_at, interval_secs FROM device_authorizations
+                       WHERE device_code_hash = $1 FOR UPDATE
+                   )
+                   UPDATE device_authorizations d SET
+                       last_polled_at = now(),
+                       interval_secs = CASE
+                           WHEN prev.last_polled_at + make_interval(secs => prev.interval_secs) > now()
+                           THEN prev.interval_secs + 5 ELSE prev.interval_secs END
+                   FROM prev WHERE d.device_code_hash = prev.device_code_hash
+                   RETURNING d.client_id, d.scope, d.status, d.expires_at,
+                       COALESCE(prev.last_polled_at + make_interval(secs => prev.interval_secs) > now(), false)"#,
+                &[&device_code_hash],
+            )
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(row.map(|r| DevicePoll {
+            authorization: DeviceAuthorization {
+                client_id: r.get(0),
+                scope: r.get(1),
+                status: r.get(2),
+                expires_at: r.get(3),
+            },
+            slow_down: r.get(4),
+        }))
+    }
+
+    // Tokens are issued once: the first poll after approval consumes the grant
+    pub(crate) async fn consume_approved(&self, device_code_hash: &str) -> Result<Option<ApprovedDevice>, RepoError> {
+        let row = self.client
+            .query_opt(
+                r#"UPDATE device_authorizations SET status = 'consumed'
+                   WHERE device_code_hash = $1 AND status = 'approved' AND expires_at > now()
+                   RETURNING user_id, scope, auth_time"#,
+                &[&device_code_hash],
+            )
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(row.map(|r| ApprovedDevice {
+            user_id: r.get(0),
+            scope: r.get(1),
+            auth_time: r.get(2),
+        }))
+    }
+}
@@ -0,0 +1,516 @@
+use std::sync::{Mutex, MutexGuard};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use uuid::Uuid;
+use webauthn_rs::prelude::Passkey;
+
+use crate::attestation::VerifiedAttestation;
+use crate::db_operations_repo::{
+    poll_repo::{OptionTally, Poll, PollDetails, PollFilter, PollOptions, PollPage, PollState, PollTransition, RepoError as PollRepoError, VoteOutcome, VoteTally, require_open, require_resettable, selection_change},
+    store::{NewPoll, PollStore, RoleStore, StoreHealth, UserStore},
+    user_passkey_repo::{CredentialColumns, RepoError, StoredPasskey},
+};
+use crate::rbac::{Permission, Role};
+
+struct MemoryPasskey {
+    user_id: Uuid,
+    credential_id: String,
+    counter: i64,
+    passkey: Passkey,
+    attestation_aaguid: Option<Uuid>,
+    attestation_ca: Option<String>,
+}
+
+struct MemoryOption {
+    id: i32,
+    poll_id: i32,
+    option_text: String,
+    votes: i32,
+}
+
+#[allow(dead_code)]
+struct MemoryVote {
+    user_id: Uuid,
+    poll_id: i32,
+    // Sorted
+    option_ids: Vec<i32>,
+    voted_at: String,
+    assertion: Option<serde_json::Value>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    users: Vec<(Uuid, String)>,
+    passkeys: Vec<MemoryPasskey>,
+    // Kept in id order, with their options left empty
+    polls: Vec<PollDetails>,
+    options: Vec<MemoryOption>,
+    votes: Vec<MemoryVote>,
+    user_roles: Vec<(Uuid, Role)>,
+}
+
+impl MemoryState {
+    fn poll_state(&self, poll_id: i32) -> Option<PollState> {
+        self.polls.iter().find(|p| p.id == poll_id).map(|p| p.state)
+    }
+
+    // Options are kept in id order, so the tallies come out in id order too
+    fn adjust_vote_counts(&mut self, option_ids: &[i32], delta: i32) -> Vec<OptionTally> {
+        self.options
+            .iter_mut()
+            .filter(|o| option_ids.contains(&o.id))
+            .map(|o| {
+                o.votes += delta;
+                OptionTally { option_id: o.id, option_text: o.option_text.clone(), votes: o.votes }
+            })
+            .collect()
+    }
+
+    fn voters(&self, poll_id: i32) -> i64 {
+        self.votes.iter().filter(|v| v.poll_id == poll_id).count() as i64
+    }
+
+    fn poll_options(&self, poll_id: i32) -> Vec<PollOptions> {
+        self.options
+            .iter()
+            .filter(|o| o.poll_id == poll_id)
+            .map(|o| PollOptions { option_text: o.option_text.clone(), votes: o.votes })
+            .collect()
+    }
+
+    // Same semantics as the SQL stores; titles match case-insensitively
+    fn polls_matching(&self, filter: &PollFilter) -> Vec<Poll> {
+        let search = filter.title_search.as_ref().map(|s| s.to_lowercase());
+        self.polls
+            .iter()
+            .filter(|p| {
+                filter.creator_id.is_none_or(|c| p.creator_id == c)
+                    && filter.state.is_none_or(|s| p.state == s)
+                    && filter.shows(p.state, p.creator_id)
+                    && filter.created_after.is_none_or(|at| p.created_at >= at.to_string())
+                    && filter.created_before.is_none_or(|at| p.created_at < at.to_string())
+                    && search.as_ref().is_none_or(|s| p.title.to_lowercase().contains(s))
+                    && filter.tags.iter().all(|t| p.tags.contains(t))
+            })
+            .map(|p| Poll {
+                id: p.id,
+                title: p.title.clone(),
+                creator_id: p.creator_id,
+                state: p.state,
+                tags: p.tags.clone(),
+                created_at: p.created_at.clone(),
+                closes_at: p.closes_at.clone(),
+                options: self.poll_options(p.id),
+            })
+            .collect()
+    }
+}
+
+/**
+The user, poll and role stores kept in process memory, for running the API without a
+database (in-process tests, demos). Everything is lost when the process exits. A
+single lock guards all of it, so every method is atomic. Roles grant exactly their
+[Role::default_permissions].
+*/
+#[derive(Default)]
+pub(crate) struct MemoryStore {
+    state: Mutex<MemoryState>,
+}
+
+impl MemoryStore {
+    fn state(&self) -> MutexGuard<'_, MemoryState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[async_trait]
+impl UserStore for MemoryStore {
+    async fn find_unique_id_by_username(&self, username: &str) -> Result<Option<Uuid>, RepoError> {
+        Ok(self.state().users.iter().find(|(_, name)| name == username).map(|(id, _)| *id))
+    }
+
+    async fn find_username_by_id(&self, unique_id: &Uuid) -> Result<Option<String>, RepoError> {
+        Ok(self.state().users.iter().find(|(id, _)| id == unique_id).map(|(_, name)| name.clone()))
+    }
+
+    async fn create_user(&self, unique_id: &Uuid, username: &str, passkey: &Passkey) -> Result<(), RepoError> {
+        let mut state = self.state();
+        if state.users.iter().any(|(id, name)| id == unique_id || name == username) {
+            return Err(RepoError::DatabaseQueryError);
+        }
+        let columns = CredentialColumns::of(passkey);
+        if state.passkeys.iter().any(|p| p.credential_id == columns.credential_id) {
+            return Err(RepoError::DuplicateCredential);
+        }
+        state.users.push((*unique_id, username.to_string()));
+        state.passkeys.push(MemoryPasskey {
+            user_id: *unique_id,
+            credential_id: columns.credential_id,
+            counter: columns.counter,
+            passkey: passkey.clone(),
+            attestation_aaguid: None,
+            attestation_ca: None,
+        });
+        Ok(())
+    }
+
+    async fn find_passkeys_by_user_id(&self, user_id: &Uuid) -> Result<Vec<StoredPasskey>, RepoError> {
+        Ok(self.state()
+            .passkeys
+            .iter()
+            .filter(|p| p.user_id == *user_id)
+            .map(|p| StoredPasskey { counter: p.counter, passkey: p.passkey.clone() })
+            .collect())
+    }
+
+    async fn find_passkey(&self, user_id: &Uuid, credential_id: &str) -> Result<Option<StoredPasskey>, RepoError> {
+        Ok(self.state()
+            .passkeys
+            .iter()
+            .find(|p| p.user_id == *user_id && p.credential_id == credential_id)
+            .map(|p| StoredPasskey { counter: p.counter, passkey: p.passkey.clone() }))
+    }
+
+    async fn insert_passkey(
+        &self,
+        user_id: &Uuid,
+        passkey: &Passkey,
+        attestation: Option<&VerifiedAttestation>,
+    ) -> Result<(), RepoError> {
+        let mut state = self.state();
+        let columns = CredentialColumns::of(passkey);
+        if state.passkeys.iter().any(|p| p.credential_id == columns.credential_id) {
+            return Err(RepoError::DuplicateCredential);
+        }
+        state.passkeys.push(MemoryPasskey {
+            user_id: *user_id,
+            credential_id: columns.credential_id,
+            counter: columns.counter,
+            passkey: passkey.clone(),
+            attestation_aaguid: attestation.and_then(|a| a.aaguid),
+            attestation_ca: attestation.map(|a| a.ca_fingerprint.clone()),
+        });
+        Ok(())
+    }
+
+    async fn update_passkey(&self, passkey: &Passkey, expected_counter: i64) -> Result<bool, RepoError> {
+        let columns = CredentialColumns::of(passkey);
+        let mut state = self.state();
+        match state.passkeys.iter_mut().find(|p| p.credential_id == columns.credential_id && p.counter == expected_counter) {
+            Some(stored) => {
+                stored.counter = columns.counter;
+                stored.passkey = passkey.clone();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn has_attested_passkey(
+        &self,
+        user_id: &Uuid,
+        allowed_aaguids: &[Uuid],
+        allowed_cas: &[String],
+    ) -> Result<bool, RepoError> {
+        Ok(self.state().passkeys.iter().any(|p| {
+            p.user_id == *user_id
+                && (p.attestation_aaguid.is_some_and(|a| allowed_aaguids.contains(&a))
+                    || p.attestation_ca.as_ref().is_some_and(|ca| allowed_cas.contains(ca)))
+        }))
+    }
+}
+
+#[async_trait]
+impl PollStore for MemoryStore {
+    async fn create_poll(&self, poll: NewPoll<'_>) -> Result<i32, PollRepoError> {
+        let mut state = self.state();
+        let poll_id = state.polls.last().map_or(1, |p| p.id + 1);
+        let first_option_id = state.options.last().map_or(1, |o| o.id + 1);
+        state.polls.push(PollDetails {
+            id: poll_id,
+            title: poll.title.to_string(),
+            creator_id: poll.creator_id,
+            state: poll.state,
+            require_passkey_vote: poll.require_passkey_vote,
+            allowed_aaguids: poll.allowed_aaguids.to_vec(),
+            allowed_attestation_cas: poll.allowed_attestation_cas.to_vec(),
+            tags: poll.tags.to_vec(),
+            options: Vec::new(),
+            created_at: Utc::now().naive_utc().to_string(),
+            closes_at: poll.closes_at.map(|at| at.to_string()),
+            allow_vote_change: poll.allow_vote_change,
+            min_selections: poll.min_selections,
+            max_selections: poll.max_selections,
+            voters: 0,
+        });
+        for (id, option_text) in (first_option_id..).zip(poll.options) {
+            state.options.push(MemoryOption { id, poll_id, option_text: option_text.clone(), votes: 0 });
+        }
+        Ok(poll_id)
+    }
+
+    async fn get_polls_page(&self, filter: &PollFilter, page: &PollPage) -> Result<Vec<Poll>, PollRepoError> {
+        Ok(page.apply(self.state().polls_matching(filter)))
+    }
+
+    async fn count_polls(&self, filter: &PollFilter) -> Result<i64, PollRepoError> {
+        Ok(self.state().polls_matching(filter).len() as i64)
+    }
+
+    async fn get_poll_by_id(&self, poll_id: i32) -> Result<Option<PollDetails>, PollRepoError> {
+        let state = self.state();
+        Ok(state.polls.iter().find(|p| p.id == poll_id).map(|p| PollDetails {
+            id: p.id,
+            title: p.title.clone(),
+            creator_id: p.creator_id,
+            state: p.state,
+            require_passkey_vote: p.require_passkey_vote,
+            allowed_aaguids: p.allowed_aaguids.clone(),
+            allowed_attestation_cas: p.allowed_attestation_cas.clone(),
+            tags: p.tags.clone(),
+            options: Vec::new(),
+            created_at: p.created_at.clone(),
+            closes_at: p.closes_at.clone(),
+            allow_vote_change: p.allow_vote_change,
+            min_selections: p.min_selections,
+            max_selections: p.max_selections,
+            voters: state.voters(poll_id),
+        }))
+    }
+
+    async fn get_poll_options_with_votes(&self, poll_id: i32) -> Result<Vec<PollOptions>, PollRepoError> {
+        Ok(self.state().poll_options(poll_id))
+    }
+
+    async fn get_option_id_by_text_and_poll_id(&self, option_text: &str, poll_id: i32) -> Result<Option<i32>, PollRepoError> {
+        Ok(self.state()
+            .options
+            .iter()
+            .find(|o| o.poll_id == poll_id && o.option_text == option_text)
+            .map(|o| o.id))
+    }
+
+    async fn record_vote(
+        &self,
+        user_id: Uuid,
+        poll_id: i32,
+        option_ids: &[i32],
+        voted_at: String,
+        assertion: Option<&serde_json::Value>,
+        allow_change: bool,
+    ) -> Result<VoteOutcome, PollRepoError> {
+        let mut state = self.state();
+        require_open(state.poll_state(poll_id))?;
+        let assertion = assertion.cloned();
+        let vote = MemoryVote { user_id, poll_id, option_ids: option_ids.to_vec(), voted_at, assertion };
+        match state.votes.iter().position(|v| v.user_id == user_id && v.poll_id == poll_id) {
+            None => {
+                let options = state.adjust_vote_counts(option_ids, 1);
+                state.votes.push(vote);
+                Ok(VoteOutcome::Recorded(VoteTally { options, voters: state.voters(poll_id) }))
+            }
+            Some(_) if !allow_change => Err(PollRepoError::AlreadyVoted),
+            Some(i) if state.votes[i].option_ids == option_ids => Ok(VoteOutcome::Unchanged),
+            Some(i) => {
+                let (removed, added) = selection_change(&state.votes[i].option_ids, option_ids);
+                let mut options = state.adjust_vote_counts(&removed, -1);
+                options.extend(state.adjust_vote_counts(&added, 1));
+                options.sort_by_key(|t| t.option_id);
+                state.votes[i] = vote;
+                Ok(VoteOutcome::Changed(VoteTally { options, voters: state.voters(poll_id) }))
+            }
+        }
+    }
+
+    async fn retract_vote(&self, user_id: Uuid, poll_id: i32) -> Result<Option<VoteTally>, PollRepoError> {
+        let mut state = self.state();
+        require_open(state.poll_state(poll_id))?;
+        match state.votes.iter().position(|v| v.user_id == user_id && v.poll_id == poll_id) {
+            Some(i) => {
+                let vote = state.votes.remove(i);
+                let options = state.adjust_vote_counts(&vote.option_ids, -1);
+                Ok(Some(VoteTally { options, voters: state.voters(poll_id) }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn transition_poll(&self, poll_id: i32, transition: PollTransition) -> Result<Option<PollState>, PollRepoError> {
+        let mut state = self.state();
+        let Some(poll) = state.polls.iter_mut().find(|p| p.id == poll_id) else {
+            return Ok(None);
+        };
+        let from = poll.state;
+        poll.state = transition.apply(from)?;
+        Ok(Some(from))
+    }
+
+    async fn reset_votes(&self, poll_id: i32) -> Result<(), PollRepoError> {
+        let mut state = self.state();
+        require_resettable(state.poll_state(poll_id))?;
+        state.votes.retain(|v| v.poll_id != poll_id);
+        for option in state.options.iter_mut().filter(|o| o.poll_id == poll_id) {
+            option.votes = 0;
+        }
+        Ok(())
+    }
+
+    async fn is_poll_creator(&self, user_id: Uuid, poll_id: i32) -> Result<bool, PollRepoError> {
+        Ok(self.state().polls.iter().any(|p| p.id == poll_id && p.creator_id == user_id))
+    }
+}
+
+#[async_trait]
+impl RoleStore for MemoryStore {
+    async fn roles_for_user(&self, user_id: &Uuid) -> Result<Vec<Role>, RepoError> {
+        let mut roles: Vec<Role> = self.state().user_roles.iter().filter(|(u, _)| u == user_id).map(|(_, r)| *r).collect();
+        roles.sort_by_key(|r| r.as_str());
+        Ok(roles)
+    }
+
+    async fn permissions_for_user(&self, user_id: &Uuid) -> Result<Vec<Permission>, RepoError> {
+        let mut roles = self.roles_for_user(user_id).await?;
+        roles.push(Role::User);
+        let mut permissions: Vec<Permission> = Vec::new();
+        for permission in roles.iter().flat_map(|r| r.default_permissions()) {
+            if !permissions.contains(permission) {
+                permissions.push(*permission);
+            }
+        }
+        Ok(permissions)
+    }
+
+    async fn assign_role(&self, user_id: &Uuid, role: Role) -> Result<(), RepoError> {
+        let mut state = self.state();
+        if !state.user_roles.contains(&(*user_id, role)) {
+            state.user_roles.push((*user_id, role));
+        }
+        Ok(())
+    }
+
+    async fn revoke_role(&self, user_id: &Uuid, role: Role) -> Result<bool, RepoError> {
+        let mut state = self.state();
+        let before = state.user_roles.len();
+        state.user_roles.retain(|assigned| *assigned != (*user_id, role));
+        Ok(state.user_roles.len() < before)
+    }
+
+    async fn bootstrap_admin(&self, user_id: &Uuid, username: &str) -> Result<bool, RepoError> {
+        match std::env::var("BOOTSTRAP_ADMIN_USERNAME") {
+            Ok(bootstrap) if bootstrap == username => {}
+            _ => return Ok(false),
+        }
+        let mut state = self.state();
+        if state.user_roles.iter().any(|(_, r)| *r == Role::Admin) {
+            return Ok(false);
+        }
+        state.user_roles.push((*user_id, Role::Admin));
+        Ok(true)
+    }
+}
+
+// Nothing to connect to and no schema to migrate
+#[async_trait]
+impl StoreHealth for MemoryStore {
+    async fn ping(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn pending_migrations(&self) -> anyhow::Result<usize> {
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+
+    use super::*;
+    use crate::db_operations_repo::store::{check_vote_changes, seed_polls};
+
+    struct Seeded {
+        store: MemoryStore,
+        alice: Uuid,
+        bob: Uuid,
+    }
+
+    async fn seeded() -> Seeded {
+        let (store, alice, bob) = (MemoryStore::default(), Uuid::new_v4(), Uuid::new_v4());
+        seed_polls(&store, alice, bob).await;
+        Seeded { store, alice, bob }
+    }
+
+    impl Seeded {
+        fn ids(&self, filter: PollFilter) -> Vec<i32> {
+            self.store.state().polls_matching(&filter).iter().map(|p| p.id).collect()
+        }
+
+        fn created_at(&self, poll_id: i32) -> NaiveDateTime {
+            let state = self.store.state();
+            let poll = state.polls.iter().find(|p| p.id == poll_id).unwrap();
+            NaiveDateTime::parse_from_str(&poll.created_at, "%Y-%m-%d %H:%M:%S%.f").unwrap()
+        }
+    }
+
+    #[actix_web::test]
+    async fn lists_drafts_only_for_their_creator() {
+        let seeded = seeded().await;
+        assert_eq!(seeded.ids(PollFilter::new()), [1, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(seeded.ids(PollFilter::new().viewer(seeded.alice)), [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(seeded.ids(PollFilter::new().viewer(seeded.bob)), [1, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(seeded.ids(PollFilter::new().state(PollState::Draft).viewer(seeded.bob)), Vec::<i32>::new());
+    }
+
+    #[actix_web::test]
+    async fn filters_by_state_and_creator() {
+        let seeded = seeded().await;
+        assert_eq!(seeded.ids(PollFilter::new().state(PollState::Open)), [1, 3, 6, 7, 8]);
+        assert_eq!(seeded.ids(PollFilter::new().state(PollState::Closed)), [4]);
+        assert_eq!(seeded.ids(PollFilter::new().state(PollState::Archived)), [5]);
+        assert_eq!(seeded.ids(PollFilter::new().creator(seeded.alice).viewer(seeded.alice)), [1, 2, 5, 8]);
+    }
+
+    #[actix_web::test]
+    async fn requires_every_tag() {
+        let seeded = seeded().await;
+        assert_eq!(seeded.ids(PollFilter::new().tagged("team")), [1, 4, 7]);
+        assert_eq!(seeded.ids(PollFilter::new().tagged("team").tagged("food")), [1]);
+        assert_eq!(seeded.ids(PollFilter::new().tagged("Team")), Vec::<i32>::new());
+    }
+
+    #[actix_web::test]
+    async fn bounds_created_at_inclusively_from_and_exclusively_to() {
+        let seeded = seeded().await;
+        let third = seeded.created_at(3);
+        assert_eq!(seeded.ids(PollFilter::new().created_after(third)), [3, 4, 5, 6, 7, 8]);
+        assert_eq!(seeded.ids(PollFilter::new().created_before(third)), [1]);
+        assert_eq!(seeded.ids(PollFilter::new().created_after(third).created_before(third)), Vec::<i32>::new());
+        assert_eq!(seeded.ids(PollFilter::new().created_after(third).created_before(seeded.created_at(5))), [3, 4]);
+    }
+
+    #[actix_web::test]
+    async fn matches_titles_case_insensitively_and_literally() {
+        let seeded = seeded().await;
+        assert_eq!(seeded.ids(PollFilter::new().title_contains("LUNCH")), [1, 3, 5, 6]);
+        assert_eq!(seeded.ids(PollFilter::new().title_contains("été")), [8]);
+        assert_eq!(seeded.ids(PollFilter::new().title_contains("100%")), [1]);
+        assert_eq!(seeded.ids(PollFilter::new().title_contains("%")), [1]);
+        assert_eq!(seeded.ids(PollFilter::new().viewer(seeded.alice).title_contains("m_o")), [2]);
+    }
+
+    #[actix_web::test]
+    async fn resets_only_open_and_closed_polls() {
+        let seeded = seeded().await;
+        seeded.store.reset_votes(4).await.unwrap();
+        assert_eq!(seeded.store.get_poll_by_id(4).await.unwrap().unwrap().voters, 0);
+        assert!(matches!(seeded.store.reset_votes(2).await, Err(PollRepoError::NotResettable(PollState::Draft))));
+        assert!(matches!(seeded.store.reset_votes(5).await, Err(PollRepoError::NotResettable(PollState::Archived))));
+    }
+
+    #[actix_web::test]
+    async fn changes_repeats_and_retracts_votes() {
+        let (creator, alice, bob) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        check_vote_changes(&MemoryStore::default(), creator, alice, bob).await;
+    }
+}
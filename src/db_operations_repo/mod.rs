@@ -1,2 +1,15 @@
 pub mod user_passkey_repo;
-pub mod poll_repo;
\ No newline at end of file
+pub mod poll_repo;
+pub mod role_repo;
+pub mod token_repo;
+pub mod pat_repo;
+pub mod oidc_repo;
+pub mod device_repo;
+pub mod qr_login_repo;
+pub mod search;
+pub mod transaction;
+pub mod store;
+pub mod pg_store;
+pub mod memory_store;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_store;
@@ -0,0 +1,107 @@
+use chrono::{DateTime, Utc};
+use tokio_postgres::Client;
+use uuid::Uuid;
+
+use crate::db_operations_repo::user_passkey_repo::RepoError;
+
+pub(crate) struct OidcClient {
+    pub(crate) client_id: String,
+    pub(crate) name: String,
+    // Public clients (SPAs, native apps) have no secret and rely on PKCE alone
+    pub(crate) client_secret_hash: Option<String>,
+    pub(crate) redirect_uris: Vec<String>,
+}
+
+pub(crate) struct AuthorizationCode {
+    pub(crate) client_id: String,
+    pub(crate) user_id: Uuid,
+    pub(crate) redirect_uri: String,
+    pub(crate) scope: String,
+    pub(crate) nonce: Option<String>,
+    pub(crate) code_challenge: String,
+    pub(crate) auth_time: DateTime<Utc>,
+}
+
+pub(crate) struct OidcRepo<'a> {
+    pub(crate) client: &'a Client,
+}
+
+impl<'a> OidcRepo<'a> {
+    pub(crate) async fn find_client(&self, client_id: &str) -> Result<Option<OidcClient>, RepoError> {
+        let row = self.client
+            .query_opt(
+                "SELECT client_id, name, client_secret_hash, redirect_uris FROM oidc_clients WHERE client_id = $1",
+                &[&client_id],
+            )
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(row.map(|r| OidcClient {
+            client_id: r.get(0),
+            name: r.get(1),
+            client_secret_hash: r.get(2),
+            redirect_uris: r.get(3),
+        }))
+    }
+
+    pub(crate) async fn insert_client(&self, client: &OidcClient) -> Result<(), RepoError> {
+        self.client
+            .execute(
+                r#"INSERT INTO oidc_clients (client_id, name, client_secret_hash, redirect_uris, created_at)
+                   VALUES ($1, $2, $3, $4, now())"#,
+                &[&client.client_id, &client.name, &client.client_secret_hash, &client.redirect_uris],
+            )
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(())
+    }
+
+    pub(crate) async fn insert_code(
+        &self,
+        code_hash: &str,
+        code: &AuthorizationCode,
+        expires_at: &DateTime<Utc>,
+    ) -> Result<(), RepoError> {
+        self.client
+            .execute(
+                r#"INSERT INTO oidc_authorization_codes
+                   (code_hash, client_id, user_id, redirect_uri, scope, nonce, code_challenge, auth_time, expires_at)
+                   VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)"#,
+                &[
+                    &code_hash,
+                    &code.client_id,
+                    &code.user_id,
+                    &code.redirect_uri,
+                    &code.scope,
+                    &code.nonce,
+                    &code.code_challenge,
+                    &code.auth_time,
+                    expires_at,
+                ],
+            )
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(())
+    }
+
+    // Codes are single use: the first exchange marks them consumed
+    pub(crate) async fn consume_code(&self, code_hash: &str) -> Result<Option<AuthorizationCode>, RepoError> {
+        let row = self.client
+            .query_opt(
+                r#"UPDATE oidc_authorization_codes SET consumed_at = now()
+                   WHERE code_hash = $1 AND consumed_at IS NULL AND expires_at > now()
+                   RETURNING client_id, user_id, redirect_uri, scope, nonce, code_challenge, auth_time"#,
+                &[&code_hash],
+            )
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(row.map(|r| AuthorizationCode {
+            client_id: r.get(0),
+            user_id: r.get(1),
+            redirect_uri: r.get(2),
+            scope: r.get(3),
+            nonce: r.get(4),
+            code_challenge: r.get(5),
+            auth_time: r.get(6),
+        }))
+    }
+}
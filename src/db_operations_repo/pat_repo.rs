@@ -0,0 +1,97 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio_postgres::Client;
+use uuid::Uuid;
+
+use crate::db_operations_repo::user_passkey_repo::RepoError;
+
+#[derive(Serialize)]
+pub struct PersonalAccessToken {
+    pub id: Uuid,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+pub(crate) struct PatRepo<'a> {
+    pub(crate) client: &'a Client,
+}
+
+impl<'a> PatRepo<'a> {
+    pub(crate) async fn insert_token(
+        &self,
+        user_id: &Uuid,
+        name: &str,
+        token_hash: &str,
+        scopes: &[String],
+        expires_at: &DateTime<Utc>,
+    ) -> Result<PersonalAccessToken, RepoError> {
+        let id = Uuid::new_v4();
+        let row = self.client
+            .query_one(
+                r#"INSERT INTO personal_access_tokens (id, user_id, name, token_hash, scopes, expires_at, created_at)
+                   VALUES ($1, $2, $3, $4, $5, $6, now())
+                   RETURNING created_at"#,
+                &[&id, user_id, &name, &token_hash, &scopes, expires_at],
+            )
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(PersonalAccessToken {
+            id,
+            name: name.to_string(),
+            scopes: scopes.to_vec(),
+            created_at: row.get(0),
+            expires_at: *expires_at,
+            last_used_at: None,
+        })
+    }
+
+    // Resolve a live token to its owner and scopes, recording the use
+    pub(crate) async fn authenticate(&self, token_hash: &str) -> Result<Option<(Uuid, Vec<String>)>, RepoError> {
+        let row = self.client
+            .query_opt(
+                r#"UPDATE personal_access_tokens SET last_used_at = now()
+                   WHERE token_hash = $1 AND revoked_at IS NULL AND expires_at > now()
+                   RETURNING user_id, scopes"#,
+                &[&token_hash],
+            )
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(row.map(|r| (r.get(0), r.get(1))))
+    }
+
+    pub(crate) async fn list_for_user(&self, user_id: &Uuid) -> Result<Vec<PersonalAccessToken>, RepoError> {
+        let rows = self.client
+            .query(
+                r#"SELECT id, name, scopes, created_at, expires_at, last_used_at FROM personal_access_tokens
+                   WHERE user_id = $1 AND revoked_at IS NULL ORDER BY created_at DESC"#,
+                &[user_id],
+            )
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(rows
+            .iter()
+            .map(|r| PersonalAccessToken {
+                id: r.get("id"),
+                name: r.get("name"),
+                scopes: r.get("scopes"),
+                created_at: r.get("created_at"),
+                expires_at: r.get("expires_at"),
+                last_used_at: r.get("last_used_at"),
+            })
+            .collect())
+    }
+
+    pub(crate) async fn revoke(&self, user_id: &Uuid, token_id: &Uuid) -> Result<bool, RepoError> {
+        let revoked = self.client
+            .execute(
+                "UPDATE personal_access_tokens SET revoked_at = now() WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL",
+                &[token_id, user_id],
+            )
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(revoked > 0)
+    }
+}
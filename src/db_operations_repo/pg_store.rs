@@ -0,0 +1,234 @@
+use actix_web::web::Data;
+use async_trait::async_trait;
+use uuid::Uuid;
+use webauthn_rs::prelude::Passkey;
+
+use crate::attestation::VerifiedAttestation;
+use crate::db::db::PooledClient;
+use crate::db::migrations;
+use crate::db_operations_repo::{
+    poll_repo::{Poll, PollDetails, PollFilter, PollOptions, PollPage, PollState, PollTransition, PollRepo, require_open, require_resettable, RepoError as PollRepoError, VoteOutcome, VoteTally, selection_change},
+    role_repo::RoleRepo,
+    search::PollSearchHit,
+    store::{NewPoll, PollStore, RoleStore, StoreHealth, UserStore},
+    transaction::RepoTransaction,
+    user_passkey_repo::{RepoError, StoredPasskey, UserRepo},
+};
+use crate::rbac::{Permission, Role};
+use crate::startup::UserData;
+
+// The stores backed by Postgres; each call checks out its own pooled connection
+pub(crate) struct PgStore {
+    pub(crate) db: Data<UserData>,
+}
+
+impl PgStore {
+    async fn client(&self) -> Result<PooledClient, RepoError> {
+        self.db.client().await.map_err(|_| RepoError::DatabaseUnavailable)
+    }
+
+    async fn poll_client(&self) -> Result<PooledClient, PollRepoError> {
+        self.db.client().await.map_err(|_| PollRepoError::DatabaseUnavailable)
+    }
+}
+
+#[async_trait]
+impl UserStore for PgStore {
+    async fn find_unique_id_by_username(&self, username: &str) -> Result<Option<Uuid>, RepoError> {
+        UserRepo { client: &*self.client().await? }.find_unique_id_by_username(username).await
+    }
+
+    async fn find_username_by_id(&self, unique_id: &Uuid) -> Result<Option<String>, RepoError> {
+        UserRepo { client: &*self.client().await? }.find_username_by_id(unique_id).await
+    }
+
+    async fn create_user(&self, unique_id: &Uuid, username: &str, passkey: &Passkey) -> Result<(), RepoError> {
+        let mut client = self.client().await?;
+        let tx = RepoTransaction::begin(&mut client).await?;
+        tx.users().insert_user(unique_id, username).await?;
+        tx.users().insert_passkey(unique_id, passkey, None).await?;
+        tx.commit().await
+    }
+
+    async fn find_passkeys_by_user_id(&self, user_id: &Uuid) -> Result<Vec<StoredPasskey>, RepoError> {
+        UserRepo { client: &*self.client().await? }.find_passkeys_by_user_id(user_id).await
+    }
+
+    async fn find_passkey(&self, user_id: &Uuid, credential_id: &str) -> Result<Option<StoredPasskey>, RepoError> {
+        UserRepo { client: &*self.client().await? }.find_passkey(user_id, credential_id).await
+    }
+
+    async fn insert_passkey(
+        &self,
+        user_id: &Uuid,
+        passkey: &Passkey,
+        attestation: Option<&VerifiedAttestation>,
+    ) -> Result<(), RepoError> {
+        UserRepo { client: &*self.client().await? }.insert_passkey(user_id, passkey, attestation).await
+    }
+
+    async fn update_passkey(&self, passkey: &Passkey, expected_counter: i64) -> Result<bool, RepoError> {
+        UserRepo { client: &*self.client().await? }.update_passkey(passkey, expected_counter).await
+    }
+
+    async fn has_attested_passkey(
+        &self,
+        user_id: &Uuid,
+        allowed_aaguids: &[Uuid],
+        allowed_cas: &[String],
+    ) -> Result<bool, RepoError> {
+        UserRepo { client: &*self.client().await? }
+            .has_attested_passkey(user_id, allowed_aaguids, allowed_cas)
+            .await
+    }
+}
+
+#[async_trait]
+impl PollStore for PgStore {
+    async fn create_poll(&self, poll: NewPoll<'_>) -> Result<i32, PollRepoError> {
+        let mut client = self.poll_client().await?;
+        let tx = RepoTransaction::begin(&mut client).await.map_err(|_| PollRepoError::DatabaseQueryError)?;
+        let poll_id = tx.polls().insert_poll(&poll).await?;
+        tx.commit().await.map_err(|_| PollRepoError::DatabaseQueryError)?;
+        Ok(poll_id)
+    }
+
+    async fn get_polls_page(&self, filter: &PollFilter, page: &PollPage) -> Result<Vec<Poll>, PollRepoError> {
+        PollRepo { client: &*self.poll_client().await? }.get_polls_page(filter, page).await
+    }
+
+    async fn count_polls(&self, filter: &PollFilter) -> Result<i64, PollRepoError> {
+        PollRepo { client: &*self.poll_client().await? }.count_polls(filter).await
+    }
+
+    async fn search_polls(&self, terms: &[String], filter: &PollFilter, limit: i64) -> Result<Vec<PollSearchHit>, PollRepoError> {
+        PollRepo { client: &*self.poll_client().await? }.search_polls(terms, filter, limit).await
+    }
+
+    async fn get_poll_by_id(&self, poll_id: i32) -> Result<Option<PollDetails>, PollRepoError> {
+        PollRepo { client: &*self.poll_client().await? }.get_poll_by_id(poll_id).await
+    }
+
+    async fn get_poll_options_with_votes(&self, poll_id: i32) -> Result<Vec<PollOptions>, PollRepoError> {
+        PollRepo { client: &*self.poll_client().await? }.get_poll_options_with_votes(poll_id).await
+    }
+
+    async fn get_option_id_by_text_and_poll_id(&self, option_text: &str, poll_id: i32) -> Result<Option<i32>, PollRepoError> {
+        PollRepo { client: &*self.poll_client().await? }
+            .get_option_id_by_text_and_poll_id(option_text.to_string(), poll_id)
+            .await
+    }
+
+    // The counts, the vote and its selections are written together or not at all.
+    // Concurrent first votes by one user meet at the (user, poll) unique index.
+    async fn record_vote(
+        &self,
+        user_id: Uuid,
+        poll_id: i32,
+        option_ids: &[i32],
+        voted_at: String,
+        assertion: Option<&serde_json::Value>,
+        allow_change: bool,
+    ) -> Result<VoteOutcome, PollRepoError> {
+        let mut client = self.poll_client().await?;
+        let tx = RepoTransaction::begin(&mut client).await.map_err(|_| PollRepoError::DatabaseQueryError)?;
+        let repo = tx.polls();
+        require_open(repo.lock_poll_state(poll_id).await?)?;
+        let outcome = if repo.insert_vote_details(user_id, poll_id, &voted_at, assertion).await? {
+            repo.insert_selections(user_id, poll_id, option_ids).await?;
+            let options = repo.adjust_vote_counts(option_ids, 1).await?;
+            VoteOutcome::Recorded(VoteTally { options, voters: repo.count_voters(poll_id).await? })
+        } else {
+            match repo.lock_vote(user_id, poll_id).await? {
+                Some(_) if !allow_change => return Err(PollRepoError::AlreadyVoted),
+                Some(previous) if previous == option_ids => VoteOutcome::Unchanged,
+                Some(previous) => {
+                    let (removed, added) = selection_change(&previous, option_ids);
+                    repo.replace_vote(user_id, poll_id, &voted_at, assertion).await?;
+                    repo.delete_selections(user_id, poll_id, &removed).await?;
+                    repo.insert_selections(user_id, poll_id, &added).await?;
+                    let mut options = repo.adjust_vote_counts(&removed, -1).await?;
+                    options.extend(repo.adjust_vote_counts(&added, 1).await?);
+                    options.sort_by_key(|t| t.option_id);
+                    VoteOutcome::Changed(VoteTally { options, voters: repo.count_voters(poll_id).await? })
+                }
+                // Retracted since the insert ran into it
+                None => return Err(PollRepoError::DatabaseQueryError),
+            }
+        };
+        tx.commit().await.map_err(|_| PollRepoError::DatabaseQueryError)?;
+        Ok(outcome)
+    }
+
+    async fn retract_vote(&self, user_id: Uuid, poll_id: i32) -> Result<Option<VoteTally>, PollRepoError> {
+        let mut client = self.poll_client().await?;
+        let tx = RepoTransaction::begin(&mut client).await.map_err(|_| PollRepoError::DatabaseQueryError)?;
+        let repo = tx.polls();
+        require_open(repo.lock_poll_state(poll_id).await?)?;
+        let tally = match repo.delete_vote(user_id, poll_id).await? {
+            Some(option_ids) => Some(VoteTally {
+                options: repo.adjust_vote_counts(&option_ids, -1).await?,
+                voters: repo.count_voters(poll_id).await?,
+            }),
+            None => None,
+        };
+        tx.commit().await.map_err(|_| PollRepoError::DatabaseQueryError)?;
+        Ok(tally)
+    }
+
+    async fn transition_poll(&self, poll_id: i32, transition: PollTransition) -> Result<Option<PollState>, PollRepoError> {
+        let mut client = self.poll_client().await?;
+        let tx = RepoTransaction::begin(&mut client).await.map_err(|_| PollRepoError::DatabaseQueryError)?;
+        let from = tx.polls().transition_poll(poll_id, transition).await?;
+        tx.commit().await.map_err(|_| PollRepoError::DatabaseQueryError)?;
+        Ok(from)
+    }
+
+    async fn reset_votes(&self, poll_id: i32) -> Result<(), PollRepoError> {
+        let mut client = self.poll_client().await?;
+        let tx = RepoTransaction::begin(&mut client).await.map_err(|_| PollRepoError::DatabaseQueryError)?;
+        let repo = tx.polls();
+        require_resettable(repo.lock_poll_state(poll_id).await?)?;
+        repo.reset_votes(poll_id).await?;
+        tx.commit().await.map_err(|_| PollRepoError::DatabaseQueryError)
+    }
+
+    async fn is_poll_creator(&self, user_id: Uuid, poll_id: i32) -> Result<bool, PollRepoError> {
+        PollRepo { client: &*self.poll_client().await? }.is_poll_creator(user_id, poll_id).await
+    }
+}
+
+#[async_trait]
+impl RoleStore for PgStore {
+    async fn roles_for_user(&self, user_id: &Uuid) -> Result<Vec<Role>, RepoError> {
+        RoleRepo { client: &*self.client().await? }.roles_for_user(user_id).await
+    }
+
+    async fn permissions_for_user(&self, user_id: &Uuid) -> Result<Vec<Permission>, RepoError> {
+        RoleRepo { client: &*self.client().await? }.permissions_for_user(user_id).await
+    }
+
+    async fn assign_role(&self, user_id: &Uuid, role: Role) -> Result<(), RepoError> {
+        RoleRepo { client: &*self.client().await? }.assign_role(user_id, role).await
+    }
+
+    async fn revoke_role(&self, user_id: &Uuid, role: Role) -> Result<bool, RepoError> {
+        RoleRepo { client: &*self.client().await? }.revoke_role(user_id, role).await
+    }
+
+    async fn bootstrap_admin(&self, user_id: &Uuid, username: &str) -> Result<bool, RepoError> {
+        RoleRepo { client: &*self.client().await? }.bootstrap_admin(user_id, username).await
+    }
+}
+
+#[async_trait]
+impl StoreHealth for PgStore {
+    async fn ping(&self) -> anyhow::Result<()> {
+        self.db.probe_client().await?.simple_query("SELECT 1").await?;
+        Ok(())
+    }
+
+    async fn pending_migrations(&self) -> anyhow::Result<usize> {
+        Ok(migrations::status(&*self.db.probe_client().await?).await?.len())
+    }
+}
@@ -1,47 +1,71 @@
 
-use std::collections::HashMap;
+use std::{cmp::Ordering, fmt, str::FromStr};
 
-use actix_session::Session;
 use actix_web::{HttpResponse, ResponseError};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use uuid::Uuid;
-use tokio_postgres::Client;
+use tokio_postgres::{types::ToSql, Client, GenericClient, Row};
 use chrono::{NaiveDateTime, Utc};
 
+use crate::db_operations_repo::{
+    search::{marked_snippet, prefix_tsquery, PollSearchHit, HEADLINE_OPTIONS},
+    store::NewPoll,
+};
+
 
 #[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum RepoError {
     #[error("Database query error")]
     DatabaseQueryError,
-    #[error("At least 2 unique options are required")]
-    NotEnoughOptions,
-    #[error("Duplicate options are not allowed")]
-    DuplicateOptions,
     #[error("A database error occurred")]
     DatabaseError(#[from] tokio_postgres::Error),
-    #[error("Unauthorized User")]
-    UnauthorizedUser
+    #[error("Database unavailable")]
+    DatabaseUnavailable,
+    #[error("Already voted on this poll")]
+    AlreadyVoted,
+    #[error("Cannot {transition} a poll that is {from}")]
+    InvalidTransition { from: PollState, transition: PollTransition },
+    // Votes only go to open polls
+    #[error("Poll is {0}")]
+    PollNotOpen(PollState),
+    // Drafts have no votes, and archived results are final
+    #[error("Cannot reset the votes of a poll that is {0}")]
+    NotResettable(PollState),
 }
 
 impl ResponseError for RepoError {
     fn error_response(&self) -> HttpResponse {
         match self {
-            RepoError::NotEnoughOptions => HttpResponse::BadRequest().body(self.to_string()),
-            RepoError::DuplicateOptions => HttpResponse::BadRequest().body(self.to_string()),
             RepoError::DatabaseError(_) => HttpResponse::InternalServerError().body("Database error"),
             RepoError::DatabaseQueryError => HttpResponse::InternalServerError().body("Database Query Error"),
-            RepoError::UnauthorizedUser => HttpResponse::InternalServerError().body("Unauthorized User")
+            RepoError::DatabaseUnavailable => HttpResponse::ServiceUnavailable().body("Database unavailable"),
+            RepoError::AlreadyVoted => HttpResponse::Conflict().body("Already voted on this poll"),
+            RepoError::InvalidTransition { .. } | RepoError::PollNotOpen(_) | RepoError::NotResettable(_) => {
+                HttpResponse::Conflict().body(self.to_string())
+            }
         }
     }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Poll {
-    id: i32,               
-    title: String,
-    creator_id: Uuid,    
-    options: Vec<PollOptions>,
+    pub id: i32,               
+    pub title: String,
+    pub creator_id: Uuid,    
+    pub state: PollState,
+    pub tags: Vec<String>,
+    pub created_at: String,
+    pub closes_at: Option<String>,
+    pub options: Vec<PollOptions>,
+}
+
+impl Poll {
+    pub fn total_votes(&self) -> i64 {
+        self.options.iter().map(|o| i64::from(o.votes)).sum()
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -49,9 +73,35 @@ pub struct PollDetails {
     pub id: i32,               
     pub title: String,
     pub creator_id: Uuid,
-    pub closed : bool,    
+    pub state: PollState,
+    // Votes must carry a passkey assertion bound to the chosen option
+    pub require_passkey_vote: bool,
+    // When either list is non-empty, only voters holding a security key with a
+    // verified attestation from one of these device models or roots may vote
+    pub allowed_aaguids: Vec<Uuid>,
+    pub allowed_attestation_cas: Vec<String>,
+    pub tags: Vec<String>,
     pub options: Vec<PollOptions>,
     pub created_at:  String,
+    // When the poll is scheduled to close, if it is
+    pub closes_at: Option<String>,
+    // Voters may replace their vote with another option
+    pub allow_vote_change: bool,
+    // How many options a vote selects, at least and at most
+    pub min_selections: i32,
+    pub max_selections: i32,
+    // Distinct users who voted
+    pub voters: i64,
+}
+
+impl PollDetails {
+    // The poll's state at `now`: an open poll past its `closes_at` takes no more votes
+    pub fn state_at(&self, now: NaiveDateTime) -> PollState {
+        let past_close = self.closes_at.as_deref()
+            .and_then(|at| NaiveDateTime::parse_from_str(at, "%Y-%m-%d %H:%M:%S%.f").ok())
+            .is_some_and(|at| now >= at);
+        if self.state == PollState::Open && past_close { PollState::Closed } else { self.state }
+    }
 }
 
 #[derive(Serialize, Deserialize,Debug)]
@@ -60,18 +110,488 @@ pub struct PollOptions {
     pub votes : i32
 }
 
+// An option's vote count after a vote changed it
+#[derive(Serialize, Debug)]
+pub struct OptionTally {
+    pub option_id: i32,
+    pub option_text: String,
+    pub votes: i32,
+}
+
+// The counts a vote changed: the options whose count moved, in id order, and the
+// poll's distinct voters
+#[derive(Serialize, Debug)]
+pub struct VoteTally {
+    pub options: Vec<OptionTally>,
+    pub voters: i64,
+}
+
+/**
+What recording a vote did. Each user holds at most one vote per poll, selecting one
+or more options: voting again is refused with [RepoError::AlreadyVoted] unless the
+poll allows vote changes, in which case the selection is replaced and the counts of
+the options that left or joined it are adjusted in the same transaction.
+*/
+#[derive(Debug)]
+pub enum VoteOutcome {
+    Recorded(VoteTally),
+    Changed(VoteTally),
+    // The user selected the same options again
+    Unchanged,
+}
+
+// The options to drop from and add to the sorted selection `previous` to get `next`
+pub(crate) fn selection_change(previous: &[i32], next: &[i32]) -> (Vec<i32>, Vec<i32>) {
+    let removed = previous.iter().filter(|id| !next.contains(id)).copied().collect();
+    let added = next.iter().filter(|id| !previous.contains(id)).copied().collect();
+    (removed, added)
+}
+
+/**
+Where a poll is in its lifecycle. Drafts are only seen by their creator, and only
+open polls take votes. Closed polls keep their results and can be reopened, while
+archived polls are final and read-only. A poll only moves through a [PollTransition].
+*/
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PollState {
+    Draft,
+    #[serde(alias = "live")]
+    Open,
+    Closed,
+    Archived,
+}
+
+impl PollState {
+    // As stored in `polls.state`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PollState::Draft => "draft",
+            PollState::Open => "open",
+            PollState::Closed => "closed",
+            PollState::Archived => "archived",
+        }
+    }
+}
+
+// Votes need the poll, as looked up, to exist and be open
+pub(crate) fn require_open(state: Option<PollState>) -> Result<(), RepoError> {
+    match state {
+        Some(PollState::Open) => Ok(()),
+        Some(state) => Err(RepoError::PollNotOpen(state)),
+        None => Err(RepoError::DatabaseQueryError),
+    }
+}
+
+// Resets need the poll, as looked up, to exist and be open or closed
+pub(crate) fn require_resettable(state: Option<PollState>) -> Result<(), RepoError> {
+    match state {
+        Some(PollState::Open | PollState::Closed) => Ok(()),
+        Some(state) => Err(RepoError::NotResettable(state)),
+        None => Err(RepoError::DatabaseQueryError),
+    }
+}
+
+impl fmt::Display for PollState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for PollState {
+    type Err = RepoError;
+
+    fn from_str(state: &str) -> Result<Self, Self::Err> {
+        match state {
+            "draft" => Ok(PollState::Draft),
+            "open" => Ok(PollState::Open),
+            "closed" => Ok(PollState::Closed),
+            "archived" => Ok(PollState::Archived),
+            _ => Err(RepoError::DatabaseQueryError),
+        }
+    }
+}
+
+// The moves between [PollState]s, each allowed from exactly one state
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PollTransition {
+    Publish,
+    Close,
+    Reopen,
+    Archive,
+}
+
+impl PollTransition {
+    pub fn from(self) -> PollState {
+        match self {
+            PollTransition::Publish => PollState::Draft,
+            PollTransition::Close => PollState::Open,
+            PollTransition::Reopen => PollState::Closed,
+            PollTransition::Archive => PollState::Closed,
+        }
+    }
+
+    pub fn to(self) -> PollState {
+        match self {
+            PollTransition::Publish | PollTransition::Reopen => PollState::Open,
+            PollTransition::Close => PollState::Closed,
+            PollTransition::Archive => PollState::Archived,
+        }
+    }
+
+    // The state after this transition from `current`, if it is allowed
+    pub fn apply(self, current: PollState) -> Result<PollState, RepoError> {
+        if current == self.from() {
+            Ok(self.to())
+        } else {
+            Err(RepoError::InvalidTransition { from: current, transition: self })
+        }
+    }
+}
+
+impl fmt::Display for PollTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PollTransition::Publish => "publish",
+            PollTransition::Close => "close",
+            PollTransition::Reopen => "reopen",
+            PollTransition::Archive => "archive",
+        })
+    }
+}
+
+/**
+Which polls to list, built up from [PollFilter::new] with one method per criterion.
+Criteria left unset match every poll; all set criteria must hold. Drafts are the
+exception: they only match for their creator, given as the [PollFilter::viewer].
+Each store turns it into a query that only carries the values as bound parameters.
+*/
+#[derive(Clone, Default, Debug)]
+pub struct PollFilter {
+    pub(crate) creator_id: Option<Uuid>,
+    pub(crate) state: Option<PollState>,
+    // Who is listing, so that their own drafts are included
+    pub(crate) viewer: Option<Uuid>,
+    // Bounds on `created_at`, which is stored as the text of a UTC `NaiveDateTime`
+    pub(crate) created_after: Option<NaiveDateTime>,
+    pub(crate) created_before: Option<NaiveDateTime>,
+    // Case-insensitive substring of the title
+    pub(crate) title_search: Option<String>,
+    // The poll must carry every one of these tags
+    pub(crate) tags: Vec<String>,
+}
+
+impl PollFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn creator(mut self, creator_id: Uuid) -> Self {
+        self.creator_id = Some(creator_id);
+        self
+    }
+
+    pub fn state(mut self, state: PollState) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    pub fn viewer(mut self, viewer: Uuid) -> Self {
+        self.viewer = Some(viewer);
+        self
+    }
+
+    // Whether a poll in `state` by `creator_id` may be listed for the viewer
+    pub(crate) fn shows(&self, state: PollState, creator_id: Uuid) -> bool {
+        state != PollState::Draft || self.viewer == Some(creator_id)
+    }
+
+    pub fn created_after(mut self, at: NaiveDateTime) -> Self {
+        self.created_after = Some(at);
+        self
+    }
+
+    pub fn created_before(mut self, at: NaiveDateTime) -> Self {
+        self.created_before = Some(at);
+        self
+    }
+
+    pub fn title_contains(mut self, search: &str) -> Self {
+        self.title_search = Some(search.to_string());
+        self
+    }
+
+    pub fn tagged(mut self, tag: &str) -> Self {
+        self.tags.push(tag.to_string());
+        self
+    }
+
+    // The title search as a LIKE pattern, with the pattern characters in it escaped
+    pub(crate) fn title_pattern(&self) -> Option<String> {
+        self.title_search.as_ref().map(|search| {
+            let escaped = search.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+            format!("%{}%", escaped)
+        })
+    }
+
+    // The WHERE clause for Postgres, with `?` placeholders for its parameters
+    fn to_sql(&self) -> (String, Vec<Box<dyn ToSql + Sync + Send>>) {
+        let mut conditions: Vec<&str> = Vec::new();
+        let mut params: Vec<Box<dyn ToSql + Sync + Send>> = Vec::new();
+        let mut bind = |condition: &'static str, value: Box<dyn ToSql + Sync + Send>| {
+            params.push(value);
+            conditions.push(condition);
+        };
+        if let Some(creator_id) = self.creator_id {
+            bind("p.creator_id = ?", Box::new(creator_id));
+        }
+        if let Some(state) = self.state {
+            bind("p.state = ?", Box::new(state.as_str()));
+        }
+        bind("(p.state <> 'draft' OR p.creator_id = ?)", Box::new(self.viewer));
+        if let Some(after) = self.created_after {
+            bind("p.created_at >= ?", Box::new(after.to_string()));
+        }
+        if let Some(before) = self.created_before {
+            bind("p.created_at < ?", Box::new(before.to_string()));
+        }
+        if let Some(pattern) = self.title_pattern() {
+            bind("p.title ILIKE ?", Box::new(pattern));
+        }
+        if !self.tags.is_empty() {
+            bind("p.tags @> ?", Box::new(self.tags.clone()));
+        }
+        (conditions.join(" AND "), params)
+    }
+}
+
+// The order polls are listed in. Ties are broken by id, so pages never overlap or skip.
+#[derive(Clone, Copy, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PollSort {
+    #[default]
+    Newest,
+    Oldest,
+    MostVotes,
+    // By `closes_at`; polls without one come last
+    ClosingSoonest,
+}
+
+impl PollSort {
+    // ORDER BY over `p`, the polls with their `total_votes`
+    fn order_by(self) -> &'static str {
+        match self {
+            PollSort::Newest => "p.created_at DESC, p.id DESC",
+            PollSort::Oldest => "p.created_at ASC, p.id ASC",
+            PollSort::MostVotes => "p.total_votes DESC, p.id ASC",
+            PollSort::ClosingSoonest => "p.closes_at ASC NULLS LAST, p.id ASC",
+        }
+    }
+}
+
+// A value bound by a paging condition, in a form either database can take
+pub(crate) enum SqlValue {
+    Text(String),
+    Int(i32),
+    BigInt(i64),
+}
+
+/**
+Where a page of polls ended: the sort key and id of its last poll, in the sort it was
+listed in. Clients only see it [encoded](PollCursor::encode) as an opaque string and
+send it back to get the next page.
+*/
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PollCursor {
+    sort: PollSort,
+    id: i32,
+    created_at: String,
+    total_votes: i64,
+    closes_at: Option<String>,
+}
+
+impl PollCursor {
+    pub fn after(sort: PollSort, poll: &Poll) -> Self {
+        PollCursor {
+            sort,
+            id: poll.id,
+            created_at: poll.created_at.clone(),
+            total_votes: poll.total_votes(),
+            closes_at: poll.closes_at.clone(),
+        }
+    }
+
+    pub fn sort(&self) -> PollSort {
+        self.sort
+    }
+
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    pub fn decode(cursor: &str) -> Option<Self> {
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()
+    }
+
+    // How the polls at `self` and `other` are ordered, the same way as [PollSort::order_by]
+    fn compare(&self, other: &PollCursor) -> Ordering {
+        let by_id = self.id.cmp(&other.id);
+        match self.sort {
+            PollSort::Newest => other.created_at.cmp(&self.created_at).then(by_id.reverse()),
+            PollSort::Oldest => self.created_at.cmp(&other.created_at).then(by_id),
+            PollSort::MostVotes => other.total_votes.cmp(&self.total_votes).then(by_id),
+            PollSort::ClosingSoonest => match (&self.closes_at, &other.closes_at) {
+                (Some(a), Some(b)) => a.cmp(b),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            }
+            .then(by_id),
+        }
+    }
+
+    // The condition on `p` that selects the polls after this one, with `?` placeholders
+    fn condition(&self) -> (&'static str, Vec<SqlValue>) {
+        let id = SqlValue::Int(self.id);
+        match (self.sort, &self.closes_at) {
+            (PollSort::Newest, _) => (
+                "(p.created_at < ? OR (p.created_at = ? AND p.id < ?))",
+                vec![SqlValue::Text(self.created_at.clone()), SqlValue::Text(self.created_at.clone()), id],
+            ),
+            (PollSort::Oldest, _) => (
+                "(p.created_at > ? OR (p.created_at = ? AND p.id > ?))",
+                vec![SqlValue::Text(self.created_at.clone()), SqlValue::Text(self.created_at.clone()), id],
+            ),
+            (PollSort::MostVotes, _) => (
+                "(p.total_votes < ? OR (p.total_votes = ? AND p.id > ?))",
+                vec![SqlValue::BigInt(self.total_votes), SqlValue::BigInt(self.total_votes), id],
+            ),
+            (PollSort::ClosingSoonest, Some(closes_at)) => (
+                "(p.closes_at > ? OR (p.closes_at = ? AND p.id > ?) OR p.closes_at IS NULL)",
+                vec![SqlValue::Text(closes_at.clone()), SqlValue::Text(closes_at.clone()), id],
+            ),
+            (PollSort::ClosingSoonest, None) => ("(p.closes_at IS NULL AND p.id > ?)", vec![id]),
+        }
+    }
+}
 
-pub(crate) struct PollRepo<'a> {
-    pub(crate) client: &'a Client,
+// One page of a poll listing: its order, how many polls at most, and where to start
+#[derive(Clone, Debug, Default)]
+pub struct PollPage {
+    pub(crate) sort: PollSort,
+    pub(crate) limit: Option<i64>,
+    pub(crate) after: Option<PollCursor>,
 }
 
-impl<'a> PollRepo<'a> {
-    pub async fn insert_poll(&self , title : &str , creator_id: Uuid, options : &Vec<String>) -> Result<i32 , RepoError> {
+impl PollPage {
+    // Every poll, oldest first
+    pub fn all() -> Self {
+        PollPage { sort: PollSort::Oldest, limit: None, after: None }
+    }
+
+    /**
+    The query for this page of the polls matching `condition` (a WHERE clause over
+    `polls p`), with their options in id order. Placeholders are `?`: those of
+    `condition` come first, followed by the returned values of the cursor.
+    */
+    pub(crate) fn to_sql(&self, condition: &str) -> (String, Vec<SqlValue>) {
+        let (after, values) = match &self.after {
+            Some(cursor) => cursor.condition(),
+            None => ("1 = 1", Vec::new()),
+        };
+        let limit = self.limit.map(|l| format!("LIMIT {}", l)).unwrap_or_default();
+        let order = self.sort.order_by();
+        let query = format!(
+            r#"WITH page AS (
+                   SELECT p.* FROM (
+                       SELECT p.id, p.title, p.creator_id, p.tags, p.created_at, p.closes_at, p.state,
+                              (SELECT COALESCE(SUM(o.votes), 0) FROM poll_options o WHERE o.poll_id = p.id) AS total_votes
+                       FROM polls p
+                       WHERE {condition}
+                   ) p
+                   WHERE {after}
+                   ORDER BY {order}
+                   {limit}
+               )
+               SELECT p.id, p.title, p.creator_id, p.tags, p.created_at, p.closes_at, p.state, po.option_text, po.votes
+               FROM page p
+               LEFT JOIN poll_options po ON po.poll_id = p.id
+               ORDER BY {order}, po.id"#
+        );
+        (query, values)
+    }
+
+    // This page of `polls`, for stores that filter in memory
+    pub(crate) fn apply(&self, mut polls: Vec<Poll>) -> Vec<Poll> {
+        polls.sort_by(|a, b| PollCursor::after(self.sort, a).compare(&PollCursor::after(self.sort, b)));
+        if let Some(after) = &self.after {
+            polls.retain(|p| PollCursor::after(after.sort, p).compare(after) == Ordering::Greater);
+        }
+        if let Some(limit) = self.limit {
+            polls.truncate(usize::try_from(limit).unwrap_or(0));
+        }
+        polls
+    }
+}
+
+// Number the `?` placeholders of a query as Postgres expects them
+fn number_placeholders(query: &str) -> String {
+    let mut numbered = String::with_capacity(query.len());
+    let mut n = 0;
+    for c in query.chars() {
+        if c == '?' {
+            n += 1;
+            numbered.push_str(&format!("${}", n));
+        } else {
+            numbered.push(c);
+        }
+    }
+    numbered
+}
+
+// Rows of (poll id, title, creator, tags, created at, closes at, state, option text,
+// votes), ordered by poll, into polls
+fn polls_from_rows(rows: &[Row]) -> Result<Vec<Poll>, RepoError> {
+    let mut polls: Vec<Poll> = Vec::new();
+    for row in rows {
+        let poll_id: i32 = row.get(0);
+        if polls.last().is_none_or(|p| p.id != poll_id) {
+            polls.push(Poll {
+                id: poll_id,
+                title: row.get(1),
+                creator_id: row.get(2),
+                tags: row.get(3),
+                created_at: row.get(4),
+                closes_at: row.get(5),
+                state: row.get::<_, &str>(6).parse()?,
+                options: Vec::new(),
+            });
+        }
+        let option_text: Option<String> = row.get(7);
+        let votes: Option<i32> = row.get(8);
+        if let (Some(option_text), Some(votes), Some(poll)) = (option_text, votes, polls.last_mut()) {
+            poll.options.push(PollOptions { option_text, votes });
+        }
+    }
+    Ok(polls)
+}
+
+// Works on a plain connection or, for multi-statement writes, a [RepoTransaction]
+pub(crate) struct PollRepo<'a, C: GenericClient = Client> {
+    pub(crate) client: &'a C,
+}
+
+impl<'a, C: GenericClient + Sync> PollRepo<'a, C> {
+    pub async fn insert_poll(&self, poll: &NewPoll<'_>) -> Result<i32 , RepoError> {
         println!("inside the insert_poll function");
-        let query  = "INSERT INTO polls (title, creator_id ,created_at) VALUES ($1,$2,$3) RETURNING id";
+        let query  = r#"INSERT INTO polls (title, creator_id ,created_at, require_passkey_vote, allowed_aaguids, allowed_attestation_cas, tags, closes_at, allow_vote_change, state,
+                                           min_selections, max_selections)
+                        VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,$12) RETURNING id"#;
         let curr_time : NaiveDateTime = Utc::now().naive_utc();
+        let closes_at = poll.closes_at.map(|at| at.to_string());
         let row = self.client
-        .query_opt(query, &[&title , &creator_id , &curr_time.to_string()])
+        .query_opt(query, &[&poll.title , &poll.creator_id , &curr_time.to_string(), &poll.require_passkey_vote, &poll.allowed_aaguids, &poll.allowed_attestation_cas, &poll.tags, &closes_at, &poll.allow_vote_change, &poll.state.as_str(), &poll.min_selections, &poll.max_selections])
         .await.map_err(|e| {
             println!("error : {:?}",e);
             RepoError::DatabaseQueryError 
@@ -79,12 +599,12 @@ impl<'a> PollRepo<'a> {
         println!("inserted successfully ig");
         let poll_id = row.ok_or(RepoError::DatabaseQueryError)?.get::<_, i32>(0);
         println!("poll id passed : {:?} ",poll_id);
-        self.insert_poll_options(poll_id,  options).await.unwrap();
+        self.insert_poll_options(poll_id,  poll.options).await?;
         println!("yeah going to insert into poll options noow");
         Ok(poll_id)
     }
 
-    async fn insert_poll_options(&self , poll_id: i32 ,options: &Vec<String> ) -> Result<(),RepoError> {
+    async fn insert_poll_options(&self , poll_id: i32 ,options: &[String] ) -> Result<(),RepoError> {
         let mut query = String::from("INSERT INTO poll_options (poll_id, option_text) VALUES ");
     
     let mut params: Vec<&(dyn tokio_postgres::types::ToSql + Sync)> = vec![];
@@ -106,134 +626,77 @@ impl<'a> PollRepo<'a> {
         Ok(())
     }
 
-    pub async fn get_all_polls(&self) -> Result<Vec<Poll>, RepoError> {
-        let query = r#"SELECT p.id, p.title, p.creator_id, 
-                   po.option_text, 
-                   po.votes 
-            FROM polls p
-            LEFT JOIN poll_options po ON p.id = po.poll_id
-            GROUP BY p.id, p.title, p.creator_id, po.id"#; 
-
-        let rows = self.client.query(query, &[]).await.map_err(|_| RepoError::DatabaseQueryError)?;
-    
-        let mut polls_map: HashMap<i32, Poll> = HashMap::new();
-        for row in rows {
-            let poll_id: i32 = row.get(0);
-            let title: String = row.get(1);
-            let creator_id: Uuid = row.get(2);
-            let option_text: String = row.get(3);
-            let vote_count: i32 = row.get(4);
-
-            let poll = polls_map.entry(poll_id).or_insert(Poll {
-                id: poll_id,
-                title: title.clone(),
-                creator_id: creator_id.clone(),
-                options: Vec::new(),
-            });
-
-            poll.options.push(PollOptions {
-                option_text,
-                votes: vote_count,
+    pub async fn get_polls_page(&self, filter: &PollFilter, page: &PollPage) -> Result<Vec<Poll>, RepoError> {
+        let (condition, mut params) = filter.to_sql();
+        let (query, cursor_values) = page.to_sql(&condition);
+        for value in cursor_values {
+            params.push(match value {
+                SqlValue::Text(v) => Box::new(v),
+                SqlValue::Int(v) => Box::new(v),
+                SqlValue::BigInt(v) => Box::new(v),
             });
         }
-        let polls: Vec<Poll> = polls_map.into_iter().map(|(_, poll)| poll).collect();
-        Ok(polls)
-    }
-
-    pub async fn get_polls_filtered(
-        &self,
-        creator_id: Option<Uuid>, 
-        live: Option<bool>, 
-        closed: Option<bool>
-    )->Result<Vec<Poll>, RepoError> {
-        let mut query = r#"
-            SELECT p.id, p.title, p.creator_id, 
-                po.option_text, 
-                po.votes 
-            FROM polls p
-            LEFT JOIN poll_options po ON p.id = po.poll_id
-        "#.to_string();
-        let mut conditions = Vec::new();
-        if let Some(creator_id) = creator_id {
-            conditions.push(format!("p.creator_id = '{}'", creator_id));
-        }
-        if let Some(live) = live {
-            if live {
-                conditions.push("p.closed = false".to_string());
-            }
-        }
-        if let Some(closed) = closed {
-            if closed {
-                conditions.push("p.closed = true".to_string());
-            }
-        }
-        if !conditions.is_empty() {
-            query.push_str(" WHERE ");
-            query.push_str(&conditions.join(" AND "));
-        }
-        query.push_str(" GROUP BY p.id, p.title, p.creator_id, po.id");
-        println!("query:  {:?}",query);
-        let rows = self.client.query(&query,&[]).await.map_err(|_| RepoError::DatabaseQueryError)?;
-        let mut polls_map: HashMap<i32, Poll> = HashMap::new();
-        for row in rows {
-            let poll_id: i32 = row.get(0);
-            let title: String = row.get(1);
-            let creator_id: Uuid = row.get(2);
-            let option_text: String = row.get(3);
-            let vote_count: i32 = row.get(4);
-
-            let poll = polls_map.entry(poll_id).or_insert(Poll {
-                id: poll_id,
-                title: title.clone(),
-                creator_id: creator_id.clone(),
-                options: Vec::new(),
-            });
+        let params: Vec<&(dyn ToSql + Sync)> = params.iter().map(|p| p.as_ref() as &(dyn ToSql + Sync)).collect();
+        let rows = self.client.query(&number_placeholders(&query), &params).await.map_err(|e| {
+            log::error!("Error listing polls: {:?}", e);
+            RepoError::DatabaseQueryError
+        })?;
+        polls_from_rows(&rows)
+    }
 
-            poll.options.push(PollOptions {
-                option_text,
-                votes: vote_count,
-            });
-        }
-        let polls: Vec<Poll> = polls_map.into_iter().map(|(_, poll)| poll).collect();
-        Ok(polls)
-    }
-
-    pub async fn get_polls_by_creator(&self, user_id: Uuid) -> Result<Vec<Poll>, RepoError> {
-        
-        let query = r#"
-            SELECT p.id, p.title, p.creator_id, 
-                po.option_text, 
-                po.votes 
-            FROM polls p
-            LEFT JOIN poll_options po ON p.id = po.poll_id
-            WHERE p.creator_id = $1
-            GROUP BY p.id, p.title, p.creator_id, po.id
-        "#.to_string();
-        let rows = self.client.query(&query, &[&user_id]).await.map_err(|_| RepoError::DatabaseQueryError)?;
-        let mut polls_map: HashMap<i32, Poll> = HashMap::new();
-
-        for row in rows {
-            let poll_id: i32 = row.get(0);
-            let title: String = row.get(1);
-            let creator_id: Uuid = row.get(2);
-            let option_text: String = row.get(3);
-            let vote_count: i32 = row.get(4);
-
-            let poll = polls_map.entry(poll_id).or_insert(Poll {
-                id: poll_id,
-                title: title.clone(),
-                creator_id: creator_id.clone(),
-                options: Vec::new(),
-            });
+    /**
+    Full-text search over titles and options for polls matching the filter, best match
+    first. Every term must prefix-match a word of the title or of one option; title
+    matches weigh double.
+    */
+    pub async fn search_polls(&self, terms: &[String], filter: &PollFilter, limit: i64) -> Result<Vec<PollSearchHit>, RepoError> {
+        let (condition, filter_params) = filter.to_sql();
+        let query = format!(
+            r#"WITH q AS (SELECT to_tsquery('english', ?) AS query, ?::text AS highlight)
+               SELECT p.id, p.title, p.creator_id, p.created_at,
+                      (2 * ts_rank(p.search_vector, q.query) + COALESCE(SUM(ts_rank(po.search_vector, q.query)), 0))::real AS rank,
+                      ts_headline('english', p.title, q.query, q.highlight) AS title_snippet,
+                      COALESCE(
+                          array_agg(ts_headline('english', po.option_text, q.query, q.highlight) ORDER BY po.id)
+                              FILTER (WHERE po.id IS NOT NULL),
+                          '{{}}'
+                      ) AS option_snippets
+               FROM polls p
+               CROSS JOIN q
+               LEFT JOIN poll_options po ON po.poll_id = p.id AND po.search_vector @@ q.query
+               WHERE ({condition}) AND (p.search_vector @@ q.query OR po.id IS NOT NULL)
+               GROUP BY p.id, q.query, q.highlight
+               ORDER BY rank DESC, p.id DESC
+               LIMIT {limit}"#
+        );
+        let mut params: Vec<Box<dyn ToSql + Sync + Send>> = vec![Box::new(prefix_tsquery(terms)), Box::new(HEADLINE_OPTIONS)];
+        params.extend(filter_params);
+        let params: Vec<&(dyn ToSql + Sync)> = params.iter().map(|p| p.as_ref() as &(dyn ToSql + Sync)).collect();
+        let rows = self.client.query(&number_placeholders(&query), &params).await.map_err(|e| {
+            log::error!("Error searching polls: {:?}", e);
+            RepoError::DatabaseQueryError
+        })?;
+        Ok(rows
+            .iter()
+            .map(|row| PollSearchHit {
+                poll_id: row.get(0),
+                title: row.get(1),
+                creator_id: row.get(2),
+                created_at: row.get(3),
+                rank: row.get(4),
+                title_snippet: marked_snippet(row.get(5)),
+                option_snippets: row.get::<_, Vec<String>>(6).iter().map(|s| marked_snippet(s)).collect(),
+            })
+            .collect())
+    }
 
-            poll.options.push(PollOptions {
-                option_text,
-                votes: vote_count,
-            });
-        }
-        let polls: Vec<Poll> = polls_map.into_iter().map(|(_, poll)| poll).collect();
-        Ok(polls)
-    } 
+    pub async fn count_polls(&self, filter: &PollFilter) -> Result<i64, RepoError> {
+        let (condition, params) = filter.to_sql();
+        let query = number_placeholders(&format!("SELECT COUNT(*) FROM polls p WHERE {}", condition));
+        let params: Vec<&(dyn ToSql + Sync)> = params.iter().map(|p| p.as_ref() as &(dyn ToSql + Sync)).collect();
+        let row = self.client.query_one(&query, &params).await.map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(row.get(0))
+    }
 
     pub async fn get_option_id_by_text_and_poll_id(&self , option_text: String , poll_id: i32) ->Result<Option<i32>, RepoError> {
         println!("inside get option by text and poll id");
@@ -242,20 +705,48 @@ impl<'a> PollRepo<'a> {
         Ok(rows.iter().map(|row| row.get(0)).next())
     }
 
-    pub async fn increment_vote_count(&self , option_id: i32) -> Result<(), RepoError>{
-        println!("inside increment vote function");
-        let query = "UPDATE poll_options SET votes = votes + 1 WHERE id = $1";
-        self.client.execute(query, &[&option_id]).await?;
-        Ok(())
+    // Add `delta` to the count of each option, returning them in id order
+    pub async fn adjust_vote_counts(&self, option_ids: &[i32], delta: i32) -> Result<Vec<OptionTally>, RepoError> {
+        let query = "UPDATE poll_options SET votes = votes + $2 WHERE id = ANY($1) RETURNING id, option_text, votes";
+        let rows = self.client.query(query, &[&option_ids, &delta]).await?;
+        let mut tallies: Vec<OptionTally> = rows
+            .iter()
+            .map(|row| OptionTally { option_id: row.get(0), option_text: row.get(1), votes: row.get(2) })
+            .collect();
+        tallies.sort_by_key(|t| t.option_id);
+        Ok(tallies)
     }
 
-    pub async fn close_poll(&self, poll_id: i32) -> Result<(), RepoError> {
-        let query = "UPDATE polls SET closed = TRUE WHERE id = $1";
-        self.client.execute(query, &[&poll_id]).await.map_err(|_| RepoError::DatabaseQueryError)?;
-        Ok(())
+    pub async fn count_voters(&self, poll_id: i32) -> Result<i64, RepoError> {
+        let row = self.client.query_one("SELECT COUNT(*) FROM votes WHERE poll_id = $1", &[&poll_id]).await?;
+        Ok(row.get(0))
+    }
+
+    /**
+    Move a poll through `transition`, returning the state it left, or `None` if there
+    is no such poll. The state is read under a row lock, so transitions of one poll take
+    turns and a vote never lands after the poll closed. Call it in a transaction.
+    */
+    pub async fn transition_poll(&self, poll_id: i32, transition: PollTransition) -> Result<Option<PollState>, RepoError> {
+        let row = self.client.query_opt("SELECT state FROM polls WHERE id = $1 FOR UPDATE", &[&poll_id]).await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let from: PollState = row.get::<_, &str>(0).parse()?;
+        let to = transition.apply(from)?;
+        self.client.execute("UPDATE polls SET state = $2 WHERE id = $1", &[&poll_id, &to.as_str()]).await?;
+        Ok(Some(from))
+    }
+
+    // A poll's state, kept from changing until the transaction ends
+    pub async fn lock_poll_state(&self, poll_id: i32) -> Result<Option<PollState>, RepoError> {
+        let row = self.client.query_opt("SELECT state FROM polls WHERE id = $1 FOR SHARE", &[&poll_id]).await?;
+        row.map(|row| row.get::<_, &str>(0).parse()).transpose()
     }
 
     pub async fn reset_votes(&self , poll_id: i32) -> Result<(), RepoError> {
+        // The votes go too, with their selections, so that everybody can vote again
+        self.client.execute("DELETE FROM votes WHERE poll_id = $1", &[&poll_id]).await.map_err(|_| RepoError::DatabaseQueryError)?;
         let query = "UPDATE poll_options SET votes = 0 WHERE poll_id = $1";
         self.client.execute(query, &[&poll_id]).await.map_err(|_| RepoError::DatabaseQueryError)?;
         Ok(())
@@ -268,17 +759,57 @@ impl<'a> PollRepo<'a> {
     }
 
 
-    pub async fn insert_vote_details(&self, user_id: Uuid, poll_id: i32, option_id: i32,voted_at:String) -> Result<(), RepoError> {
+    // `assertion` is the passkey evidence kept for polls that require confirmed votes.
+    // Returns false, inserting nothing, when the user already voted on the poll
+    pub async fn insert_vote_details(&self, user_id: Uuid, poll_id: i32, voted_at:&str, assertion: Option<&serde_json::Value>) -> Result<bool, RepoError> {
         println!("inside insert vote details function");
-        let query = "INSERT INTO votes (user_id, poll_id, option_id, voted_at) VALUES ($1, $2, $3, $4)";
-        self.client.execute(query, &[&user_id, &poll_id, &option_id , &voted_at]).await?;
+        let query = r#"INSERT INTO votes (user_id, poll_id, voted_at, assertion) VALUES ($1, $2, $3, $4)
+                       ON CONFLICT (user_id, poll_id) DO NOTHING"#;
+        let inserted = self.client.execute(query, &[&user_id, &poll_id, &voted_at, &assertion]).await?;
+        Ok(inserted == 1)
+    }
+
+    // The options the user selected, in id order, locking the vote until the transaction ends
+    pub async fn lock_vote(&self, user_id: Uuid, poll_id: i32) -> Result<Option<Vec<i32>>, RepoError> {
+        let query = "SELECT 1 FROM votes WHERE user_id = $1 AND poll_id = $2 FOR UPDATE";
+        if self.client.query_opt(query, &[&user_id, &poll_id]).await?.is_none() {
+            return Ok(None);
+        }
+        let query = "SELECT option_id FROM vote_selections WHERE user_id = $1 AND poll_id = $2 ORDER BY option_id";
+        let rows = self.client.query(query, &[&user_id, &poll_id]).await?;
+        Ok(Some(rows.iter().map(|row| row.get(0)).collect()))
+    }
+
+    pub async fn replace_vote(&self, user_id: Uuid, poll_id: i32, voted_at: &str, assertion: Option<&serde_json::Value>) -> Result<(), RepoError> {
+        let query = "UPDATE votes SET voted_at = $3, assertion = $4 WHERE user_id = $1 AND poll_id = $2";
+        self.client.execute(query, &[&user_id, &poll_id, &voted_at, &assertion]).await?;
+        Ok(())
+    }
+
+    pub async fn insert_selections(&self, user_id: Uuid, poll_id: i32, option_ids: &[i32]) -> Result<(), RepoError> {
+        let query = "INSERT INTO vote_selections (user_id, poll_id, option_id) SELECT $1, $2, unnest($3::int[])";
+        self.client.execute(query, &[&user_id, &poll_id, &option_ids]).await?;
+        Ok(())
+    }
+
+    pub async fn delete_selections(&self, user_id: Uuid, poll_id: i32, option_ids: &[i32]) -> Result<(), RepoError> {
+        let query = "DELETE FROM vote_selections WHERE user_id = $1 AND poll_id = $2 AND option_id = ANY($3)";
+        self.client.execute(query, &[&user_id, &poll_id, &option_ids]).await?;
         Ok(())
     }
 
+    // Returns the options the deleted vote selected, in id order
+    pub async fn delete_vote(&self, user_id: Uuid, poll_id: i32) -> Result<Option<Vec<i32>>, RepoError> {
+        let query = "DELETE FROM vote_selections WHERE user_id = $1 AND poll_id = $2 RETURNING option_id";
+        let mut option_ids: Vec<i32> = self.client.query(query, &[&user_id, &poll_id]).await?.iter().map(|row| row.get(0)).collect();
+        option_ids.sort_unstable();
+        let deleted = self.client.execute("DELETE FROM votes WHERE user_id = $1 AND poll_id = $2", &[&user_id, &poll_id]).await?;
+        Ok((deleted == 1).then_some(option_ids))
+    }
+
     pub async fn get_poll_by_id(&self , poll_id: i32) -> Result<Option<PollDetails> ,RepoError>{
-        let query = "select * from polls where id = $1";
-        let row = self.client.query_opt(query, &[&poll_id]).await.expect("database query failed for get poll by id fn");
-        print!("row : {:?}",row);
+        let query = "SELECT p.*, (SELECT COUNT(*) FROM votes v WHERE v.poll_id = p.id) AS voters FROM polls p WHERE p.id = $1";
+        let row = self.client.query_opt(query, &[&poll_id]).await?;
         match row { 
             Some(row) => Ok(Some(PollDetails {
                 id : row.get("id"),
@@ -286,14 +817,23 @@ impl<'a> PollRepo<'a> {
                 creator_id: row.get("creator_id"),
                 created_at: row.get("created_at"),
                 options: Vec::new(),
-                closed: row.get("closed"),
+                state: row.get::<_, &str>("state").parse()?,
+                require_passkey_vote: row.get("require_passkey_vote"),
+                allowed_aaguids: row.get("allowed_aaguids"),
+                allowed_attestation_cas: row.get("allowed_attestation_cas"),
+                tags: row.get("tags"),
+                closes_at: row.get("closes_at"),
+                allow_vote_change: row.get("allow_vote_change"),
+                min_selections: row.get("min_selections"),
+                max_selections: row.get("max_selections"),
+                voters: row.get("voters"),
             })),
             None => Ok(None),
         }
     }
 
     pub async fn get_poll_options_with_votes(&self, poll_id: i32) -> Result<Vec<PollOptions>, RepoError> {
-        let query = "SELECT option_text, votes FROM poll_options WHERE poll_id = $1";
+        let query = "SELECT option_text, votes FROM poll_options WHERE poll_id = $1 ORDER BY id";
         let rows = self.client.query(query, &[&poll_id]).await?;
 
         let options = rows.iter()
@@ -305,12 +845,205 @@ impl<'a> PollRepo<'a> {
             println!("options:  {:?}",options);
         Ok(options)
     }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SORTS: [PollSort; 4] = [PollSort::Newest, PollSort::Oldest, PollSort::MostVotes, PollSort::ClosingSoonest];
+
+    fn poll(id: i32, created_at: &str, votes: i32, closes_at: Option<&str>) -> Poll {
+        Poll {
+            id,
+            title: format!("Poll {}", id),
+            creator_id: Uuid::nil(),
+            state: PollState::Open,
+            tags: Vec::new(),
+            created_at: created_at.to_string(),
+            closes_at: closes_at.map(str::to_string),
+            options: vec![PollOptions { option_text: "Yes".to_string(), votes }],
+        }
+    }
 
-    pub async fn get_vote_count(&self, option_id: i32) -> Result<i32, tokio_postgres::Error> {
-        let query = "SELECT votes FROM poll_options WHERE id = $1";
-        let row = self.client.query_one(query, &[&option_id]).await?;
-        let vote_count: i32 = row.get("votes");
-        Ok(vote_count)
+    // Ties on every sort key, broken by id
+    fn polls() -> Vec<Poll> {
+        vec![
+            poll(1, "2024-01-01 10:00:00", 3, Some("2024-02-01 00:00:00")),
+            poll(2, "2024-01-01 10:00:00", 5, None),
+            poll(3, "2024-01-02 10:00:00", 3, Some("2024-01-15 00:00:00")),
+            poll(4, "2024-01-03 10:00:00", 0, Some("2024-02-01 00:00:00")),
+            poll(5, "2024-01-03 10:00:00", 5, None),
+        ]
     }
-}
 
+    fn ids(polls: &[Poll]) -> Vec<i32> {
+        polls.iter().map(|p| p.id).collect()
+    }
+
+    #[test]
+    fn allows_each_transition_from_exactly_one_state() {
+        let states = [PollState::Draft, PollState::Open, PollState::Closed, PollState::Archived];
+        let transitions = [PollTransition::Publish, PollTransition::Close, PollTransition::Reopen, PollTransition::Archive];
+        let allowed = |transition, from| match (transition, from) {
+            (PollTransition::Publish, PollState::Draft) | (PollTransition::Reopen, PollState::Closed) => Some(PollState::Open),
+            (PollTransition::Close, PollState::Open) => Some(PollState::Closed),
+            (PollTransition::Archive, PollState::Closed) => Some(PollState::Archived),
+            _ => None,
+        };
+        for transition in transitions {
+            for from in states {
+                match (transition.apply(from), allowed(transition, from)) {
+                    (Ok(to), Some(expected)) => assert_eq!(to, expected),
+                    (Err(RepoError::InvalidTransition { from: f, transition: t }), None) => assert_eq!((f, t), (from, transition)),
+                    (result, expected) => panic!("{} from {}: {:?}, expected {:?}", transition, from, result, expected),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lets_votes_into_open_polls_and_resets_into_open_or_closed_ones() {
+        assert!(require_open(Some(PollState::Open)).is_ok());
+        assert!(matches!(require_open(Some(PollState::Closed)), Err(RepoError::PollNotOpen(PollState::Closed))));
+        assert!(require_resettable(Some(PollState::Closed)).is_ok());
+        assert!(matches!(require_resettable(Some(PollState::Archived)), Err(RepoError::NotResettable(PollState::Archived))));
+        assert!(matches!(require_resettable(Some(PollState::Draft)), Err(RepoError::NotResettable(PollState::Draft))));
+    }
+
+    #[test]
+    fn closes_open_polls_at_their_deadline() {
+        let at = |s: &str| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap();
+        let details = |state: PollState, closes_at: Option<&str>| PollDetails {
+            id: 1,
+            title: "Lunch".to_string(),
+            creator_id: Uuid::nil(),
+            state,
+            require_passkey_vote: false,
+            allowed_aaguids: Vec::new(),
+            allowed_attestation_cas: Vec::new(),
+            tags: Vec::new(),
+            options: Vec::new(),
+            created_at: "2024-01-01 10:00:00".to_string(),
+            closes_at: closes_at.map(str::to_string),
+            allow_vote_change: false,
+            min_selections: 1,
+            max_selections: 1,
+            voters: 0,
+        };
+        let poll = details(PollState::Open, Some("2024-01-02 12:00:00"));
+        assert_eq!(poll.state_at(at("2024-01-02 11:59:59")), PollState::Open);
+        assert_eq!(poll.state_at(at("2024-01-02 12:00:00")), PollState::Closed);
+        assert_eq!(details(PollState::Open, None).state_at(at("2099-01-01 00:00:00")), PollState::Open);
+        let archived = details(PollState::Archived, Some("2024-01-02 12:00:00"));
+        assert_eq!(archived.state_at(at("2024-01-03 00:00:00")), PollState::Archived);
+    }
+
+    #[test]
+    fn escapes_like_wildcards_in_the_title_search() {
+        let filter = PollFilter::new().title_contains(r"50%_off\");
+        assert_eq!(filter.title_pattern().as_deref(), Some(r"%50\%\_off\\%"));
+        assert_eq!(PollFilter::new().title_pattern(), None);
+    }
+
+    #[test]
+    fn binds_every_criterion_as_a_parameter() {
+        let at = NaiveDateTime::parse_from_str("2024-01-02 00:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let filter = PollFilter::new()
+            .creator(Uuid::nil())
+            .state(PollState::Closed)
+            .viewer(Uuid::nil())
+            .created_after(at)
+            .created_before(at)
+            .title_contains("it's")
+            .tagged("a")
+            .tagged("b");
+        let (condition, params) = filter.to_sql();
+        assert_eq!(
+            condition,
+            "p.creator_id = ? AND p.state = ? AND (p.state <> 'draft' OR p.creator_id = ?) AND p.created_at >= ? \
+             AND p.created_at < ? AND p.title ILIKE ? AND p.tags @> ?"
+        );
+        assert_eq!(params.len(), condition.matches('?').count());
+        assert!(!condition.contains("it's"));
+    }
+
+    #[test]
+    fn hides_drafts_from_everyone_but_their_creator() {
+        let (condition, params) = PollFilter::new().to_sql();
+        assert_eq!(condition, "(p.state <> 'draft' OR p.creator_id = ?)");
+        assert_eq!(params.len(), 1);
+
+        let creator = Uuid::new_v4();
+        assert!(PollFilter::new().viewer(creator).shows(PollState::Draft, creator));
+        assert!(!PollFilter::new().viewer(Uuid::new_v4()).shows(PollState::Draft, creator));
+        assert!(!PollFilter::new().shows(PollState::Draft, creator));
+        assert!(PollFilter::new().shows(PollState::Closed, creator));
+    }
+
+    #[test]
+    fn numbers_placeholders_for_postgres() {
+        assert_eq!(number_placeholders("a = ? AND (b = ? OR c = ?)"), "a = $1 AND (b = $2 OR c = $3)");
+    }
+
+    #[test]
+    fn round_trips_a_cursor() {
+        let polls = polls();
+        for sort in SORTS {
+            let cursor = PollCursor::after(sort, &polls[1]);
+            let decoded = PollCursor::decode(&cursor.encode()).unwrap();
+            assert_eq!(decoded.sort(), sort);
+            assert_eq!(decoded.compare(&cursor), Ordering::Equal);
+        }
+        assert!(PollCursor::decode("not a cursor").is_none());
+        assert!(PollCursor::decode(&URL_SAFE_NO_PAD.encode(b"{}")).is_none());
+    }
+
+    #[test]
+    fn sorts_a_page_with_ties_broken_by_id() {
+        let sorted = |sort| ids(&PollPage { sort, limit: None, after: None }.apply(polls()));
+        assert_eq!(sorted(PollSort::Newest), [5, 4, 3, 2, 1]);
+        assert_eq!(sorted(PollSort::Oldest), [1, 2, 3, 4, 5]);
+        assert_eq!(sorted(PollSort::MostVotes), [2, 5, 1, 3, 4]);
+        assert_eq!(sorted(PollSort::ClosingSoonest), [3, 1, 4, 2, 5]);
+    }
+
+    #[test]
+    fn pages_through_every_poll_once() {
+        for sort in SORTS {
+            let all = ids(&PollPage { sort, limit: None, after: None }.apply(polls()));
+            for limit in 1..=6 {
+                let mut seen = Vec::new();
+                let mut after = None;
+                loop {
+                    let page = PollPage { sort, limit: Some(limit), after: after.take() }.apply(polls());
+                    assert!(page.len() <= limit as usize);
+                    let Some(last) = page.last() else { break };
+                    after = PollCursor::decode(&PollCursor::after(sort, last).encode());
+                    seen.extend(ids(&page));
+                }
+                assert_eq!(seen, all, "{:?} in pages of {}", sort, limit);
+            }
+        }
+    }
+
+    #[test]
+    fn starts_after_the_cursor_poll() {
+        let after = PollCursor::after(PollSort::MostVotes, &polls()[0]);
+        let page = PollPage { sort: PollSort::MostVotes, limit: Some(10), after: Some(after) }.apply(polls());
+        assert_eq!(ids(&page), [3, 4]);
+        assert!(PollPage { sort: PollSort::Oldest, limit: Some(0), after: None }.apply(polls()).is_empty());
+    }
+
+    #[test]
+    fn binds_the_cursor_after_the_filter() {
+        let after = PollCursor::after(PollSort::ClosingSoonest, &polls()[1]);
+        let page = PollPage { sort: PollSort::ClosingSoonest, limit: Some(2), after: Some(after) };
+        let (query, values) = page.to_sql("p.state = ?");
+        assert!(query.contains("WHERE p.state = ?"));
+        assert!(query.contains("(p.closes_at IS NULL AND p.id > ?)"));
+        assert!(query.contains("LIMIT 2"));
+        assert!(matches!(values.as_slice(), [SqlValue::Int(2)]));
+    }
+}
@@ -0,0 +1,83 @@
+use chrono::{DateTime, Utc};
+use tokio_postgres::Client;
+use uuid::Uuid;
+
+use crate::db_operations_repo::user_passkey_repo::RepoError;
+
+pub(crate) struct QrLoginRequest {
+    pub(crate) status: String,
+    pub(crate) requester_user_agent: Option<String>,
+    pub(crate) created_at: DateTime<Utc>,
+    pub(crate) expires_at: DateTime<Utc>,
+}
+
+pub(crate) struct QrLoginRepo<'a> {
+    pub(crate) client: &'a Client,
+}
+
+impl<'a> QrLoginRepo<'a> {
+    pub(crate) async fn insert(
+        &self,
+        id: &Uuid,
+        browser_secret_hash: &str,
+        requester_user_agent: Option<&str>,
+        expires_at: &DateTime<Utc>,
+    ) -> Result<(), RepoError> {
+        self.client
+            .execute(
+                r#"INSERT INTO qr_login_requests (id, browser_secret_hash, requester_user_agent, status, expires_at, created_at)
+                   VALUES ($1, $2, $3, 'pending', $4, now())"#,
+                &[id, &browser_secret_hash, &requester_user_agent, expires_at],
+            )
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(())
+    }
+
+    pub(crate) async fn find(&self, id: &Uuid) -> Result<Option<QrLoginRequest>, RepoError> {
+        let row = self.client
+            .query_opt(
+                "SELECT status, requester_user_agent, created_at, expires_at FROM qr_login_requests WHERE id = $1",
+                &[id],
+            )
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(row.map(|r| QrLoginRequest {
+            status: r.get(0),
+            requester_user_agent: r.get(1),
+            created_at: r.get(2),
+            expires_at: r.get(3),
+        }))
+    }
+
+    // Called once the phone has produced a fresh passkey assertion
+    pub(crate) async fn approve(&self, id: &Uuid, user_id: &Uuid) -> Result<bool, RepoError> {
+        let updated = self.client
+            .execute(
+                r#"UPDATE qr_login_requests SET status = 'approved', user_id = $2, approved_at = now()
+                   WHERE id = $1 AND status = 'pending' AND expires_at > now()"#,
+                &[id, user_id],
+            )
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(updated > 0)
+    }
+
+    // Hand the approved login to the browser holding the request secret, once
+    pub(crate) async fn claim(
+        &self,
+        id: &Uuid,
+        browser_secret_hash: &str,
+    ) -> Result<Option<Uuid>, RepoError> {
+        let row = self.client
+            .query_opt(
+                r#"UPDATE qr_login_requests SET status = 'consumed'
+                   WHERE id = $1 AND browser_secret_hash = $2 AND status = 'approved' AND expires_at > now()
+                   RETURNING user_id"#,
+                &[id, &browser_secret_hash],
+            )
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(row.map(|r| r.get(0)))
+    }
+}
@@ -0,0 +1,92 @@
+use tokio_postgres::Client;
+use uuid::Uuid;
+
+use crate::db_operations_repo::user_passkey_repo::RepoError;
+use crate::rbac::{Permission, Role};
+
+pub(crate) struct RoleRepo<'a> {
+    pub(crate) client: &'a Client,
+}
+
+impl<'a> RoleRepo<'a> {
+    // Make sure every role has at least its default permissions
+    pub(crate) async fn seed_default_permissions(&self) -> Result<(), RepoError> {
+        for role in Role::ALL {
+            for permission in role.default_permissions() {
+                self.client
+                    .execute(
+                        "INSERT INTO role_permissions (role, permission) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                        &[&role.as_str(), &permission.as_str()],
+                    )
+                    .await
+                    .map_err(|_| RepoError::DatabaseQueryError)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Roles explicitly assigned to a user; everybody implicitly holds `user`
+    pub(crate) async fn roles_for_user(&self, user_id: &Uuid) -> Result<Vec<Role>, RepoError> {
+        let rows = self.client
+            .query("SELECT role FROM user_roles WHERE user_id = $1 ORDER BY role", &[user_id])
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(rows.iter().filter_map(|r| r.get::<_, String>(0).parse().ok()).collect())
+    }
+
+    // Union of the permissions of every role the user holds, including `user`
+    pub(crate) async fn permissions_for_user(&self, user_id: &Uuid) -> Result<Vec<Permission>, RepoError> {
+        let query = r#"
+            SELECT DISTINCT rp.permission FROM role_permissions rp
+            WHERE rp.role = $2
+               OR rp.role IN (SELECT role FROM user_roles WHERE user_id = $1)"#;
+        let rows = self.client
+            .query(query, &[user_id, &Role::User.as_str()])
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(rows.iter().filter_map(|r| r.get::<_, String>(0).parse().ok()).collect())
+    }
+
+    pub(crate) async fn assign_role(&self, user_id: &Uuid, role: Role) -> Result<(), RepoError> {
+        self.client
+            .execute(
+                "INSERT INTO user_roles (user_id, role) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                &[user_id, &role.as_str()],
+            )
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(())
+    }
+
+    pub(crate) async fn revoke_role(&self, user_id: &Uuid, role: Role) -> Result<bool, RepoError> {
+        let removed = self.client
+            .execute(
+                "DELETE FROM user_roles WHERE user_id = $1 AND role = $2",
+                &[user_id, &role.as_str()],
+            )
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(removed > 0)
+    }
+
+    pub(crate) async fn admin_exists(&self) -> Result<bool, RepoError> {
+        let row = self.client
+            .query_one("SELECT EXISTS (SELECT 1 FROM user_roles WHERE role = $1)", &[&Role::Admin.as_str()])
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(row.get(0))
+    }
+
+    // Grant `admin` to the configured bootstrap user while no admin exists yet
+    pub(crate) async fn bootstrap_admin(&self, user_id: &Uuid, username: &str) -> Result<bool, RepoError> {
+        match std::env::var("BOOTSTRAP_ADMIN_USERNAME") {
+            Ok(bootstrap) if bootstrap == username => {}
+            _ => return Ok(false),
+        }
+        if self.admin_exists().await? {
+            return Ok(false);
+        }
+        self.assign_role(user_id, Role::Admin).await?;
+        Ok(true)
+    }
+}
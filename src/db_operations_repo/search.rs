@@ -0,0 +1,184 @@
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::db_operations_repo::poll_repo::Poll;
+
+// Where matches start and end in raw snippets, before they are escaped and marked up
+const MATCH_START: char = '\u{2}';
+const MATCH_END: char = '\u{3}';
+
+// `ts_headline` options producing raw snippets of the whole text
+pub(crate) const HEADLINE_OPTIONS: &str = "StartSel=\"\u{2}\", StopSel=\"\u{3}\", HighlightAll=true";
+
+/**
+A poll found by a search. The snippets are HTML-escaped, with the matching words
+wrapped in `<mark>`; `option_snippets` only has the options that matched.
+*/
+#[derive(Serialize, Debug)]
+pub struct PollSearchHit {
+    pub poll_id: i32,
+    pub title: String,
+    pub creator_id: Uuid,
+    pub created_at: String,
+    pub rank: f32,
+    pub title_snippet: String,
+    pub option_snippets: Vec<String>,
+}
+
+/**
+The words of a search, lowercased, keeping only letters and digits so that nothing the
+user types is read as query syntax. Each word matches as a prefix, and all of them
+must match the title or the same option.
+*/
+pub(crate) fn search_terms(query: &str) -> Vec<String> {
+    query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+// The terms as a Postgres `to_tsquery` expression matching every one as a prefix
+pub(crate) fn prefix_tsquery(terms: &[String]) -> String {
+    terms.iter().map(|term| format!("{}:*", term)).collect::<Vec<_>>().join(" & ")
+}
+
+// A raw snippet as HTML: escaped, with its matches in `<mark>`
+pub(crate) fn marked_snippet(raw: &str) -> String {
+    raw.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace(MATCH_START, "<mark>")
+        .replace(MATCH_END, "</mark>")
+}
+
+// `text` as a raw snippet marking the words that start with a term; `None` unless every term matched
+fn highlight(text: &str, terms: &[String]) -> Option<String> {
+    let mut matched = vec![false; terms.len()];
+    let mut snippet = String::new();
+    let mut word = String::new();
+    let mut flush = |word: &mut String, snippet: &mut String| {
+        let lower = word.to_lowercase();
+        let mut hit = false;
+        for (i, term) in terms.iter().enumerate() {
+            if lower.starts_with(term.as_str()) {
+                matched[i] = true;
+                hit = true;
+            }
+        }
+        if hit {
+            snippet.push(MATCH_START);
+            snippet.push_str(word);
+            snippet.push(MATCH_END);
+        } else {
+            snippet.push_str(word);
+        }
+        word.clear();
+    };
+    for c in text.chars() {
+        if c.is_alphanumeric() {
+            word.push(c);
+        } else {
+            flush(&mut word, &mut snippet);
+            snippet.push(c);
+        }
+    }
+    flush(&mut word, &mut snippet);
+    matched.iter().all(|m| *m).then(|| marked_snippet(&snippet))
+}
+
+/**
+Search for stores without full-text indexes: match prefixes word by word, ranking
+title matches above option matches. Postgres ranks with `ts_rank` instead, so scores
+are only comparable within one backend.
+*/
+pub(crate) fn search_in_memory(polls: Vec<Poll>, terms: &[String], limit: usize) -> Vec<PollSearchHit> {
+    let mut hits: Vec<PollSearchHit> = polls
+        .into_iter()
+        .filter_map(|poll| {
+            let title_match = highlight(&poll.title, terms);
+            let option_snippets: Vec<String> = poll.options.iter().filter_map(|o| highlight(&o.option_text, terms)).collect();
+            if title_match.is_none() && option_snippets.is_empty() {
+                return None;
+            }
+            let rank = if title_match.is_some() { 1.0 } else { 0.0 } + 0.5 * option_snippets.len() as f32;
+            Some(PollSearchHit {
+                poll_id: poll.id,
+                title_snippet: title_match.unwrap_or_else(|| marked_snippet(&poll.title)),
+                title: poll.title,
+                creator_id: poll.creator_id,
+                created_at: poll.created_at,
+                rank,
+                option_snippets,
+            })
+        })
+        .collect();
+    hits.sort_by(|a, b| b.rank.total_cmp(&a.rank).then(b.poll_id.cmp(&a.poll_id)));
+    hits.truncate(limit);
+    hits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db_operations_repo::poll_repo::{PollOptions, PollState};
+
+    fn poll(id: i32, title: &str, options: &[&str]) -> Poll {
+        Poll {
+            id,
+            title: title.to_string(),
+            creator_id: Uuid::nil(),
+            state: PollState::Open,
+            tags: Vec::new(),
+            created_at: "2024-01-01 10:00:00".to_string(),
+            closes_at: None,
+            options: options.iter().map(|o| PollOptions { option_text: o.to_string(), votes: 0 }).collect(),
+        }
+    }
+
+    #[test]
+    fn keeps_only_the_words_of_a_search() {
+        assert_eq!(search_terms("  Lunch & (pizza:*) | !Sushi's  "), ["lunch", "pizza", "sushi", "s"]);
+        assert_eq!(search_terms("ÉTÉ café"), ["été", "café"]);
+        assert!(search_terms("&|!:*()'\"").is_empty());
+    }
+
+    #[test]
+    fn matches_every_term_as_a_prefix_in_postgres() {
+        let terms = search_terms("lunch piz");
+        assert_eq!(prefix_tsquery(&terms), "lunch:* & piz:*");
+    }
+
+    #[test]
+    fn escapes_snippets_before_marking_matches() {
+        let raw = format!("<b>{}Tom & Jerry{}</b> \"quoted\"", MATCH_START, MATCH_END);
+        assert_eq!(marked_snippet(&raw), "&lt;b&gt;<mark>Tom &amp; Jerry</mark>&lt;/b&gt; &quot;quoted&quot;");
+        assert_eq!(marked_snippet("&lt;"), "&amp;lt;");
+    }
+
+    #[test]
+    fn highlights_only_when_every_term_matches() {
+        let terms = search_terms("piz lun");
+        assert_eq!(highlight("Pizza <for> lunch", &terms).as_deref(), Some("<mark>Pizza</mark> &lt;for&gt; <mark>lunch</mark>"));
+        assert_eq!(highlight("Pizza for dinner", &terms), None);
+        assert_eq!(highlight("apizza lunch", &terms), None);
+    }
+
+    #[test]
+    fn ranks_title_matches_above_option_matches() {
+        let polls = vec![
+            poll(1, "Dinner", &["Pizza place", "Sushi delivery"]),
+            poll(2, "Pizza night", &["Yes", "No"]),
+            poll(3, "Lunch", &["Sushi"]),
+            poll(4, "Dinner <script>", &["Pizza"]),
+        ];
+        let hits = search_in_memory(polls, &search_terms("pizza"), 10);
+        let ranked: Vec<(i32, f32)> = hits.iter().map(|h| (h.poll_id, h.rank)).collect();
+        assert_eq!(ranked, [(2, 1.0), (4, 0.5), (1, 0.5)]);
+        assert_eq!(hits[0].title_snippet, "<mark>Pizza</mark> night");
+        assert_eq!(hits[1].title_snippet, "Dinner &lt;script&gt;");
+        assert_eq!(hits[2].option_snippets, ["<mark>Pizza</mark> place"]);
+        assert_eq!(search_in_memory(vec![poll(2, "Pizza night", &[])], &search_terms("pizza"), 0).len(), 0);
+    }
+}
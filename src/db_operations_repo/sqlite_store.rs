@@ -0,0 +1,801 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use rusqlite::{functions::FunctionFlags, params, params_from_iter, types::Value, Connection, ErrorCode, OptionalExtension, TransactionBehavior};
+use uuid::Uuid;
+use webauthn_rs::prelude::Passkey;
+
+use crate::attestation::VerifiedAttestation;
+use crate::db::migrations::{migrate_sqlite, status_sqlite, Migration, MigrationError};
+use crate::db_operations_repo::{
+    poll_repo::{OptionTally, Poll, PollDetails, PollFilter, PollOptions, PollPage, PollState, PollTransition, RepoError as PollRepoError, VoteOutcome, VoteTally, SqlValue, require_open, require_resettable, selection_change},
+    store::{NewPoll, PollStore, RoleStore, StoreHealth, UserStore},
+    user_passkey_repo::{CredentialColumns, RepoError, StoredPasskey},
+};
+use crate::rbac::{Permission, Role};
+
+/**
+The user, poll and role stores kept in a SQLite file, for running without a Postgres
+server. One connection is shared behind a lock, and every query runs on tokio's
+blocking pool so that it never stalls the async workers. UUIDs are stored as text and
+the poll allow-lists as JSON arrays.
+*/
+pub(crate) struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+// Run `f` on the shared connection from the blocking pool
+pub(crate) async fn blocking<T, E>(
+    conn: &Arc<Mutex<Connection>>,
+    f: impl FnOnce(&mut Connection) -> Result<T, E> + Send + 'static,
+) -> Result<T, E>
+where
+    T: Send + 'static,
+    E: Send + 'static,
+{
+    let conn = conn.clone();
+    tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap_or_else(|e| e.into_inner())))
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+fn query_error(e: rusqlite::Error) -> RepoError {
+    log::error!("SQLite error: {:?}", e);
+    RepoError::DatabaseQueryError
+}
+
+fn poll_query_error(e: rusqlite::Error) -> PollRepoError {
+    log::error!("SQLite error: {:?}", e);
+    PollRepoError::DatabaseQueryError
+}
+
+// For closures that fail with both SQLite errors and poll rules
+impl From<rusqlite::Error> for PollRepoError {
+    fn from(e: rusqlite::Error) -> Self {
+        poll_query_error(e)
+    }
+}
+
+fn poll_state(conn: &Connection, poll_id: i32) -> Result<Option<PollState>, PollRepoError> {
+    let state: Option<String> = conn.query_row("SELECT state FROM polls WHERE id = ?1", [poll_id], |r| r.get(0)).optional()?;
+    state.map(|state| state.parse()).transpose()
+}
+
+fn state_column(row: &rusqlite::Row, index: usize) -> rusqlite::Result<PollState> {
+    row.get::<_, String>(index)?
+        .parse()
+        .map_err(|e: PollRepoError| rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(e)))
+}
+
+// Add `delta` to the count of each of the sorted options, returning them in the same order
+fn adjust_vote_counts(conn: &Connection, option_ids: &[i32], delta: i32) -> rusqlite::Result<Vec<OptionTally>> {
+    let mut statement = conn.prepare("UPDATE poll_options SET votes = votes + ?2 WHERE id = ?1 RETURNING id, option_text, votes")?;
+    option_ids
+        .iter()
+        .map(|option_id| {
+            statement.query_row([*option_id, delta], |r| Ok(OptionTally { option_id: r.get(0)?, option_text: r.get(1)?, votes: r.get(2)? }))
+        })
+        .collect()
+}
+
+fn insert_selections(conn: &Connection, user_id: &str, poll_id: i32, option_ids: &[i32]) -> rusqlite::Result<()> {
+    let mut statement = conn.prepare("INSERT INTO vote_selections (user_id, poll_id, option_id) VALUES (?1, ?2, ?3)")?;
+    for option_id in option_ids {
+        statement.execute(params![user_id, poll_id, option_id])?;
+    }
+    Ok(())
+}
+
+// The options the user selected, in id order
+fn vote_selections(conn: &Connection, user_id: &str, poll_id: i32) -> rusqlite::Result<Vec<i32>> {
+    conn.prepare("SELECT option_id FROM vote_selections WHERE user_id = ?1 AND poll_id = ?2 ORDER BY option_id")?
+        .query_map(params![user_id, poll_id], |r| r.get(0))?
+        .collect()
+}
+
+fn count_voters(conn: &Connection, poll_id: i32) -> rusqlite::Result<i64> {
+    conn.query_row("SELECT COUNT(*) FROM votes WHERE poll_id = ?1", [poll_id], |r| r.get(0))
+}
+
+fn parse_uuid(value: String) -> Result<Uuid, RepoError> {
+    Uuid::parse_str(&value).map_err(|_| RepoError::DatabaseQueryError)
+}
+
+// Insert a passkey row; a credential ID that is already stored is reported as such
+fn insert_passkey_row(
+    conn: &Connection,
+    user_id: &Uuid,
+    passkey: &Passkey,
+    attestation: Option<(Option<Uuid>, String)>,
+) -> Result<(), RepoError> {
+    let columns = CredentialColumns::of(passkey);
+    let passkey_data = serde_json::to_string(passkey).map_err(|_| RepoError::CorruptCredential)?;
+    let (attestation_aaguid, attestation_ca) = attestation.map_or((None, None), |(aaguid, ca)| (aaguid, Some(ca)));
+    conn.execute(
+        r#"INSERT INTO passkeys_data
+               (user_id, passkey_data, credential_id, counter, backup_eligible, backup_state, aaguid,
+                attestation_aaguid, attestation_ca)
+           VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)"#,
+        params![
+            user_id.to_string(),
+            passkey_data,
+            columns.credential_id,
+            columns.counter,
+            columns.backup_eligible,
+            columns.backup_state,
+            columns.aaguid.map(|a| a.to_string()),
+            attestation_aaguid.map(|a| a.to_string()),
+            attestation_ca,
+        ],
+    )
+    .map_err(|e| match e.sqlite_error_code() {
+        Some(ErrorCode::ConstraintViolation) => RepoError::DuplicateCredential,
+        _ => query_error(e),
+    })?;
+    Ok(())
+}
+
+fn uuid_column(row: &rusqlite::Row, index: usize) -> rusqlite::Result<Uuid> {
+    Uuid::parse_str(&row.get::<_, String>(index)?)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(e)))
+}
+
+// A column holding a JSON array
+fn json_column<T: serde::de::DeserializeOwned>(row: &rusqlite::Row, index: usize) -> rusqlite::Result<T> {
+    serde_json::from_str(&row.get::<_, String>(index)?)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(e)))
+}
+
+fn stored_passkey(counter: i64, passkey_data: String) -> Result<StoredPasskey, RepoError> {
+    Ok(StoredPasskey {
+        counter,
+        passkey: serde_json::from_str(&passkey_data).map_err(|_| RepoError::CorruptCredential)?,
+    })
+}
+
+// The WHERE clause of a [PollFilter] with its bound values, as [PollRepo](super::poll_repo::PollRepo) builds it for Postgres
+fn filter_sql(filter: &PollFilter) -> (String, Vec<Value>) {
+    let mut conditions: Vec<&str> = Vec::new();
+    let mut values: Vec<Value> = Vec::new();
+    if let Some(creator_id) = filter.creator_id {
+        conditions.push("p.creator_id = ?");
+        values.push(Value::Text(creator_id.to_string()));
+    }
+    if let Some(state) = filter.state {
+        conditions.push("p.state = ?");
+        values.push(Value::Text(state.as_str().to_string()));
+    }
+    conditions.push("(p.state <> 'draft' OR p.creator_id = ?)");
+    values.push(filter.viewer.map_or(Value::Null, |viewer| Value::Text(viewer.to_string())));
+    if let Some(after) = filter.created_after {
+        conditions.push("p.created_at >= ?");
+        values.push(Value::Text(after.to_string()));
+    }
+    if let Some(before) = filter.created_before {
+        conditions.push("p.created_at < ?");
+        values.push(Value::Text(before.to_string()));
+    }
+    // LIKE only folds ASCII letters, so both sides are lowercased first
+    if let Some(pattern) = filter.title_pattern() {
+        conditions.push("unicode_lower(p.title) LIKE ? ESCAPE '\\'");
+        values.push(Value::Text(pattern.to_lowercase()));
+    }
+    for tag in &filter.tags {
+        conditions.push("EXISTS (SELECT 1 FROM json_each(p.tags) WHERE value = ?)");
+        values.push(Value::Text(tag.clone()));
+    }
+    (conditions.join(" AND "), values)
+}
+
+// One page of the polls matching the filter, with their options in id order
+fn polls_page(conn: &Connection, filter: &PollFilter, page: &PollPage) -> Result<Vec<Poll>, PollRepoError> {
+    let (condition, mut values) = filter_sql(filter);
+    let (query, cursor_values) = page.to_sql(&condition);
+    values.extend(cursor_values.into_iter().map(|value| match value {
+        SqlValue::Text(v) => Value::Text(v),
+        SqlValue::Int(v) => Value::Integer(i64::from(v)),
+        SqlValue::BigInt(v) => Value::Integer(v),
+    }));
+    let mut statement = conn.prepare(&query).map_err(poll_query_error)?;
+    let rows = statement
+        .query_map(params_from_iter(values), |r| {
+            let poll = Poll {
+                id: r.get(0)?,
+                title: r.get(1)?,
+                creator_id: uuid_column(r, 2)?,
+                tags: json_column(r, 3)?,
+                created_at: r.get(4)?,
+                closes_at: r.get(5)?,
+                state: state_column(r, 6)?,
+                options: Vec::new(),
+            };
+            let option = match (r.get(7)?, r.get(8)?) {
+                (Some(option_text), Some(votes)) => Some(PollOptions { option_text, votes }),
+                _ => None,
+            };
+            Ok((poll, option))
+        })
+        .map_err(poll_query_error)?;
+    let mut polls: Vec<Poll> = Vec::new();
+    for row in rows {
+        let (poll, option) = row.map_err(poll_query_error)?;
+        if polls.last().is_none_or(|p| p.id != poll.id) {
+            polls.push(poll);
+        }
+        if let (Some(option), Some(poll)) = (option, polls.last_mut()) {
+            poll.options.push(option);
+        }
+    }
+    Ok(polls)
+}
+
+impl SqliteStore {
+    pub(crate) fn open(path: &str) -> Result<Self, rusqlite::Error> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.busy_timeout(std::time::Duration::from_secs(5))?;
+        // Lowercases beyond ASCII, as the memory store does, for title searches
+        conn.create_scalar_function("unicode_lower", 1, FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC, |ctx| {
+            Ok(ctx.get::<Option<String>>(0)?.map(|text| text.to_lowercase()))
+        })?;
+        Ok(SqliteStore { conn: Arc::new(Mutex::new(conn)) })
+    }
+
+    // The connection, shared with the session store
+    pub(crate) fn connection(&self) -> Arc<Mutex<Connection>> {
+        self.conn.clone()
+    }
+
+    pub(crate) fn migrate(&self, apply: bool) -> Result<Vec<&'static Migration>, MigrationError> {
+        migrate_sqlite(&mut self.conn.lock().unwrap_or_else(|e| e.into_inner()), apply)
+    }
+
+    // Make sure every role has at least its default permissions
+    pub(crate) fn seed_default_permissions(&self) -> Result<(), RepoError> {
+        let conn = self.conn.lock().unwrap_or_else(|e| e.into_inner());
+        for role in Role::ALL {
+            for permission in role.default_permissions() {
+                conn.execute(
+                    "INSERT OR IGNORE INTO role_permissions (role, permission) VALUES (?1, ?2)",
+                    params![role.as_str(), permission.as_str()],
+                )
+                .map_err(query_error)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl UserStore for SqliteStore {
+    async fn find_unique_id_by_username(&self, username: &str) -> Result<Option<Uuid>, RepoError> {
+        let username = username.to_string();
+        let unique_id: Option<String> = blocking(&self.conn, move |conn| {
+            conn.query_row("SELECT unique_id FROM users WHERE username = ?1", [username], |r| r.get(0))
+                .optional()
+                .map_err(query_error)
+        })
+        .await?;
+        unique_id.map(parse_uuid).transpose()
+    }
+
+    async fn find_username_by_id(&self, unique_id: &Uuid) -> Result<Option<String>, RepoError> {
+        let unique_id = unique_id.to_string();
+        blocking(&self.conn, move |conn| {
+            conn.query_row("SELECT username FROM users WHERE unique_id = ?1", [unique_id], |r| r.get(0))
+                .optional()
+                .map_err(query_error)
+        })
+        .await
+    }
+
+    async fn create_user(&self, unique_id: &Uuid, username: &str, passkey: &Passkey) -> Result<(), RepoError> {
+        let (unique_id, username, passkey) = (*unique_id, username.to_string(), passkey.clone());
+        blocking(&self.conn, move |conn| {
+            let tx = conn.transaction().map_err(query_error)?;
+            tx.execute("INSERT INTO users (unique_id, username) VALUES (?1, ?2)", params![unique_id.to_string(), username])
+                .map_err(query_error)?;
+            insert_passkey_row(&tx, &unique_id, &passkey, None)?;
+            tx.commit().map_err(query_error)
+        })
+        .await
+    }
+
+    async fn find_passkeys_by_user_id(&self, user_id: &Uuid) -> Result<Vec<StoredPasskey>, RepoError> {
+        let user_id = user_id.to_string();
+        let rows: Vec<(i64, String)> = blocking(&self.conn, move |conn| {
+            conn.prepare("SELECT counter, passkey_data FROM passkeys_data WHERE user_id = ?1")?
+                .query_map([user_id], |r| Ok((r.get(0)?, r.get(1)?)))?
+                .collect()
+        })
+        .await
+        .map_err(query_error)?;
+        rows.into_iter().map(|(counter, data)| stored_passkey(counter, data)).collect()
+    }
+
+    async fn find_passkey(&self, user_id: &Uuid, credential_id: &str) -> Result<Option<StoredPasskey>, RepoError> {
+        let (user_id, credential_id) = (user_id.to_string(), credential_id.to_string());
+        let row: Option<(i64, String)> = blocking(&self.conn, move |conn| {
+            conn.query_row(
+                "SELECT counter, passkey_data FROM passkeys_data WHERE user_id = ?1 AND credential_id = ?2",
+                [user_id, credential_id],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .optional()
+        })
+        .await
+        .map_err(query_error)?;
+        row.map(|(counter, data)| stored_passkey(counter, data)).transpose()
+    }
+
+    async fn insert_passkey(
+        &self,
+        user_id: &Uuid,
+        passkey: &Passkey,
+        attestation: Option<&VerifiedAttestation>,
+    ) -> Result<(), RepoError> {
+        let (user_id, passkey) = (*user_id, passkey.clone());
+        let attestation = attestation.map(|a| (a.aaguid, a.ca_fingerprint.clone()));
+        blocking(&self.conn, move |conn| insert_passkey_row(conn, &user_id, &passkey, attestation)).await
+    }
+
+    async fn update_passkey(&self, passkey: &Passkey, expected_counter: i64) -> Result<bool, RepoError> {
+        let columns = CredentialColumns::of(passkey);
+        let passkey_data = serde_json::to_string(passkey).map_err(|_| RepoError::CorruptCredential)?;
+        let updated = blocking(&self.conn, move |conn| {
+            conn.execute(
+                r#"UPDATE passkeys_data
+                   SET passkey_data = ?1, counter = ?2, backup_eligible = ?3, backup_state = ?4,
+                       last_used_at = CURRENT_TIMESTAMP
+                   WHERE credential_id = ?5 AND counter = ?6"#,
+                params![
+                    passkey_data,
+                    columns.counter,
+                    columns.backup_eligible,
+                    columns.backup_state,
+                    columns.credential_id,
+                    expected_counter,
+                ],
+            )
+        })
+        .await
+        .map_err(query_error)?;
+        Ok(updated == 1)
+    }
+
+    async fn has_attested_passkey(
+        &self,
+        user_id: &Uuid,
+        allowed_aaguids: &[Uuid],
+        allowed_cas: &[String],
+    ) -> Result<bool, RepoError> {
+        let user_id = user_id.to_string();
+        let allowed_aaguids: Vec<String> = allowed_aaguids.iter().map(|a| a.to_string()).collect();
+        let allowed_aaguids = serde_json::to_string(&allowed_aaguids).map_err(|_| RepoError::DatabaseQueryError)?;
+        let allowed_cas = serde_json::to_string(allowed_cas).map_err(|_| RepoError::DatabaseQueryError)?;
+        blocking(&self.conn, move |conn| {
+            conn.query_row(
+                r#"SELECT EXISTS (
+                       SELECT 1 FROM passkeys_data
+                       WHERE user_id = ?1
+                         AND (attestation_aaguid IN (SELECT value FROM json_each(?2))
+                              OR attestation_ca IN (SELECT value FROM json_each(?3)))
+                   )"#,
+                [user_id, allowed_aaguids, allowed_cas],
+                |r| r.get(0),
+            )
+        })
+        .await
+        .map_err(query_error)
+    }
+}
+
+#[async_trait]
+impl PollStore for SqliteStore {
+    async fn create_poll(&self, poll: NewPoll<'_>) -> Result<i32, PollRepoError> {
+        let title = poll.title.to_string();
+        let creator_id = poll.creator_id.to_string();
+        let options = poll.options.to_vec();
+        let require_passkey_vote = poll.require_passkey_vote;
+        let allowed_aaguids: Vec<String> = poll.allowed_aaguids.iter().map(|a| a.to_string()).collect();
+        let allowed_aaguids = serde_json::to_string(&allowed_aaguids).map_err(|_| PollRepoError::DatabaseQueryError)?;
+        let allowed_cas = serde_json::to_string(poll.allowed_attestation_cas).map_err(|_| PollRepoError::DatabaseQueryError)?;
+        let tags = serde_json::to_string(poll.tags).map_err(|_| PollRepoError::DatabaseQueryError)?;
+        let closes_at = poll.closes_at.map(|at| at.to_string());
+        let allow_vote_change = poll.allow_vote_change;
+        let (min_selections, max_selections) = (poll.min_selections, poll.max_selections);
+        let state = poll.state.as_str();
+        let created_at = Utc::now().naive_utc().to_string();
+        blocking(&self.conn, move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                r#"INSERT INTO polls
+                       (title, creator_id, created_at, require_passkey_vote, allowed_aaguids, allowed_attestation_cas, tags, closes_at,
+                        allow_vote_change, state, min_selections, max_selections)
+                   VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)"#,
+                params![
+                    title, creator_id, created_at, require_passkey_vote, allowed_aaguids, allowed_cas, tags, closes_at, allow_vote_change, state,
+                    min_selections, max_selections
+                ],
+            )?;
+            let poll_id = tx.last_insert_rowid() as i32;
+            for option_text in &options {
+                tx.execute("INSERT INTO poll_options (poll_id, option_text) VALUES (?1, ?2)", params![poll_id, option_text])?;
+            }
+            tx.commit()?;
+            Ok(poll_id)
+        })
+        .await
+        .map_err(poll_query_error)
+    }
+
+    async fn get_polls_page(&self, filter: &PollFilter, page: &PollPage) -> Result<Vec<Poll>, PollRepoError> {
+        let (filter, page) = (filter.clone(), page.clone());
+        blocking(&self.conn, move |conn| polls_page(conn, &filter, &page)).await
+    }
+
+    async fn count_polls(&self, filter: &PollFilter) -> Result<i64, PollRepoError> {
+        let (condition, values) = filter_sql(filter);
+        blocking(&self.conn, move |conn| {
+            conn.query_row(&format!("SELECT COUNT(*) FROM polls p WHERE {}", condition), params_from_iter(values), |r| r.get(0))
+        })
+        .await
+        .map_err(poll_query_error)
+    }
+
+    async fn get_poll_by_id(&self, poll_id: i32) -> Result<Option<PollDetails>, PollRepoError> {
+        blocking(&self.conn, move |conn| {
+            conn.query_row(
+                r#"SELECT id, title, creator_id, created_at, state, require_passkey_vote, allowed_aaguids,
+                          allowed_attestation_cas, tags, closes_at, allow_vote_change, min_selections, max_selections,
+                          (SELECT COUNT(*) FROM votes v WHERE v.poll_id = polls.id)
+                   FROM polls WHERE id = ?1"#,
+                [poll_id],
+                |r| {
+                    let aaguids: Vec<String> = json_column(r, 6)?;
+                    Ok(PollDetails {
+                        id: r.get(0)?,
+                        title: r.get(1)?,
+                        creator_id: uuid_column(r, 2)?,
+                        created_at: r.get(3)?,
+                        state: state_column(r, 4)?,
+                        require_passkey_vote: r.get(5)?,
+                        allowed_aaguids: aaguids.iter().filter_map(|a| Uuid::parse_str(a).ok()).collect(),
+                        allowed_attestation_cas: json_column(r, 7)?,
+                        tags: json_column(r, 8)?,
+                        closes_at: r.get(9)?,
+                        allow_vote_change: r.get(10)?,
+                        min_selections: r.get(11)?,
+                        max_selections: r.get(12)?,
+                        voters: r.get(13)?,
+                        options: Vec::new(),
+                    })
+                },
+            )
+            .optional()
+        })
+        .await
+        .map_err(poll_query_error)
+    }
+
+    async fn get_poll_options_with_votes(&self, poll_id: i32) -> Result<Vec<PollOptions>, PollRepoError> {
+        blocking(&self.conn, move |conn| {
+            conn.prepare("SELECT option_text, votes FROM poll_options WHERE poll_id = ?1 ORDER BY id")?
+                .query_map([poll_id], |r| Ok(PollOptions { option_text: r.get(0)?, votes: r.get(1)? }))?
+                .collect()
+        })
+        .await
+        .map_err(poll_query_error)
+    }
+
+    async fn get_option_id_by_text_and_poll_id(&self, option_text: &str, poll_id: i32) -> Result<Option<i32>, PollRepoError> {
+        let option_text = option_text.to_string();
+        blocking(&self.conn, move |conn| {
+            conn.query_row(
+                "SELECT id FROM poll_options WHERE option_text = ?1 AND poll_id = ?2",
+                params![option_text, poll_id],
+                |r| r.get(0),
+            )
+            .optional()
+        })
+        .await
+        .map_err(poll_query_error)
+    }
+
+    // The counts, the vote and its selections are written together or not at all.
+    // The immediate transaction keeps other writers out between reading the user's
+    // vote and replacing it
+    async fn record_vote(
+        &self,
+        user_id: Uuid,
+        poll_id: i32,
+        option_ids: &[i32],
+        voted_at: String,
+        assertion: Option<&serde_json::Value>,
+        allow_change: bool,
+    ) -> Result<VoteOutcome, PollRepoError> {
+        let assertion = assertion.map(|a| a.to_string());
+        let user_id = user_id.to_string();
+        let option_ids = option_ids.to_vec();
+        blocking(&self.conn, move |conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            require_open(poll_state(&tx, poll_id)?)?;
+            let voted = tx
+                .query_row("SELECT 1 FROM votes WHERE user_id = ?1 AND poll_id = ?2", params![user_id, poll_id], |_| Ok(()))
+                .optional()?
+                .is_some();
+            let outcome = if !voted {
+                tx.execute(
+                    "INSERT INTO votes (user_id, poll_id, voted_at, assertion) VALUES (?1, ?2, ?3, ?4)",
+                    params![user_id, poll_id, voted_at, assertion],
+                )?;
+                insert_selections(&tx, &user_id, poll_id, &option_ids)?;
+                let options = adjust_vote_counts(&tx, &option_ids, 1)?;
+                VoteOutcome::Recorded(VoteTally { options, voters: count_voters(&tx, poll_id)? })
+            } else if !allow_change {
+                return Err(PollRepoError::AlreadyVoted);
+            } else {
+                let previous = vote_selections(&tx, &user_id, poll_id)?;
+                if previous == option_ids {
+                    return Ok(VoteOutcome::Unchanged);
+                }
+                let (removed, added) = selection_change(&previous, &option_ids);
+                tx.execute(
+                    "UPDATE votes SET voted_at = ?3, assertion = ?4 WHERE user_id = ?1 AND poll_id = ?2",
+                    params![user_id, poll_id, voted_at, assertion],
+                )?;
+                for option_id in &removed {
+                    tx.execute(
+                        "DELETE FROM vote_selections WHERE user_id = ?1 AND poll_id = ?2 AND option_id = ?3",
+                        params![user_id, poll_id, option_id],
+                    )?;
+                }
+                insert_selections(&tx, &user_id, poll_id, &added)?;
+                let mut options = adjust_vote_counts(&tx, &removed, -1)?;
+                options.extend(adjust_vote_counts(&tx, &added, 1)?);
+                options.sort_by_key(|t| t.option_id);
+                VoteOutcome::Changed(VoteTally { options, voters: count_voters(&tx, poll_id)? })
+            };
+            tx.commit()?;
+            Ok(outcome)
+        })
+        .await
+    }
+
+    async fn retract_vote(&self, user_id: Uuid, poll_id: i32) -> Result<Option<VoteTally>, PollRepoError> {
+        let user_id = user_id.to_string();
+        blocking(&self.conn, move |conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            require_open(poll_state(&tx, poll_id)?)?;
+            let option_ids = vote_selections(&tx, &user_id, poll_id)?;
+            tx.execute("DELETE FROM vote_selections WHERE user_id = ?1 AND poll_id = ?2", params![user_id, poll_id])?;
+            let tally = if tx.execute("DELETE FROM votes WHERE user_id = ?1 AND poll_id = ?2", params![user_id, poll_id])? == 1 {
+                let options = adjust_vote_counts(&tx, &option_ids, -1)?;
+                Some(VoteTally { options, voters: count_voters(&tx, poll_id)? })
+            } else {
+                None
+            };
+            tx.commit()?;
+            Ok(tally)
+        })
+        .await
+    }
+
+    async fn transition_poll(&self, poll_id: i32, transition: PollTransition) -> Result<Option<PollState>, PollRepoError> {
+        blocking(&self.conn, move |conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let Some(from) = poll_state(&tx, poll_id)? else {
+                return Ok(None);
+            };
+            let to = transition.apply(from)?;
+            tx.execute("UPDATE polls SET state = ?2 WHERE id = ?1", params![poll_id, to.as_str()])?;
+            tx.commit()?;
+            Ok(Some(from))
+        })
+        .await
+    }
+
+    async fn reset_votes(&self, poll_id: i32) -> Result<(), PollRepoError> {
+        blocking(&self.conn, move |conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            require_resettable(poll_state(&tx, poll_id)?)?;
+            // The votes go too, so that everybody can vote again
+            tx.execute("DELETE FROM votes WHERE poll_id = ?1", [poll_id])?;
+            tx.execute("UPDATE poll_options SET votes = 0 WHERE poll_id = ?1", [poll_id])?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn is_poll_creator(&self, user_id: Uuid, poll_id: i32) -> Result<bool, PollRepoError> {
+        blocking(&self.conn, move |conn| {
+            conn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM polls WHERE id = ?1 AND creator_id = ?2)",
+                params![poll_id, user_id.to_string()],
+                |r| r.get(0),
+            )
+        })
+        .await
+        .map_err(poll_query_error)
+    }
+}
+
+#[async_trait]
+impl RoleStore for SqliteStore {
+    async fn roles_for_user(&self, user_id: &Uuid) -> Result<Vec<Role>, RepoError> {
+        let user_id = user_id.to_string();
+        let roles: Vec<String> = blocking(&self.conn, move |conn| {
+            conn.prepare("SELECT role FROM user_roles WHERE user_id = ?1 ORDER BY role")?
+                .query_map([user_id], |r| r.get(0))?
+                .collect()
+        })
+        .await
+        .map_err(query_error)?;
+        Ok(roles.iter().filter_map(|r| r.parse().ok()).collect())
+    }
+
+    async fn permissions_for_user(&self, user_id: &Uuid) -> Result<Vec<Permission>, RepoError> {
+        let user_id = user_id.to_string();
+        let permissions: Vec<String> = blocking(&self.conn, move |conn| {
+            conn.prepare(
+                r#"SELECT DISTINCT rp.permission FROM role_permissions rp
+                   WHERE rp.role = ?2
+                      OR rp.role IN (SELECT role FROM user_roles WHERE user_id = ?1)"#,
+            )?
+            .query_map([user_id.as_str(), Role::User.as_str()], |r| r.get(0))?
+            .collect()
+        })
+        .await
+        .map_err(query_error)?;
+        Ok(permissions.iter().filter_map(|p| p.parse().ok()).collect())
+    }
+
+    async fn assign_role(&self, user_id: &Uuid, role: Role) -> Result<(), RepoError> {
+        let user_id = user_id.to_string();
+        blocking(&self.conn, move |conn| {
+            conn.execute("INSERT OR IGNORE INTO user_roles (user_id, role) VALUES (?1, ?2)", [user_id.as_str(), role.as_str()])
+        })
+        .await
+        .map_err(query_error)?;
+        Ok(())
+    }
+
+    async fn revoke_role(&self, user_id: &Uuid, role: Role) -> Result<bool, RepoError> {
+        let user_id = user_id.to_string();
+        let removed = blocking(&self.conn, move |conn| {
+            conn.execute("DELETE FROM user_roles WHERE user_id = ?1 AND role = ?2", [user_id.as_str(), role.as_str()])
+        })
+        .await
+        .map_err(query_error)?;
+        Ok(removed > 0)
+    }
+
+    async fn bootstrap_admin(&self, user_id: &Uuid, username: &str) -> Result<bool, RepoError> {
+        match std::env::var("BOOTSTRAP_ADMIN_USERNAME") {
+            Ok(bootstrap) if bootstrap == username => {}
+            _ => return Ok(false),
+        }
+        let user_id = user_id.to_string();
+        blocking(&self.conn, move |conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let admin_exists: bool = tx.query_row(
+                "SELECT EXISTS (SELECT 1 FROM user_roles WHERE role = ?1)",
+                [Role::Admin.as_str()],
+                |r| r.get(0),
+            )?;
+            if admin_exists {
+                return Ok(false);
+            }
+            tx.execute("INSERT INTO user_roles (user_id, role) VALUES (?1, ?2)", [user_id.as_str(), Role::Admin.as_str()])?;
+            tx.commit()?;
+            Ok(true)
+        })
+        .await
+        .map_err(query_error)
+    }
+}
+
+#[async_trait]
+impl StoreHealth for SqliteStore {
+    async fn ping(&self) -> anyhow::Result<()> {
+        blocking(&self.conn, |conn| conn.query_row("SELECT 1", [], |_| Ok(()))).await?;
+        Ok(())
+    }
+
+    async fn pending_migrations(&self) -> anyhow::Result<usize> {
+        Ok(blocking(&self.conn, |conn| status_sqlite(conn)).await?.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+
+    use super::*;
+    use crate::db_operations_repo::{
+        memory_store::MemoryStore,
+        poll_repo::{PollCursor, PollSort},
+        store::{check_vote_changes, seed_polls},
+    };
+
+    const SORTS: [PollSort; 4] = [PollSort::Newest, PollSort::Oldest, PollSort::MostVotes, PollSort::ClosingSoonest];
+
+    // Every poll the filter lists, fetched `limit` at a time through cursors
+    async fn page_through(store: &dyn PollStore, filter: &PollFilter, sort: PollSort, limit: i64) -> Vec<i32> {
+        let mut ids = Vec::new();
+        let mut after = None;
+        loop {
+            let page = PollPage { sort, limit: Some(limit), after: after.take() };
+            let polls = store.get_polls_page(filter, &page).await.unwrap();
+            let Some(last) = polls.last() else { return ids };
+            after = PollCursor::decode(&PollCursor::after(sort, last).encode());
+            ids.extend(polls.iter().map(|p| p.id));
+        }
+    }
+
+    // A migrated in-memory database holding these users
+    fn store_with_users(users: &[(Uuid, &str)]) -> SqliteStore {
+        let store = SqliteStore::open(":memory:").unwrap();
+        store.migrate(true).unwrap();
+        for (user_id, username) in users {
+            store.conn.lock().unwrap().execute("INSERT INTO users (unique_id, username) VALUES (?1, ?2)", params![user_id.to_string(), username]).unwrap();
+        }
+        store
+    }
+
+    #[actix_web::test]
+    async fn changes_repeats_and_retracts_votes() {
+        let (creator, alice, bob) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let sqlite = store_with_users(&[(creator, "creator"), (alice, "alice"), (bob, "bob")]);
+        check_vote_changes(&sqlite, creator, alice, bob).await;
+    }
+
+    #[actix_web::test]
+    async fn lists_polls_like_the_memory_store() {
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let sqlite = store_with_users(&[(alice, "alice"), (bob, "bob")]);
+        let memory = MemoryStore::default();
+        assert_eq!(seed_polls(&sqlite, alice, bob).await, seed_polls(&memory, alice, bob).await);
+
+        // Give both stores the same creation times
+        let mut created = Vec::new();
+        for poll in memory.get_polls_filtered(&PollFilter::new().viewer(alice)).await.unwrap() {
+            sqlite.conn.lock().unwrap().execute("UPDATE polls SET created_at = ?1 WHERE id = ?2", params![poll.created_at, poll.id]).unwrap();
+            created.push(poll.created_at);
+        }
+        let at = |i: usize| NaiveDateTime::parse_from_str(&created[i], "%Y-%m-%d %H:%M:%S%.f").unwrap();
+        let sqlite_created: Vec<String> = sqlite.get_polls_filtered(&PollFilter::new().viewer(alice)).await.unwrap().into_iter().map(|p| p.created_at).collect();
+        assert_eq!(sqlite_created, created);
+
+        let filters = [
+            PollFilter::new(),
+            PollFilter::new().viewer(alice),
+            PollFilter::new().viewer(bob).state(PollState::Draft),
+            PollFilter::new().state(PollState::Open),
+            PollFilter::new().state(PollState::Closed),
+            PollFilter::new().creator(bob).viewer(alice),
+            PollFilter::new().tagged("team"),
+            PollFilter::new().tagged("team").tagged("food"),
+            PollFilter::new().created_after(at(2)),
+            PollFilter::new().created_before(at(3)),
+            PollFilter::new().created_after(at(1)).created_before(at(4)),
+            PollFilter::new().title_contains("LUNCH"),
+            PollFilter::new().title_contains("été"),
+            PollFilter::new().title_contains("100%"),
+            PollFilter::new().viewer(alice).title_contains("m_o"),
+            PollFilter::new().title_contains(r"\"),
+        ];
+        for filter in &filters {
+            assert_eq!(sqlite.count_polls(filter).await.unwrap(), memory.count_polls(filter).await.unwrap(), "{:?}", filter);
+            for sort in SORTS {
+                let all = page_through(&memory, filter, sort, 100).await;
+                assert_eq!(page_through(&sqlite, filter, sort, 100).await, all, "{:?} by {:?}", filter, sort);
+                assert_eq!(page_through(&sqlite, filter, sort, 2).await, all, "{:?} by {:?} in pages", filter, sort);
+                assert_eq!(page_through(&memory, filter, sort, 2).await, all, "{:?} by {:?} in pages", filter, sort);
+            }
+        }
+    }
+}
@@ -0,0 +1,305 @@
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+#[cfg(test)]
+use chrono::Utc;
+use uuid::Uuid;
+use webauthn_rs::prelude::Passkey;
+
+use crate::attestation::VerifiedAttestation;
+use crate::rbac::{Permission, Role};
+use crate::db_operations_repo::{
+    poll_repo::{Poll, PollDetails, PollFilter, PollOptions, PollPage, PollState, PollTransition, RepoError as PollRepoError, VoteOutcome, VoteTally},
+    search::{search_in_memory, PollSearchHit},
+    user_passkey_repo::{RepoError, StoredPasskey},
+};
+
+/**
+Storage for users and their passkeys, as handlers see it. Handlers receive it as
+`Data<dyn UserStore>`; [PgStore](super::pg_store::PgStore) keeps it in Postgres and
+[MemoryStore](super::memory_store::MemoryStore) in process memory. Methods that write
+more than one record apply all of it or none of it.
+*/
+#[async_trait]
+pub(crate) trait UserStore: Send + Sync {
+    async fn find_unique_id_by_username(&self, username: &str) -> Result<Option<Uuid>, RepoError>;
+
+    async fn find_username_by_id(&self, unique_id: &Uuid) -> Result<Option<String>, RepoError>;
+
+    // Create a user together with its first passkey
+    async fn create_user(&self, unique_id: &Uuid, username: &str, passkey: &Passkey) -> Result<(), RepoError>;
+
+    async fn find_passkeys_by_user_id(&self, user_id: &Uuid) -> Result<Vec<StoredPasskey>, RepoError>;
+
+    async fn find_passkey(&self, user_id: &Uuid, credential_id: &str) -> Result<Option<StoredPasskey>, RepoError>;
+
+    async fn insert_passkey(
+        &self,
+        user_id: &Uuid,
+        passkey: &Passkey,
+        attestation: Option<&VerifiedAttestation>,
+    ) -> Result<(), RepoError>;
+
+    // Store a passkey after an authentication if its counter is still `expected_counter`
+    async fn update_passkey(&self, passkey: &Passkey, expected_counter: i64) -> Result<bool, RepoError>;
+
+    async fn has_attested_passkey(
+        &self,
+        user_id: &Uuid,
+        allowed_aaguids: &[Uuid],
+        allowed_cas: &[String],
+    ) -> Result<bool, RepoError>;
+}
+
+// The settings a poll is created with
+pub(crate) struct NewPoll<'a> {
+    pub(crate) title: &'a str,
+    pub(crate) creator_id: Uuid,
+    pub(crate) options: &'a [String],
+    pub(crate) require_passkey_vote: bool,
+    pub(crate) allowed_aaguids: &'a [Uuid],
+    pub(crate) allowed_attestation_cas: &'a [String],
+    pub(crate) tags: &'a [String],
+    pub(crate) closes_at: Option<NaiveDateTime>,
+    pub(crate) allow_vote_change: bool,
+    // Bounds on how many options one vote selects
+    pub(crate) min_selections: i32,
+    pub(crate) max_selections: i32,
+    // `Draft` or `Open`
+    pub(crate) state: PollState,
+}
+
+/**
+Storage for polls, their options and votes, as handlers see it through
+`Data<dyn PollStore>`. See [UserStore] for the implementations.
+*/
+#[async_trait]
+pub(crate) trait PollStore: Send + Sync {
+    // Create a poll with its options, returning its id
+    async fn create_poll(&self, poll: NewPoll<'_>) -> Result<i32, PollRepoError>;
+
+    // One page of the polls matching the filter, with their options in id order
+    async fn get_polls_page(&self, filter: &PollFilter, page: &PollPage) -> Result<Vec<Poll>, PollRepoError>;
+
+    async fn count_polls(&self, filter: &PollFilter) -> Result<i64, PollRepoError>;
+
+    /**
+    Polls matching the filter whose title or an option matches every search term as a
+    prefix, best match first. Postgres overrides this with full-text search; other
+    stores scan the filtered polls.
+    */
+    async fn search_polls(&self, terms: &[String], filter: &PollFilter, limit: i64) -> Result<Vec<PollSearchHit>, PollRepoError> {
+        let polls = self.get_polls_filtered(filter).await?;
+        Ok(search_in_memory(polls, terms, usize::try_from(limit).unwrap_or(0)))
+    }
+
+    // Every poll matching the filter, oldest first
+    async fn get_polls_filtered(&self, filter: &PollFilter) -> Result<Vec<Poll>, PollRepoError> {
+        self.get_polls_page(filter, &PollPage::all()).await
+    }
+
+    async fn get_polls_by_creator(&self, user_id: Uuid) -> Result<Vec<Poll>, PollRepoError> {
+        self.get_polls_filtered(&PollFilter::new().creator(user_id).viewer(user_id)).await
+    }
+
+    async fn get_poll_by_id(&self, poll_id: i32) -> Result<Option<PollDetails>, PollRepoError>;
+
+    async fn get_poll_options_with_votes(&self, poll_id: i32) -> Result<Vec<PollOptions>, PollRepoError>;
+
+    async fn get_option_id_by_text_and_poll_id(&self, option_text: &str, poll_id: i32) -> Result<Option<i32>, PollRepoError>;
+
+    /**
+    Count a vote for the selected options and record who cast it, or replace the
+    user's earlier selection when `allow_change` is set. `option_ids` is sorted and
+    free of duplicates; checking it against the poll's selection limits is up to the
+    caller. See [VoteOutcome]. Polls that are not open refuse it with
+    [PollRepoError::PollNotOpen], checked in the same transaction as the write.
+    */
+    async fn record_vote(
+        &self,
+        user_id: Uuid,
+        poll_id: i32,
+        option_ids: &[i32],
+        voted_at: String,
+        assertion: Option<&serde_json::Value>,
+        allow_change: bool,
+    ) -> Result<VoteOutcome, PollRepoError>;
+
+    // Withdraw the user's vote, returning the options it selected with their new counts.
+    // Only open polls allow it, as for [PollStore::record_vote]
+    async fn retract_vote(&self, user_id: Uuid, poll_id: i32) -> Result<Option<VoteTally>, PollRepoError>;
+
+    // Move a poll through `transition`, returning the state it left; `None` if there is no such poll
+    async fn transition_poll(&self, poll_id: i32, transition: PollTransition) -> Result<Option<PollState>, PollRepoError>;
+
+    // Clear a poll's votes so that everybody can vote again. Only open and closed polls
+    // allow it, refused otherwise with [PollRepoError::NotResettable] under the same lock
+    async fn reset_votes(&self, poll_id: i32) -> Result<(), PollRepoError>;
+
+    async fn is_poll_creator(&self, user_id: Uuid, poll_id: i32) -> Result<bool, PollRepoError>;
+}
+
+/**
+Role assignments and the permissions they grant, behind `Data<dyn RoleStore>`. Each
+implementation seeds [Role::default_permissions] when it is set up.
+*/
+#[async_trait]
+pub(crate) trait RoleStore: Send + Sync {
+    // Roles explicitly assigned to a user; everybody implicitly holds `user`
+    async fn roles_for_user(&self, user_id: &Uuid) -> Result<Vec<Role>, RepoError>;
+
+    // Union of the permissions of every role the user holds, including `user`
+    async fn permissions_for_user(&self, user_id: &Uuid) -> Result<Vec<Permission>, RepoError>;
+
+    async fn assign_role(&self, user_id: &Uuid, role: Role) -> Result<(), RepoError>;
+
+    // Returns whether the user held the role
+    async fn revoke_role(&self, user_id: &Uuid, role: Role) -> Result<bool, RepoError>;
+
+    // Grant `admin` to the `BOOTSTRAP_ADMIN_USERNAME` user while no admin exists yet
+    async fn bootstrap_admin(&self, user_id: &Uuid, username: &str) -> Result<bool, RepoError>;
+}
+
+/**
+What the readiness probe asks of the database behind the stores: that it answers, and
+how many of this build's migrations it is missing.
+*/
+#[async_trait]
+pub(crate) trait StoreHealth: Send + Sync {
+    async fn ping(&self) -> anyhow::Result<()>;
+
+    async fn pending_migrations(&self) -> anyhow::Result<usize>;
+}
+
+/**
+Polls by `alice` and `bob` covering every [PollFilter] criterion, for checking that
+the stores list them alike: each state, overlapping tags, titles with `LIKE` pattern
+characters, a few votes and closing dates. Returns their ids in creation order.
+*/
+#[cfg(test)]
+pub(crate) async fn seed_polls(store: &dyn PollStore, alice: Uuid, bob: Uuid) -> Vec<i32> {
+    let closes = |day: u32| chrono::NaiveDate::from_ymd_opt(2030, 1, day).and_then(|d| d.and_hms_opt(12, 0, 0));
+    let polls = [
+        ("Lunch: 100% pizza", alice, &["food", "team"][..], None, PollState::Open),
+        ("Team_offsite", alice, &["team"][..], None, PollState::Draft),
+        ("Lunch vote", bob, &["food"][..], closes(20), PollState::Open),
+        ("Release plan", bob, &["work", "team"][..], closes(10), PollState::Closed),
+        ("Old lunch", alice, &["food"][..], None, PollState::Archived),
+        ("lunch break", bob, &[][..], closes(10), PollState::Open),
+        ("Teamoffsite", bob, &["team"][..], None, PollState::Open),
+        ("Fête d'ÉTÉ", alice, &[][..], None, PollState::Open),
+    ];
+    let options = ["Yes".to_string(), "No".to_string()];
+    let mut ids = Vec::new();
+    for (title, creator_id, tags, closes_at, state) in polls {
+        let tags: Vec<String> = tags.iter().map(|t| t.to_string()).collect();
+        let poll_id = store
+            .create_poll(NewPoll {
+                title,
+                creator_id,
+                options: &options,
+                require_passkey_vote: false,
+                allowed_aaguids: &[],
+                allowed_attestation_cas: &[],
+                tags: &tags,
+                closes_at,
+                allow_vote_change: false,
+                min_selections: 1,
+                max_selections: 1,
+                state: if state == PollState::Draft { PollState::Draft } else { PollState::Open },
+            })
+            .await
+            .unwrap();
+        let voters: &[Uuid] = match ids.len() {
+            0 => &[alice, bob],
+            2 | 3 => &[bob],
+            _ => &[],
+        };
+        let yes = store.get_option_id_by_text_and_poll_id("Yes", poll_id).await.unwrap().unwrap();
+        for voter in voters {
+            store.record_vote(*voter, poll_id, &[yes], Utc::now().to_string(), None, false).await.unwrap();
+        }
+        if matches!(state, PollState::Closed | PollState::Archived) {
+            store.transition_poll(poll_id, PollTransition::Close).await.unwrap();
+        }
+        if state == PollState::Archived {
+            store.transition_poll(poll_id, PollTransition::Archive).await.unwrap();
+        }
+        ids.push(poll_id);
+    }
+    ids
+}
+
+/**
+Vote, change, repeat and retract votes on a fresh poll by `creator_id`, checking the
+outcomes and counts every store must agree on. `alice` and `bob` are the voters.
+*/
+#[cfg(test)]
+pub(crate) async fn check_vote_changes(store: &dyn PollStore, creator_id: Uuid, alice: Uuid, bob: Uuid) {
+    use crate::db_operations_repo::poll_repo::VoteTally;
+
+    let options = ["Pizza".to_string(), "Sushi".to_string(), "Tacos".to_string()];
+    let poll_id = store
+        .create_poll(NewPoll {
+            title: "Lunch",
+            creator_id,
+            options: &options,
+            require_passkey_vote: false,
+            allowed_aaguids: &[],
+            allowed_attestation_cas: &[],
+            tags: &[],
+            closes_at: None,
+            allow_vote_change: true,
+            min_selections: 1,
+            max_selections: 2,
+            state: PollState::Open,
+        })
+        .await
+        .unwrap();
+    let mut ids = Vec::new();
+    for option in &options {
+        ids.push(store.get_option_id_by_text_and_poll_id(option, poll_id).await.unwrap().unwrap());
+    }
+    let (pizza, sushi, tacos) = (ids[0], ids[1], ids[2]);
+    let vote = |user_id: Uuid, option_ids: Vec<i32>| async move {
+        store.record_vote(user_id, poll_id, &option_ids, Utc::now().to_string(), None, true).await
+    };
+    let counts = || async {
+        let options = store.get_poll_options_with_votes(poll_id).await.unwrap();
+        let voters = store.get_poll_by_id(poll_id).await.unwrap().unwrap().voters;
+        (options.into_iter().map(|o| o.votes).collect::<Vec<i32>>(), voters)
+    };
+    let tally = |tally: &VoteTally| {
+        let options: Vec<(i32, i32)> = tally.options.iter().map(|o| (o.option_id, o.votes)).collect();
+        (options, tally.voters)
+    };
+
+    match vote(alice, vec![pizza, sushi]).await.unwrap() {
+        VoteOutcome::Recorded(t) => assert_eq!(tally(&t), (vec![(pizza, 1), (sushi, 1)], 1)),
+        outcome => panic!("expected a recorded vote, got {:?}", outcome),
+    }
+    assert!(matches!(vote(bob, vec![pizza]).await.unwrap(), VoteOutcome::Recorded(_)));
+    assert_eq!(counts().await, (vec![2, 1, 0], 2));
+
+    // Only the options that left or joined the selection move
+    match vote(alice, vec![sushi, tacos]).await.unwrap() {
+        VoteOutcome::Changed(t) => assert_eq!(tally(&t), (vec![(pizza, 1), (tacos, 1)], 2)),
+        outcome => panic!("expected a changed vote, got {:?}", outcome),
+    }
+    assert_eq!(counts().await, (vec![1, 1, 1], 2));
+    assert!(matches!(vote(alice, vec![sushi, tacos]).await.unwrap(), VoteOutcome::Unchanged));
+    assert_eq!(counts().await, (vec![1, 1, 1], 2));
+    assert!(matches!(
+        store.record_vote(bob, poll_id, &[sushi], Utc::now().to_string(), None, false).await,
+        Err(PollRepoError::AlreadyVoted)
+    ));
+
+    let retracted = store.retract_vote(alice, poll_id).await.unwrap().unwrap();
+    assert_eq!(tally(&retracted), (vec![(sushi, 0), (tacos, 0)], 1));
+    assert_eq!(counts().await, (vec![1, 0, 0], 1));
+    assert!(store.retract_vote(alice, poll_id).await.unwrap().is_none());
+
+    store.transition_poll(poll_id, PollTransition::Close).await.unwrap();
+    assert!(matches!(store.retract_vote(bob, poll_id).await, Err(PollRepoError::PollNotOpen(PollState::Closed))));
+    assert!(matches!(vote(alice, vec![pizza]).await, Err(PollRepoError::PollNotOpen(PollState::Closed))));
+    assert_eq!(counts().await, (vec![1, 0, 0], 1));
+}
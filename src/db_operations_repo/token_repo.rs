@@ -0,0 +1,92 @@
+use chrono::{DateTime, Utc};
+use tokio_postgres::Client;
+use uuid::Uuid;
+
+use crate::db_operations_repo::user_passkey_repo::RepoError;
+
+pub(crate) enum RefreshOutcome {
+    // The token was live and is now marked as used
+    Rotated { family_id: Uuid, user_id: Uuid, auth_time: DateTime<Utc>, scope: Option<Vec<String>> },
+    // The token had already been used or revoked: the family must be revoked
+    Reused { family_id: Uuid },
+    // Unknown or expired token
+    Invalid,
+}
+
+pub(crate) struct TokenRepo<'a> {
+    pub(crate) client: &'a Client,
+}
+
+impl<'a> TokenRepo<'a> {
+    pub(crate) async fn insert_refresh_token(
+        &self,
+        family_id: &Uuid,
+        user_id: &Uuid,
+        token_hash: &str,
+        auth_time: &DateTime<Utc>,
+        scope: Option<&[String]>,
+        expires_at: &DateTime<Utc>,
+    ) -> Result<(), RepoError> {
+        self.client
+            .execute(
+                r#"INSERT INTO refresh_tokens (id, family_id, user_id, token_hash, auth_time, scope, expires_at, created_at)
+                   VALUES ($1, $2, $3, $4, $5, $6, $7, now())"#,
+                &[&Uuid::new_v4(), family_id, user_id, &token_hash, auth_time, &scope, expires_at],
+            )
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(())
+    }
+
+    // Atomically mark a refresh token as used, reporting reuse of a spent token
+    pub(crate) async fn consume_refresh_token(&self, token_hash: &str) -> Result<RefreshOutcome, RepoError> {
+        let rotated = self.client
+            .query_opt(
+                r#"UPDATE refresh_tokens SET used_at = now()
+                   WHERE token_hash = $1 AND used_at IS NULL AND revoked_at IS NULL AND expires_at > now()
+                   RETURNING family_id, user_id, auth_time, scope"#,
+                &[&token_hash],
+            )
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        if let Some(row) = rotated {
+            return Ok(RefreshOutcome::Rotated {
+                family_id: row.get(0),
+                user_id: row.get(1),
+                auth_time: row.get(2),
+                scope: row.get(3),
+            });
+        }
+
+        let existing = self.client
+            .query_opt(
+                "SELECT family_id, used_at IS NOT NULL OR revoked_at IS NOT NULL FROM refresh_tokens WHERE token_hash = $1",
+                &[&token_hash],
+            )
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(match existing {
+            Some(row) if row.get::<_, bool>(1) => RefreshOutcome::Reused { family_id: row.get(0) },
+            _ => RefreshOutcome::Invalid,
+        })
+    }
+
+    pub(crate) async fn family_of(&self, token_hash: &str) -> Result<Option<Uuid>, RepoError> {
+        let row = self.client
+            .query_opt("SELECT family_id FROM refresh_tokens WHERE token_hash = $1", &[&token_hash])
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(row.map(|r| r.get(0)))
+    }
+
+    pub(crate) async fn revoke_family(&self, family_id: &Uuid) -> Result<(), RepoError> {
+        self.client
+            .execute(
+                "UPDATE refresh_tokens SET revoked_at = now() WHERE family_id = $1 AND revoked_at IS NULL",
+                &[family_id],
+            )
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(())
+    }
+}
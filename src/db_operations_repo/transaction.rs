@@ -0,0 +1,32 @@
+use tokio_postgres::{Client, Transaction};
+
+use crate::db_operations_repo::{poll_repo::PollRepo, user_passkey_repo::{RepoError, UserRepo}};
+
+/**
+A database transaction for writes that span several statements. Repositories are
+obtained from it with [RepoTransaction::users] and [RepoTransaction::polls]; nothing
+they write is visible until [RepoTransaction::commit], and dropping the transaction
+without committing (for example on an early `?` return) rolls everything back.
+*/
+pub(crate) struct RepoTransaction<'a> {
+    tx: Transaction<'a>,
+}
+
+impl<'a> RepoTransaction<'a> {
+    pub(crate) async fn begin(client: &'a mut Client) -> Result<Self, RepoError> {
+        let tx = client.transaction().await.map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(RepoTransaction { tx })
+    }
+
+    pub(crate) fn users(&self) -> UserRepo<'_, Transaction<'a>> {
+        UserRepo { client: &self.tx }
+    }
+
+    pub(crate) fn polls(&self) -> PollRepo<'_, Transaction<'a>> {
+        PollRepo { client: &self.tx }
+    }
+
+    pub(crate) async fn commit(self) -> Result<(), RepoError> {
+        self.tx.commit().await.map_err(|_| RepoError::DatabaseQueryError)
+    }
+}
@@ -1,19 +1,77 @@
-use tokio_postgres::Client;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use tokio_postgres::{error::SqlState, Client, GenericClient, Row};
 use serde_json::Value;
+use webauthn_rs::prelude::{Credential, CredentialID, Passkey};
+use webauthn_rs_core::proto::AttestationMetadata;
 use uuid::Uuid;
 use thiserror::Error;
 
+use crate::attestation::VerifiedAttestation;
+
 #[derive(Debug, Error)]
 pub enum RepoError {
     #[error("Database query error")]
     DatabaseQueryError,
+    #[error("Credential is already registered")]
+    DuplicateCredential,
+    #[error("Stored credential is corrupt")]
+    CorruptCredential,
+    #[error("Database unavailable")]
+    DatabaseUnavailable,
+}
+
+// A user's credential together with the counter its row was read at
+pub(crate) struct StoredPasskey {
+    pub(crate) counter: i64,
+    pub(crate) passkey: Passkey,
+}
+
+impl StoredPasskey {
+    fn from_row(row: &Row) -> Result<Self, RepoError> {
+        Ok(StoredPasskey {
+            counter: row.get(0),
+            passkey: serde_json::from_value(row.get(1)).map_err(|_| RepoError::CorruptCredential)?,
+        })
+    }
+}
+
+// The parts of a credential kept in their own columns next to the serialized passkey
+pub(crate) struct CredentialColumns {
+    pub(crate) credential_id: String,
+    pub(crate) counter: i64,
+    pub(crate) backup_eligible: bool,
+    pub(crate) backup_state: bool,
+    pub(crate) aaguid: Option<Uuid>,
+}
+
+impl CredentialColumns {
+    pub(crate) fn of(passkey: &Passkey) -> Self {
+        let credential = Credential::from(passkey.clone());
+        let aaguid = match credential.attestation.metadata {
+            AttestationMetadata::Packed { aaguid } | AttestationMetadata::Tpm { aaguid, .. } => Some(aaguid),
+            _ => None,
+        };
+        CredentialColumns {
+            credential_id: credential_id_string(&credential.cred_id),
+            counter: i64::from(credential.counter),
+            backup_eligible: credential.backup_eligible,
+            backup_state: credential.backup_state,
+            aaguid,
+        }
+    }
+}
+
+// Credential IDs are stored base64url encoded, as browsers present them
+pub(crate) fn credential_id_string(credential_id: &CredentialID) -> String {
+    URL_SAFE_NO_PAD.encode(credential_id)
 }
 
-pub(crate) struct UserRepo<'a> {
-    pub(crate) client: &'a Client,
+// Works on a plain connection or, for multi-statement writes, a [RepoTransaction]
+pub(crate) struct UserRepo<'a, C: GenericClient = Client> {
+    pub(crate) client: &'a C,
 }
 
-impl<'a> UserRepo<'a> {
+impl<'a, C: GenericClient + Sync> UserRepo<'a, C> {
     // Fetch unique ID for a username
     pub(crate) async fn find_unique_id_by_username(
         &self,
@@ -32,6 +90,18 @@ impl<'a> UserRepo<'a> {
         result
     }
 
+    // Fetch the username for a unique ID
+    pub(crate) async fn find_username_by_id(
+        &self,
+        unique_id: &Uuid
+    ) -> Result<Option<String>, RepoError> {
+        self.client
+            .query_opt("SELECT username FROM users WHERE unique_id = $1", &[unique_id])
+            .await
+            .map(|row| row.map(|r| r.get(0)))
+            .map_err(|_| RepoError::DatabaseQueryError)
+    }
+
     // Insert a new user
     pub(crate) async fn insert_user(
         &self,
@@ -48,48 +118,150 @@ impl<'a> UserRepo<'a> {
         Ok(())
     }
 
-    //Fetch passkeys for a user id 
+    //Fetch passkeys for a user id; a user may also hold attested security keys
     pub async fn find_passkeys_by_user_id(
         &self, 
         user_id: &Uuid
-    ) -> Result<Option<serde_json::Value>, RepoError> {
-        let row = self.client
-            .query_opt("SELECT passkey_data FROM passkeys_data WHERE user_id = $1", &[user_id])
-            .await.unwrap();
+    ) -> Result<Vec<StoredPasskey>, RepoError> {
+        let rows = self.client
+            .query("SELECT counter, passkey_data FROM passkeys_data WHERE user_id = $1", &[user_id])
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
         
-        Ok(row.map(|r| r.get(0)))
+        rows.iter().map(StoredPasskey::from_row).collect()
     }
 
-    // Insert a passkey for a user
+    // Fetch one of a user's passkeys by its credential ID
+    pub(crate) async fn find_passkey(
+        &self,
+        user_id: &Uuid,
+        credential_id: &str,
+    ) -> Result<Option<StoredPasskey>, RepoError> {
+        let row = self.client
+            .query_opt(
+                "SELECT counter, passkey_data FROM passkeys_data WHERE user_id = $1 AND credential_id = $2",
+                &[user_id, &credential_id],
+            )
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        row.as_ref().map(StoredPasskey::from_row).transpose()
+    }
+
+    // Insert a passkey for a user, with what its attestation proved if it was verified
     pub(crate) async fn insert_passkey(
         &self,
         user_id: &Uuid,
-        passkey_data: &Value
+        passkey: &Passkey,
+        attestation: Option<&VerifiedAttestation>,
     ) -> Result<(), RepoError> {
+        let columns = CredentialColumns::of(passkey);
+        let passkey_data = serde_json::to_value(passkey).map_err(|_| RepoError::CorruptCredential)?;
         self.client
             .execute(
-                "INSERT INTO passkeys_data (user_id, passkey_data) VALUES ($1, $2)",
-                &[user_id, passkey_data],
+                r#"INSERT INTO passkeys_data
+                       (user_id, passkey_data, credential_id, counter, backup_eligible, backup_state, aaguid,
+                        attestation_aaguid, attestation_ca, created_at)
+                   VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, now())"#,
+                &[
+                    user_id,
+                    &passkey_data,
+                    &columns.credential_id,
+                    &columns.counter,
+                    &columns.backup_eligible,
+                    &columns.backup_state,
+                    &columns.aaguid,
+                    &attestation.and_then(|a| a.aaguid),
+                    &attestation.map(|a| a.ca_fingerprint.as_str()),
+                ],
             )
             .await
-            .map_err(|_| RepoError::DatabaseQueryError)?;
+            .map_err(|e| match e.code() {
+                Some(&SqlState::UNIQUE_VIOLATION) => RepoError::DuplicateCredential,
+                _ => RepoError::DatabaseQueryError,
+            })?;
         Ok(())
     }
 
-    // Update an existing passkey
+    // Store a passkey after an authentication, keyed by credential ID. Only applies
+    // if the stored counter is still `expected_counter`, so that two concurrent
+    // logins cannot overwrite each other; returns whether the row was updated.
     pub(crate) async fn update_passkey(
         &self,
-        user_id: &Uuid,
-        old_data: &Value,
-        new_data: &Value
-    ) -> Result<(), RepoError> {
-        self.client
+        passkey: &Passkey,
+        expected_counter: i64,
+    ) -> Result<bool, RepoError> {
+        let columns = CredentialColumns::of(passkey);
+        let passkey_data = serde_json::to_value(passkey).map_err(|_| RepoError::CorruptCredential)?;
+        let updated = self.client
             .execute(
-                "UPDATE passkeys_data SET passkey_data = $1 WHERE user_id = $2 AND passkey_data = $3",
-                &[new_data, user_id, old_data],
+                r#"UPDATE passkeys_data
+                   SET passkey_data = $1, counter = $2, backup_eligible = $3, backup_state = $4, last_used_at = now()
+                   WHERE credential_id = $5 AND counter = $6"#,
+                &[
+                    &passkey_data,
+                    &columns.counter,
+                    &columns.backup_eligible,
+                    &columns.backup_state,
+                    &columns.credential_id,
+                    &expected_counter,
+                ],
             )
             .await
             .map_err(|_| RepoError::DatabaseQueryError)?;
-        Ok(())
+        Ok(updated == 1)
+    }
+
+    // Fill the typed columns of rows stored before they existed, from their JSON
+    pub(crate) async fn backfill_credential_columns(&self) -> Result<u64, RepoError> {
+        let rows = self.client
+            .query("SELECT user_id, passkey_data FROM passkeys_data WHERE credential_id IS NULL", &[])
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        let mut filled = 0;
+        for row in rows {
+            let user_id: Uuid = row.get(0);
+            let passkey_data: Value = row.get(1);
+            let passkey: Passkey = serde_json::from_value(passkey_data.clone()).map_err(|_| RepoError::CorruptCredential)?;
+            let columns = CredentialColumns::of(&passkey);
+            filled += self.client
+                .execute(
+                    r#"UPDATE passkeys_data
+                       SET credential_id = $1, counter = $2, backup_eligible = $3, backup_state = $4, aaguid = $5
+                       WHERE user_id = $6 AND passkey_data = $7 AND credential_id IS NULL"#,
+                    &[
+                        &columns.credential_id,
+                        &columns.counter,
+                        &columns.backup_eligible,
+                        &columns.backup_state,
+                        &columns.aaguid,
+                        &user_id,
+                        &passkey_data,
+                    ],
+                )
+                .await
+                .map_err(|_| RepoError::DatabaseQueryError)?;
+        }
+        Ok(filled)
+    }
+
+    // Whether the user registered a credential whose verified attestation matches
+    // one of the allowed device models or attestation roots
+    pub(crate) async fn has_attested_passkey(
+        &self,
+        user_id: &Uuid,
+        allowed_aaguids: &[Uuid],
+        allowed_cas: &[String],
+    ) -> Result<bool, RepoError> {
+        let row = self.client
+            .query_one(
+                r#"SELECT EXISTS (
+                       SELECT 1 FROM passkeys_data
+                       WHERE user_id = $1 AND (attestation_aaguid = ANY($2) OR attestation_ca = ANY($3))
+                   )"#,
+                &[user_id, &allowed_aaguids, &allowed_cas],
+            )
+            .await
+            .map_err(|_| RepoError::DatabaseQueryError)?;
+        Ok(row.get(0))
     }
 }
@@ -0,0 +1,137 @@
+use actix_web::{web::{self, Data}, Error, HttpResponse};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{
+    db_operations_repo::{oidc_repo::{OidcClient, OidcRepo}, store::{RoleStore, UserStore}},
+    identity::Scope,
+    rbac::{Permission, Role, UserPermissions},
+    startup::UserData,
+    tokens::{generate_opaque_token, hash_token},
+};
+
+#[derive(Deserialize)]
+pub struct AssignRoleRequest {
+    role: String,
+}
+
+#[derive(Deserialize)]
+pub struct RegisterClientRequest {
+    name: String,
+    redirect_uris: Vec<String>,
+    // Public clients get no secret and must use PKCE alone
+    #[serde(default)]
+    public: bool,
+}
+
+fn parse_role(role: &str) -> Result<Role, Error> {
+    role.parse()
+        .map_err(|_| actix_web::error::ErrorBadRequest(format!("Unknown role: {}", role)))
+}
+
+async fn user_id_for(repo: &dyn UserStore, username: &str) -> Result<Uuid, Error> {
+    match repo.find_unique_id_by_username(username).await {
+        Ok(Some(id)) => Ok(id),
+        Ok(None) => Err(actix_web::error::ErrorNotFound("User not found")),
+        Err(_) => Err(actix_web::error::ErrorInternalServerError("Error fetching user")),
+    }
+}
+
+pub async fn get_user_roles(
+    permissions: UserPermissions,
+    users: Data<dyn UserStore>,
+    roles: Data<dyn RoleStore>,
+    username: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    permissions.user.require_scope(Scope::Admin)?;
+    permissions.require(Permission::ManageUsers)?;
+    let user_id = user_id_for(&**users, &username).await?;
+
+    let roles = roles
+        .roles_for_user(&user_id)
+        .await
+        .map_err(|_| actix_web::error::ErrorInternalServerError("Error fetching roles"))?;
+    let roles: Vec<&str> = roles.iter().map(Role::as_str).collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "user_id": user_id,
+        "username": username.as_str(),
+        "roles": roles,
+    })))
+}
+
+pub async fn assign_user_role(
+    permissions: UserPermissions,
+    users: Data<dyn UserStore>,
+    roles: Data<dyn RoleStore>,
+    username: web::Path<String>,
+    req: web::Json<AssignRoleRequest>,
+) -> Result<HttpResponse, Error> {
+    permissions.user.require_scope(Scope::Admin)?;
+    permissions.require(Permission::ManageUsers)?;
+    let role = parse_role(&req.role)?;
+    let user_id = user_id_for(&**users, &username).await?;
+
+    roles
+        .assign_role(&user_id, role)
+        .await
+        .map_err(|_| actix_web::error::ErrorInternalServerError("Error assigning role"))?;
+    Ok(HttpResponse::Ok().json("Role assigned successfully"))
+}
+
+pub async fn revoke_user_role(
+    permissions: UserPermissions,
+    users: Data<dyn UserStore>,
+    roles: Data<dyn RoleStore>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    permissions.user.require_scope(Scope::Admin)?;
+    permissions.require(Permission::ManageUsers)?;
+    let (username, role) = path.into_inner();
+    let role = parse_role(&role)?;
+    let user_id = user_id_for(&**users, &username).await?;
+
+    if role == Role::Admin && user_id == permissions.user.user_id {
+        return Err(actix_web::error::ErrorBadRequest("Admins cannot revoke their own admin role"));
+    }
+
+    match roles.revoke_role(&user_id, role).await {
+        Ok(true) => Ok(HttpResponse::Ok().json("Role revoked successfully")),
+        Ok(false) => Err(actix_web::error::ErrorNotFound("User does not hold that role")),
+        Err(_) => Err(actix_web::error::ErrorInternalServerError("Error revoking role")),
+    }
+}
+
+pub async fn register_oidc_client(
+    permissions: UserPermissions,
+    webauthn_users: Data<UserData>,
+    req: web::Json<RegisterClientRequest>,
+) -> Result<HttpResponse, Error> {
+    permissions.user.require_scope(Scope::Admin)?;
+    permissions.require(Permission::ManageUsers)?;
+    if req.redirect_uris.is_empty() {
+        return Err(actix_web::error::ErrorBadRequest("At least one redirect URI is required"));
+    }
+    if let Some(invalid) = req.redirect_uris.iter().find(|uri| webauthn_rs::prelude::Url::parse(uri).is_err()) {
+        return Err(actix_web::error::ErrorBadRequest(format!("Invalid redirect URI: {}", invalid)));
+    }
+
+    let client_secret = (!req.public).then(generate_opaque_token);
+    let client = OidcClient {
+        client_id: Uuid::new_v4().to_string(),
+        name: req.name.clone(),
+        client_secret_hash: client_secret.as_deref().map(hash_token),
+        redirect_uris: req.redirect_uris.clone(),
+    };
+    OidcRepo { client: &*webauthn_users.client().await? }
+        .insert_client(&client)
+        .await
+        .map_err(|_| actix_web::error::ErrorInternalServerError("Error registering client"))?;
+
+    // The secret is only ever shown in this response
+    Ok(HttpResponse::Created().json(serde_json::json!({
+        "client_id": client.client_id,
+        "client_secret": client_secret,
+        "name": client.name,
+        "redirect_uris": client.redirect_uris,
+    })))
+}
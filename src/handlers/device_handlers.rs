@@ -0,0 +1,244 @@
+use actix_web::{
+    http::StatusCode,
+    web::{self, Data},
+    Error, HttpRequest, HttpResponse, ResponseError,
+};
+use chrono::{DateTime, Duration, Utc};
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::json;
+use tokio_postgres::Client;
+use uuid::Uuid;
+use webauthn_rs::prelude::Url;
+
+use crate::{
+    db_operations_repo::{
+        device_repo::DeviceRepo,
+        oidc_repo::{OidcClient, OidcRepo},
+        token_repo::TokenRepo,
+    },
+    handlers::{
+        oidc_handlers::{authenticate_client, oauth_error},
+        token_handlers::issue_token_pair,
+    },
+    identity::{AuthMethod, AuthenticatedUser, Scope},
+    oidc::OidcProvider,
+    startup::UserData,
+    tokens::{generate_opaque_token, hash_token, TokenSigner},
+};
+
+pub(crate) const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+const DEVICE_CODE_TTL_SECS: i64 = 10 * 60;
+const DEFAULT_INTERVAL_SECS: i32 = 5;
+// No vowels or look-alike characters, so codes are easy to read out and type
+const USER_CODE_ALPHABET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+
+#[derive(Deserialize)]
+pub struct DeviceAuthorizationRequest {
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    scope: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DeviceDecision {
+    approve: bool,
+}
+
+fn generate_user_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..8)
+        .map(|_| USER_CODE_ALPHABET[rng.gen_range(0..USER_CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+// Accept codes typed in lowercase or with the dash and spaces
+fn normalize_user_code(user_code: &str) -> String {
+    user_code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+fn display_user_code(user_code: &str) -> String {
+    format!("{}-{}", &user_code[..4], &user_code[4..])
+}
+
+pub async fn device_authorization(
+    req: HttpRequest,
+    form: web::Form<DeviceAuthorizationRequest>,
+    oidc: Data<OidcProvider>,
+    webauthn_users: Data<UserData>,
+) -> HttpResponse {
+    let db = match webauthn_users.client().await {
+        Ok(db) => db,
+        Err(e) => return e.error_response(),
+    };
+    let client = match authenticate_client(
+        &OidcRepo { client: &db },
+        &req,
+        form.client_id.as_deref(),
+        form.client_secret.as_deref(),
+    )
+    .await
+    {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+
+    let scope: Vec<String> = match &form.scope {
+        Some(scope) => scope.split(' ').filter(|s| !s.is_empty()).map(str::to_string).collect(),
+        None => vec![Scope::ReadPolls.as_str().to_string()],
+    };
+    if scope.is_empty() || scope.iter().any(|s| s.parse::<Scope>().is_err()) {
+        return oauth_error(StatusCode::BAD_REQUEST, "invalid_scope", "Unknown scope requested");
+    }
+
+    let device_code = generate_opaque_token();
+    let user_code = generate_user_code();
+    let expires_at = Utc::now() + Duration::seconds(DEVICE_CODE_TTL_SECS);
+    let repo = DeviceRepo { client: &db };
+    if repo
+        .insert(&hash_token(&device_code), &user_code, &client.client_id, &scope, DEFAULT_INTERVAL_SECS, &expires_at)
+        .await
+        .is_err()
+    {
+        return HttpResponse::InternalServerError().body("Error storing device authorization");
+    }
+
+    let verification_uri = oidc.device_verification_url.clone();
+    let mut verification_uri_complete = match Url::parse(&verification_uri) {
+        Ok(url) => url,
+        Err(_) => return HttpResponse::InternalServerError().body("Invalid DEVICE_VERIFICATION_URL"),
+    };
+    verification_uri_complete
+        .query_pairs_mut()
+        .append_pair("user_code", &display_user_code(&user_code));
+
+    HttpResponse::Ok()
+        .insert_header((actix_web::http::header::CACHE_CONTROL, "no-store"))
+        .json(json!({
+            "device_code": device_code,
+            "user_code": display_user_code(&user_code),
+            "verification_uri": verification_uri,
+            "verification_uri_complete": verification_uri_complete.as_str(),
+            "expires_in": DEVICE_CODE_TTL_SECS,
+            "interval": DEFAULT_INTERVAL_SECS,
+        }))
+}
+
+// Token endpoint branch for the device_code grant (RFC 8628 section 3.4)
+pub(crate) async fn device_code_grant(
+    db: &Client,
+    signer: &TokenSigner,
+    client: &OidcClient,
+    device_code: &str,
+) -> HttpResponse {
+    let repo = DeviceRepo { client: db };
+    let device_code_hash = hash_token(device_code);
+    let poll = match repo.poll(&device_code_hash).await {
+        Ok(Some(poll)) => poll,
+        Ok(None) => return oauth_error(StatusCode::BAD_REQUEST, "invalid_grant", "Unknown device_code"),
+        Err(_) => return HttpResponse::InternalServerError().body("Error reading device authorization"),
+    };
+    let authorization = poll.authorization;
+    if authorization.client_id != client.client_id {
+        return oauth_error(StatusCode::BAD_REQUEST, "invalid_grant", "device_code was issued to another client");
+    }
+    if authorization.expires_at <= Utc::now() {
+        return oauth_error(StatusCode::BAD_REQUEST, "expired_token", "The device_code has expired");
+    }
+
+    match authorization.status.as_str() {
+        "denied" => return oauth_error(StatusCode::BAD_REQUEST, "access_denied", "The user denied the request"),
+        "consumed" => return oauth_error(StatusCode::BAD_REQUEST, "invalid_grant", "device_code was already used"),
+        "pending" if poll.slow_down => {
+            return oauth_error(StatusCode::BAD_REQUEST, "slow_down", "Polling too frequently")
+        }
+        "pending" => {
+            return oauth_error(StatusCode::BAD_REQUEST, "authorization_pending", "Waiting for the user to approve")
+        }
+        _ => {}
+    }
+
+    let approved = match repo.consume_approved(&device_code_hash).await {
+        Ok(Some(approved)) => approved,
+        Ok(None) => return oauth_error(StatusCode::BAD_REQUEST, "invalid_grant", "device_code was already used"),
+        Err(_) => return HttpResponse::InternalServerError().body("Error reading device authorization"),
+    };
+    let tokens = issue_token_pair(
+        &TokenRepo { client: db },
+        signer,
+        &Uuid::new_v4(),
+        approved.user_id,
+        approved.auth_time,
+        Some(approved.scope),
+    )
+    .await;
+    match tokens {
+        Ok(tokens) => HttpResponse::Ok()
+            .insert_header((actix_web::http::header::CACHE_CONTROL, "no-store"))
+            .json(tokens),
+        Err(_) => HttpResponse::InternalServerError().body("Error issuing tokens"),
+    }
+}
+
+// The approval page must run in a browser signed in with a passkey
+fn require_browser_session(user: &AuthenticatedUser) -> Result<(), Error> {
+    if user.method != AuthMethod::Session {
+        return Err(actix_web::error::ErrorForbidden("Device requests are approved from a passkey login session"));
+    }
+    Ok(())
+}
+
+pub async fn get_device_request(
+    user: AuthenticatedUser,
+    user_code: web::Path<String>,
+    webauthn_users: Data<UserData>,
+) -> Result<HttpResponse, Error> {
+    require_browser_session(&user)?;
+    let user_code = normalize_user_code(&user_code);
+    let db = webauthn_users.client().await?;
+    let authorization = DeviceRepo { client: &db }
+        .find_by_user_code(&user_code)
+        .await
+        .map_err(|_| actix_web::error::ErrorInternalServerError("Error fetching device request"))?
+        .filter(|a| a.status == "pending" && a.expires_at > Utc::now())
+        .ok_or_else(|| actix_web::error::ErrorNotFound("Unknown or expired code"))?;
+    let client_name = OidcRepo { client: &db }
+        .find_client(&authorization.client_id)
+        .await
+        .ok()
+        .flatten()
+        .map(|c| c.name);
+
+    Ok(HttpResponse::Ok().json(json!({
+        "user_code": display_user_code(&user_code),
+        "client_id": authorization.client_id,
+        "client_name": client_name,
+        "scope": authorization.scope,
+        "expires_at": authorization.expires_at,
+    })))
+}
+
+pub async fn decide_device_request(
+    user: AuthenticatedUser,
+    user_code: web::Path<String>,
+    req: web::Json<DeviceDecision>,
+    webauthn_users: Data<UserData>,
+) -> Result<HttpResponse, Error> {
+    require_browser_session(&user)?;
+    let auth_time = user
+        .auth_time
+        .and_then(|t| DateTime::from_timestamp(t, 0))
+        .unwrap_or_else(Utc::now);
+    let repo = DeviceRepo { client: &*webauthn_users.client().await? };
+    match repo.decide(&normalize_user_code(&user_code), &user.user_id, &auth_time, req.approve).await {
+        Ok(true) if req.approve => Ok(HttpResponse::Ok().json("Device approved")),
+        Ok(true) => Ok(HttpResponse::Ok().json("Device request denied")),
+        Ok(false) => Err(actix_web::error::ErrorNotFound("Unknown or expired code")),
+        Err(_) => Err(actix_web::error::ErrorInternalServerError("Error updating device request")),
+    }
+}
@@ -1,16 +1,22 @@
 
 use actix_session::{Session, SessionGetError, SessionInsertError};
 use actix_web::{web::{Data, Json, Path}, HttpResponse };
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
 use log::{debug, error, info};
-use tokio::sync::Mutex;
 use webauthn_rs::prelude::*;
 use actix_web::http::StatusCode;
 use webauthn_rs::prelude::WebauthnError;
-use crate::{db_operations_repo::user_passkey_repo::UserRepo, startup::UserData};
+use crate::{attestation::AttestationTrust, db::db::DatabaseUnavailable, db_operations_repo::{store::UserStore, user_passkey_repo::{credential_id_string, RepoError}}, identity::{AuthenticatedUser, RecentAuth, LAST_AUTH_AT, REAUTH_STATE}, pow::{PowChallenge, PowVerified, ProofOfWork}};
 use thiserror::Error;
+
+const SECURITY_KEY_REG_STATE: &str = "security_key_reg_state";
+const PASSKEY_UPDATE_ATTEMPTS: usize = 3;
+
 type WebResult<T> = Result<T, Error>;
 
 #[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
 pub(crate) enum Error {
     #[error("Unknown webauthn error")]
     Unknown(WebauthnError),
@@ -33,28 +39,59 @@ pub(crate) enum Error {
     #[error("Serialization error ")]
     SerialisationError,
     #[error("Username is not available")]
-    UsernameUnavailable
+    UsernameUnavailable,
+    #[error("Security key registration is not configured")]
+    AttestationNotConfigured,
+    #[error("Security key attestation could not be verified")]
+    AttestationNotTrusted,
+    #[error("Credential is already registered")]
+    CredentialAlreadyRegistered,
+    #[error("Credential was updated concurrently")]
+    CredentialUpdateConflict,
+    #[error("Database unavailable")]
+    DatabaseUnavailable(#[from] DatabaseUnavailable),
+}
+
+impl From<RepoError> for Error {
+    fn from(e: RepoError) -> Self {
+        match e {
+            RepoError::DuplicateCredential => Error::CredentialAlreadyRegistered,
+            RepoError::CorruptCredential => Error::DeserialisationError,
+            RepoError::DatabaseQueryError => Error::DatabaseQueryError,
+            RepoError::DatabaseUnavailable => Error::DatabaseUnavailable(DatabaseUnavailable),
+        }
+    }
 }
 
 impl actix_web::ResponseError for Error {
     fn status_code(&self) -> StatusCode {
-        StatusCode::INTERNAL_SERVER_ERROR
+        match self {
+            Error::DatabaseUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
     }
 }
 
 
+pub(crate) async fn pow_challenge(pow: Data<ProofOfWork>) -> Result<Json<PowChallenge>, actix_web::Error> {
+    if !pow.enabled() {
+        return Err(actix_web::error::ErrorNotFound("Proof of work is disabled"));
+    }
+    Ok(Json(pow.issue()))
+}
+
+
 pub(crate) async fn register_start(
+    _pow: PowVerified,
     username: Path<String>,
     session:Session,
-    webauthn_users: Data<Mutex<UserData>>,
+    users: Data<dyn UserStore>,
     webauthn: Data<Webauthn>,
 ) -> WebResult<Json<CreationChallengeResponse>> {
     info!("Start Register");
 
-    let repo = UserRepo { client : &webauthn_users.lock().await.client};
-
     // Check if user exists
-    let user_exists = repo.find_unique_id_by_username(&username).await.unwrap().is_some();
+    let user_exists = users.find_unique_id_by_username(&username).await?.is_some();
     if user_exists {
         return Err(Error::UsernameUnavailable);
     }
@@ -84,13 +121,11 @@ pub(crate) async fn register_finish(
     req: Json<RegisterPublicKeyCredential>,
     session: Session,
     webauthn: Data<Webauthn>,
-    webauthn_users: Data<Mutex<UserData>>,
+    users: Data<dyn UserStore>,
 ) -> WebResult<HttpResponse> {
     let (username, user_unique_id, reg_state): (String, Uuid, PasskeyRegistration) =
         session.get("reg_state")?.ok_or(Error::CorruptSession)?;
 
-    let repo = UserRepo { client: &webauthn_users.lock().await.client };
-
     // Finish WebAuthn registration
     let sk = webauthn
         .finish_passkey_registration(&req, &reg_state)
@@ -99,15 +134,13 @@ pub(crate) async fn register_finish(
             Error::BadRequest(e)
         })?;
 
-    let sk_json = serde_json::to_value(&sk).unwrap();
-
-    // Check if the user exists, insert the user and passkey if not
-    if repo.find_unique_id_by_username(&username).await.unwrap().is_none() {
+    // Check if the user exists, insert the user and passkey if not. A new user row
+    // only lands together with its first passkey.
+    if users.find_unique_id_by_username(&username).await?.is_none() {
         let unique_id = Uuid::new_v4();
-        repo.insert_user(&unique_id, &username).await.unwrap();
-        repo.insert_passkey(&unique_id, &sk_json).await.unwrap();
+        users.create_user(&unique_id, &username, &sk).await?;
     } else {
-        repo.insert_passkey(&user_unique_id, &sk_json).await.unwrap();
+        users.insert_passkey(&user_unique_id, &sk, None).await.map_err(Error::from)?;
     }
 
     session.remove("reg_state");
@@ -118,19 +151,91 @@ pub(crate) async fn register_finish(
 
 
 
+// Start a passkey assertion against the credentials registered for a user
+pub(crate) async fn begin_passkey_assertion(
+    repo: &dyn UserStore,
+    webauthn: &Webauthn,
+    user_unique_id: &Uuid,
+) -> WebResult<(RequestChallengeResponse, PasskeyAuthentication)> {
+    let allow_credentials = {
+        let rows = repo.find_passkeys_by_user_id(user_unique_id).await.map_err(|e| {
+            println!("Database query error: fetching the passkey data  {:?}", e);
+            Error::DatabaseQueryError
+        })?;
+
+        if rows.is_empty() {
+            return Err(Error::UserHasNoCredentials);
+        }
+        rows.into_iter().map(|stored| stored.passkey).collect::<Vec<Passkey>>()
+    };
+
+    webauthn
+        .start_passkey_authentication(&allow_credentials)
+        .map_err(|e| {
+            println!("challenge_authenticate -> {:?}", e);
+            Error::Unknown(e)
+        })
+}
+
+// Swap the random challenge of a started assertion for a caller-computed one, so
+// the authenticator signs over that value. webauthn-rs only issues random
+// challenges, hence the round trip through the serialised state.
+pub(crate) fn bind_assertion_challenge(
+    rcr: &mut RequestChallengeResponse,
+    auth_state: &PasskeyAuthentication,
+    challenge: Vec<u8>,
+) -> WebResult<PasskeyAuthentication> {
+    let mut state = serde_json::to_value(auth_state).map_err(|_| Error::SerialisationError)?;
+    let slot = state.pointer_mut("/ast/challenge").ok_or(Error::SerialisationError)?;
+    *slot = serde_json::Value::String(URL_SAFE_NO_PAD.encode(&challenge));
+    rcr.public_key.challenge = challenge.into();
+    serde_json::from_value(state).map_err(|_| Error::DeserialisationError)
+}
+
+// Verify a passkey assertion and persist the updated credential counter
+pub(crate) async fn complete_passkey_assertion(
+    repo: &dyn UserStore,
+    webauthn: &Webauthn,
+    user_unique_id: &Uuid,
+    auth: &PublicKeyCredential,
+    auth_state: &PasskeyAuthentication,
+) -> WebResult<AuthenticationResult> {
+    let auth_result = webauthn
+        .finish_passkey_authentication(auth, auth_state)
+        .map_err(|e| {
+            info!("challenge_register -> {:?}", e);
+            Error::BadRequest(e)
+        })?;
+        println!("auth result  : {:?}",auth_result);
+
+    // A concurrent login with the same credential may have moved the counter since
+    // we read it; re-read the row and apply this result again when that happens
+    let credential_id = credential_id_string(auth_result.cred_id());
+    for _ in 0..PASSKEY_UPDATE_ATTEMPTS {
+        let mut stored = repo.find_passkey(user_unique_id, &credential_id)
+            .await
+            .map_err(Error::from)?
+            .ok_or(Error::UserHasNoCredentials)?;
+        stored.passkey.update_credential(&auth_result);
+        if repo.update_passkey(&stored.passkey, stored.counter).await.map_err(Error::from)? {
+            return Ok(auth_result);
+        }
+    }
+    Err(Error::CredentialUpdateConflict)
+}
+
 pub(crate) async fn start_authentication(
+    _pow: PowVerified,
     username: Path<String>,
     session: Session,
-    webauthn_users: Data<Mutex<UserData>>,
+    users: Data<dyn UserStore>,
     webauthn: Data<Webauthn>,
 ) -> WebResult<Json<RequestChallengeResponse>> {
     info!("Start Authentication");
     session.remove("auth_state");
 
-    let repo = UserRepo { client: &webauthn_users.lock().await.client };
-
     let user_unique_id = {
-        let user_id = repo.find_unique_id_by_username(&username).await.map_err(|e| {
+        let user_id = users.find_unique_id_by_username(&username).await.map_err(|e| {
             println!("Database query error: fetching the user details  {:?}", e);
             Error::DatabaseQueryError
         })?;
@@ -140,26 +245,7 @@ pub(crate) async fn start_authentication(
         }
     };
 
-
-    let allow_credentials = {
-        let rows = repo.find_passkeys_by_user_id(&user_unique_id).await.map_err(|e| {
-            println!("Database query error: fetching the passkey data  {:?}", e);
-            Error::DatabaseQueryError
-        })?;
-
-        let pk_json = rows.ok_or(Error::UserHasNoCredentials)?;
-        serde_json::from_value(pk_json).map_err(|e| {
-            println!("Passkey couldn't be deserialized - {:?}", e);
-            Error::DeserialisationError
-        })?
-    };
-
-    let (rcr, auth_state) = webauthn
-        .start_passkey_authentication(&[allow_credentials])
-        .map_err(|e| {
-            println!("challenge_authenticate -> {:?}", e);
-            Error::Unknown(e)
-        })?;
+    let (rcr, auth_state) = begin_passkey_assertion(&**users, &webauthn, &user_unique_id).await?;
 
     session.insert("auth_state", (user_unique_id, &auth_state))?;
     Ok(Json(rcr))
@@ -171,39 +257,122 @@ pub(crate) async fn start_authentication(
 pub(crate) async fn finish_authentication(
     auth: Json<PublicKeyCredential>,
     session: Session,
-    webauthn_users: Data<Mutex<UserData>>,
+    users: Data<dyn UserStore>,
     webauthn: Data<Webauthn>,
 ) -> WebResult<HttpResponse> {
     println!("startedt finish authentication");
     let (user_unique_id , auth_state) : (Uuid, PasskeyAuthentication)= session.get("auth_state")?.ok_or(Error::CorruptSession)?;
-    
-    let auth_result = webauthn
-        .finish_passkey_authentication(&auth, &auth_state)
-        .map_err(|e| {
-            info!("challenge_register -> {:?}", e);
-            Error::BadRequest(e)
-        })?;
-        println!("auth result  : {:?}",auth_result);
 
-    let repo = UserRepo { client: &webauthn_users.lock().await.client };
+    complete_passkey_assertion(&**users, &webauthn, &user_unique_id, &auth, &auth_state).await?;
 
-    let stored_passkey_json = repo.find_passkeys_by_user_id(&user_unique_id)
-        .await.unwrap()
-        .ok_or(Error::UserHasNoCredentials)?;
-
-    let mut stored_passkey: Passkey = serde_json::from_value(stored_passkey_json.clone())
-    .map_err(|e| {Error::DeserialisationError})?;
-
-    stored_passkey.update_credential(&auth_result);
-
-    let updated_passkey_json = serde_json::to_value(&stored_passkey)
-        .map_err(|e| {Error::SerialisationError})?;
-
-    repo.update_passkey(&user_unique_id, &stored_passkey_json , &updated_passkey_json).await.unwrap();
+    session.remove("auth_state");
     session
         .insert("user_unique_id", user_unique_id)
         .map_err(|_| Error::CorruptSession)?;
+    session.insert(LAST_AUTH_AT, Utc::now().timestamp())?;
 
     println!("Authentication Successful for user: {:?}", user_unique_id);
     Ok(HttpResponse::Ok().finish())
-}
\ No newline at end of file
+}
+
+// Issue a fresh challenge for the logged-in user ahead of a sensitive action
+pub(crate) async fn reauth_start(
+    user: AuthenticatedUser,
+    session: Session,
+    users: Data<dyn UserStore>,
+    webauthn: Data<Webauthn>,
+) -> WebResult<Json<RequestChallengeResponse>> {
+    let (rcr, auth_state) = begin_passkey_assertion(&**users, &webauthn, &user.user_id).await?;
+    session.insert(REAUTH_STATE, (user.user_id, &auth_state))?;
+    Ok(Json(rcr))
+}
+
+pub(crate) async fn reauth_finish(
+    user: AuthenticatedUser,
+    auth: Json<PublicKeyCredential>,
+    session: Session,
+    users: Data<dyn UserStore>,
+    webauthn: Data<Webauthn>,
+) -> WebResult<HttpResponse> {
+    let (user_unique_id, auth_state): (Uuid, PasskeyAuthentication) =
+        session.get(REAUTH_STATE)?.ok_or(Error::CorruptSession)?;
+    if user_unique_id != user.user_id {
+        return Err(Error::CorruptSession);
+    }
+
+    complete_passkey_assertion(&**users, &webauthn, &user_unique_id, &auth, &auth_state).await?;
+
+    session.remove(REAUTH_STATE);
+    session.insert(LAST_AUTH_AT, Utc::now().timestamp())?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+// Register an additional credential for the logged-in user from a security key whose
+// attestation chains to one of the roots in `ATTESTATION_CA_PATHS`
+pub(crate) async fn security_key_register_start(
+    recent: RecentAuth,
+    session: Session,
+    users: Data<dyn UserStore>,
+    webauthn: Data<Webauthn>,
+    attestation: Data<AttestationTrust>,
+) -> WebResult<Json<CreationChallengeResponse>> {
+    if !attestation.enabled() {
+        return Err(Error::AttestationNotConfigured);
+    }
+    let user_id = recent.user.user_id;
+    let username = users
+        .find_username_by_id(&user_id)
+        .await
+        .map_err(|_| Error::DatabaseQueryError)?
+        .ok_or(Error::UserNotFound)?;
+
+    session.remove(SECURITY_KEY_REG_STATE);
+    let (ccr, reg_state) = webauthn
+        .start_attested_passkey_registration(
+            user_id,
+            &username,
+            &username,
+            None,
+            attestation.ca_list.clone(),
+            Some(AuthenticatorAttachment::CrossPlatform),
+        )
+        .map_err(|e| {
+            debug!("Challenge_register -> {:?}", e);
+            Error::Unknown(e)
+        })?;
+    session.insert(SECURITY_KEY_REG_STATE, (user_id, reg_state))?;
+    Ok(Json(ccr))
+}
+
+pub(crate) async fn security_key_register_finish(
+    user: AuthenticatedUser,
+    req: Json<RegisterPublicKeyCredential>,
+    session: Session,
+    users: Data<dyn UserStore>,
+    webauthn: Data<Webauthn>,
+    attestation: Data<AttestationTrust>,
+) -> WebResult<HttpResponse> {
+    let (user_id, reg_state): (Uuid, AttestedPasskeyRegistration) =
+        session.get(SECURITY_KEY_REG_STATE)?.ok_or(Error::CorruptSession)?;
+    if user_id != user.user_id {
+        return Err(Error::CorruptSession);
+    }
+
+    let security_key = webauthn
+        .finish_attested_passkey_registration(&req, &reg_state)
+        .map_err(|e| {
+            println!("Error during security key registration: {:?}", e);
+            Error::BadRequest(e)
+        })?;
+    let verified = attestation.verify(&security_key).ok_or(Error::AttestationNotTrusted)?;
+
+    users.insert_passkey(&user_id, &Passkey::from(security_key), Some(&verified))
+        .await
+        .map_err(Error::from)?;
+
+    session.remove(SECURITY_KEY_REG_STATE);
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "aaguid": verified.aaguid,
+        "attestation_ca": verified.ca_fingerprint,
+    })))
+}
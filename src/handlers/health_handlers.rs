@@ -0,0 +1,51 @@
+use actix_web::{web::Data, HttpResponse};
+use serde_json::{json, Value};
+
+use crate::{db_operations_repo::store::StoreHealth, health::Readiness, session::SessionBackend};
+
+fn component(result: anyhow::Result<Value>) -> (bool, Value) {
+    match result {
+        Ok(mut details) => {
+            details["status"] = json!("up");
+            (true, details)
+        }
+        Err(e) => (false, json!({"status": "down", "error": e.to_string()})),
+    }
+}
+
+// Liveness: the process is up and serving requests
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(json!({"status": "alive"}))
+}
+
+/**
+Readiness: the database answers, the session store can be read and the schema has
+every migration this build expects. Reports each component and answers `503` when any
+of them is down or the server is shutting down.
+*/
+pub async fn readyz(
+    readiness: Data<Readiness>,
+    health: Data<dyn StoreHealth>,
+    session: Data<SessionBackend>,
+) -> HttpResponse {
+    if readiness.is_shutting_down() {
+        return HttpResponse::ServiceUnavailable().json(json!({"status": "shutting_down"}));
+    }
+    let (database_up, database) = component(health.ping().await.map(|_| json!({})));
+    let (session_up, session_store) = component(session.ping().await.map(|_| json!({})));
+    let (migrations_up, migrations) = component(health.pending_migrations().await.and_then(|pending| {
+        match pending {
+            0 => Ok(json!({"pending": 0})),
+            n => Err(anyhow::anyhow!("{} pending migrations", n)),
+        }
+    }));
+    let body = |status: &str| json!({
+        "status": status,
+        "components": {"database": database, "session_store": session_store, "migrations": migrations},
+    });
+    if database_up && session_up && migrations_up {
+        HttpResponse::Ok().json(body("ready"))
+    } else {
+        HttpResponse::ServiceUnavailable().json(body("not_ready"))
+    }
+}
@@ -1,2 +1,9 @@
+pub mod admin_handlers;
+pub mod device_handlers;
+pub mod health_handlers;
+#[allow(clippy::module_inception)]
 pub mod handlers;
-pub mod polls_handlers;
\ No newline at end of file
+pub mod polls_handlers;
+pub mod token_handlers;
+pub mod oidc_handlers;
+pub mod qr_login_handlers;
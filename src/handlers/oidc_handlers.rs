@@ -0,0 +1,291 @@
+use actix_web::{
+    http::header,
+    web::{self, Data},
+    Error, HttpRequest, HttpResponse, ResponseError,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use serde_json::json;
+use webauthn_rs::prelude::Url;
+
+use crate::{
+    db_operations_repo::{
+        oidc_repo::{AuthorizationCode, OidcClient, OidcRepo},
+        store::UserStore,
+    },
+    handlers::device_handlers::{device_code_grant, DEVICE_CODE_GRANT},
+    identity::{bearer_token, AuthMethod, AuthenticatedUser},
+    oidc::{verify_pkce, IdTokenClaims, OidcProvider},
+    startup::UserData,
+    tokens::{generate_opaque_token, hash_token, TokenSigner, USERINFO_AUDIENCE},
+};
+
+#[derive(Deserialize)]
+pub struct AuthorizeParams {
+    response_type: String,
+    client_id: String,
+    redirect_uri: String,
+    scope: String,
+    state: Option<String>,
+    nonce: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+    max_age: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct TokenRequest {
+    grant_type: String,
+    code: Option<String>,
+    redirect_uri: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    code_verifier: Option<String>,
+    device_code: Option<String>,
+}
+
+// OAuth error body as described in RFC 6749 section 5.2
+pub(crate) fn oauth_error(status: actix_web::http::StatusCode, error: &str, description: &str) -> HttpResponse {
+    HttpResponse::build(status).json(json!({
+        "error": error,
+        "error_description": description,
+    }))
+}
+
+fn redirect(location: &str) -> HttpResponse {
+    HttpResponse::Found().insert_header((header::LOCATION, location)).finish()
+}
+
+// Errors discovered after the redirect URI is validated go back to the client
+fn redirect_error(redirect_uri: &str, error: &str, state: &Option<String>) -> Result<HttpResponse, Error> {
+    let mut url = Url::parse(redirect_uri).map_err(|_| actix_web::error::ErrorBadRequest("Invalid redirect_uri"))?;
+    url.query_pairs_mut().append_pair("error", error);
+    if let Some(state) = state {
+        url.query_pairs_mut().append_pair("state", state);
+    }
+    Ok(redirect(url.as_str()))
+}
+
+pub async fn openid_configuration(oidc: Data<OidcProvider>) -> HttpResponse {
+    HttpResponse::Ok().json(oidc.discovery_document())
+}
+
+pub async fn jwks(oidc: Data<OidcProvider>) -> HttpResponse {
+    HttpResponse::Ok().json(oidc.jwks())
+}
+
+pub async fn authorize(
+    req: HttpRequest,
+    user: Option<AuthenticatedUser>,
+    params: web::Query<AuthorizeParams>,
+    oidc: Data<OidcProvider>,
+    webauthn_users: Data<UserData>,
+) -> Result<HttpResponse, Error> {
+    let repo = OidcRepo { client: &*webauthn_users.client().await? };
+    let client = repo
+        .find_client(&params.client_id)
+        .await
+        .map_err(|_| actix_web::error::ErrorInternalServerError("Error fetching client"))?
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("Unknown client_id"))?;
+    if !client.redirect_uris.contains(&params.redirect_uri) {
+        return Err(actix_web::error::ErrorBadRequest("redirect_uri is not registered for this client"));
+    }
+
+    if params.response_type != "code" {
+        return redirect_error(&params.redirect_uri, "unsupported_response_type", &params.state);
+    }
+    if !params.scope.split(' ').any(|s| s == "openid") {
+        return redirect_error(&params.redirect_uri, "invalid_scope", &params.state);
+    }
+    let code_challenge = match (&params.code_challenge, params.code_challenge_method.as_deref()) {
+        (Some(challenge), Some("S256")) => challenge.clone(),
+        _ => return redirect_error(&params.redirect_uri, "invalid_request", &params.state),
+    };
+
+    // Only a browser session that went through the passkey login counts; otherwise
+    // send the user to the login page and have it come back here afterwards
+    let now = Utc::now().timestamp();
+    let logged_in = user.filter(|u| u.method == AuthMethod::Session).and_then(|u| {
+        let auth_time = u.auth_time?;
+        match params.max_age {
+            Some(max_age) if now - auth_time > max_age => None,
+            _ => Some((u.user_id, auth_time)),
+        }
+    });
+    let (user_id, auth_time) = match logged_in {
+        Some(logged_in) => logged_in,
+        None => {
+            let mut login = Url::parse(&oidc.login_url)
+                .map_err(|_| actix_web::error::ErrorInternalServerError("Invalid OIDC_LOGIN_URL"))?;
+            let return_to = format!("{}{}", oidc.issuer.trim_end_matches('/'), req.uri());
+            login.query_pairs_mut().append_pair("return_to", &return_to);
+            return Ok(redirect(login.as_str()));
+        }
+    };
+
+    let code = generate_opaque_token();
+    let authorization = AuthorizationCode {
+        client_id: client.client_id,
+        user_id,
+        redirect_uri: params.redirect_uri.clone(),
+        scope: params.scope.clone(),
+        nonce: params.nonce.clone(),
+        code_challenge,
+        auth_time: DateTime::from_timestamp(auth_time, 0).unwrap_or_else(Utc::now),
+    };
+    let expires_at = Utc::now() + Duration::seconds(oidc.code_ttl_secs);
+    repo.insert_code(&hash_token(&code), &authorization, &expires_at)
+        .await
+        .map_err(|_| actix_web::error::ErrorInternalServerError("Error storing authorization code"))?;
+
+    let mut url = Url::parse(&params.redirect_uri).map_err(|_| actix_web::error::ErrorBadRequest("Invalid redirect_uri"))?;
+    url.query_pairs_mut().append_pair("code", &code);
+    if let Some(state) = &params.state {
+        url.query_pairs_mut().append_pair("state", state);
+    }
+    Ok(redirect(url.as_str()))
+}
+
+// Authenticate the client from HTTP Basic credentials or the form body
+pub(crate) async fn authenticate_client(
+    repo: &OidcRepo<'_>,
+    req: &HttpRequest,
+    form_client_id: Option<&str>,
+    form_client_secret: Option<&str>,
+) -> Result<OidcClient, HttpResponse> {
+    let basic = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Basic "))
+        .and_then(|v| STANDARD.decode(v).ok())
+        .and_then(|v| String::from_utf8(v).ok())
+        .and_then(|v| v.split_once(':').map(|(id, secret)| (id.to_string(), Some(secret.to_string()))));
+    let (client_id, client_secret) = match basic {
+        Some(credentials) => credentials,
+        None => match form_client_id {
+            Some(client_id) => (client_id.to_string(), form_client_secret.map(str::to_string)),
+            None => return Err(oauth_error(actix_web::http::StatusCode::UNAUTHORIZED, "invalid_client", "Missing client credentials")),
+        },
+    };
+
+    let invalid_client = || oauth_error(actix_web::http::StatusCode::UNAUTHORIZED, "invalid_client", "Client authentication failed");
+    let client = match repo.find_client(&client_id).await {
+        Ok(Some(client)) => client,
+        Ok(None) => return Err(invalid_client()),
+        Err(_) => return Err(HttpResponse::InternalServerError().body("Error fetching client")),
+    };
+    match (&client.client_secret_hash, client_secret) {
+        (None, _) => Ok(client),
+        (Some(expected), Some(secret)) if *expected == hash_token(&secret) => Ok(client),
+        _ => Err(invalid_client()),
+    }
+}
+
+pub async fn token(
+    req: HttpRequest,
+    form: web::Form<TokenRequest>,
+    oidc: Data<OidcProvider>,
+    signer: Data<TokenSigner>,
+    webauthn_users: Data<UserData>,
+    users: Data<dyn UserStore>,
+) -> HttpResponse {
+    use actix_web::http::StatusCode;
+
+    let db = match webauthn_users.client().await {
+        Ok(db) => db,
+        Err(e) => return e.error_response(),
+    };
+    let repo = OidcRepo { client: &db };
+    let client = match authenticate_client(&repo, &req, form.client_id.as_deref(), form.client_secret.as_deref()).await {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+
+    match form.grant_type.as_str() {
+        "authorization_code" => {}
+        DEVICE_CODE_GRANT => {
+            let device_code = match &form.device_code {
+                Some(device_code) => device_code,
+                None => return oauth_error(StatusCode::BAD_REQUEST, "invalid_request", "device_code is required"),
+            };
+            return device_code_grant(&db, &signer, &client, device_code).await;
+        }
+        _ => return oauth_error(StatusCode::BAD_REQUEST, "unsupported_grant_type", "Unsupported grant_type"),
+    }
+
+    let (code, code_verifier) = match (&form.code, &form.code_verifier) {
+        (Some(code), Some(verifier)) => (code, verifier),
+        _ => return oauth_error(StatusCode::BAD_REQUEST, "invalid_request", "code and code_verifier are required"),
+    };
+    let invalid_grant = |description| oauth_error(StatusCode::BAD_REQUEST, "invalid_grant", description);
+    let authorization = match repo.consume_code(&hash_token(code)).await {
+        Ok(Some(authorization)) => authorization,
+        Ok(None) => return invalid_grant("Unknown, expired or already used code"),
+        Err(_) => return HttpResponse::InternalServerError().body("Error reading authorization code"),
+    };
+    if authorization.client_id != client.client_id {
+        return invalid_grant("Code was issued to another client");
+    }
+    if form.redirect_uri.as_deref() != Some(authorization.redirect_uri.as_str()) {
+        return invalid_grant("redirect_uri does not match the authorization request");
+    }
+    if !verify_pkce(code_verifier, &authorization.code_challenge) {
+        return invalid_grant("PKCE verification failed");
+    }
+
+    let username = users
+        .find_username_by_id(&authorization.user_id)
+        .await
+        .ok()
+        .flatten();
+    let now = Utc::now().timestamp();
+    let scope: Vec<String> = authorization.scope.split(' ').map(str::to_string).collect();
+    let id_token = oidc.sign_id_token(&IdTokenClaims {
+        iss: &oidc.issuer,
+        sub: authorization.user_id,
+        aud: &client.client_id,
+        iat: now,
+        exp: now + oidc.id_token_ttl_secs,
+        auth_time: authorization.auth_time.timestamp(),
+        nonce: authorization.nonce.as_deref(),
+        preferred_username: username.as_deref().filter(|_| scope.iter().any(|s| s == "profile")),
+    });
+    let access_token = signer.issue_access_token(authorization.user_id, USERINFO_AUDIENCE, authorization.auth_time.timestamp(), Some(scope));
+
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(json!({
+            "access_token": access_token,
+            "token_type": "Bearer",
+            "expires_in": signer.access_ttl_secs,
+            "id_token": id_token,
+            "scope": authorization.scope,
+        }))
+}
+
+// Only accepts the access tokens issued by the token endpoint above, which are good
+// for nothing else
+pub async fn userinfo(
+    req: HttpRequest,
+    signer: Data<TokenSigner>,
+    users: Data<dyn UserStore>,
+) -> Result<HttpResponse, Error> {
+    let token = bearer_token(&req).ok_or_else(|| actix_web::error::ErrorUnauthorized("A bearer access token is required"))?;
+    let claims = signer
+        .verify_access_token(&token, USERINFO_AUDIENCE)
+        .map_err(|e| actix_web::error::ErrorUnauthorized(e.to_string()))?;
+    if !claims.scope.iter().flatten().any(|s| s == "openid") {
+        return Err(actix_web::error::ErrorForbidden("The access token lacks the openid scope"));
+    }
+    let username = users
+        .find_username_by_id(&claims.sub)
+        .await
+        .map_err(|_| actix_web::error::ErrorInternalServerError("Error fetching user"))?
+        .ok_or_else(|| actix_web::error::ErrorNotFound("User not found"))?;
+    Ok(HttpResponse::Ok().json(json!({
+        "sub": claims.sub,
+        "preferred_username": username,
+    })))
+}
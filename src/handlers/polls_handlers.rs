@@ -1,19 +1,45 @@
 
-use std::time::Duration;
-
-use crate::{db_operations_repo::{poll_repo::{PollDetails, PollOptions, PollRepo, RepoError}, user_passkey_repo::UserRepo},startup::UserData, web_socket_handlers::start_connection::Chat};
+use crate::{db_operations_repo::{poll_repo::{PollCursor, PollDetails, PollFilter, PollOptions, PollPage, PollSort, PollState, PollTransition, RepoError, VoteOutcome, VoteTally}, search::search_terms, store::{NewPoll, PollStore, UserStore}}, handlers::handlers::{begin_passkey_assertion, bind_assertion_challenge, complete_passkey_assertion}, identity::{AuthenticatedUser, RecentAuth, Scope}, rbac::{Permission, UserPermissions}, tokens::random_bytes, web_socket_handlers::start_connection::Chat};
 use actix_session::Session;
-use actix_web::{web::{self, Data}, Error, HttpResponse};
-use chrono::Utc;
+use actix_web::{error::InternalError, web::{self, Data}, Error, HttpRequest, HttpResponse};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use tokio::{sync::{broadcast::{self, Sender}, Mutex}, time::timeout};
+use serde_json::json;
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
+use webauthn_rs::prelude::*;
+
+// (poll id, option ids, nonce, voter, assertion state) of a vote awaiting its passkey confirmation
+const VOTE_CONFIRM_STATE: &str = "vote_confirm_state";
 
+// The poll creator is always the session user; a `creator` field sent by older
+// clients is ignored.
 #[derive(Serialize,Deserialize)]
 pub struct CreatePollRequest {
     title: String,
-    creator: String,
     options: Vec<String>,
+    #[serde(default)]
+    require_passkey_vote: bool,
+    // Restrict voting to company-issued security keys, by device model or attestation root
+    #[serde(default)]
+    allowed_aaguids: Vec<Uuid>,
+    #[serde(default)]
+    allowed_attestation_cas: Vec<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    // When the poll is scheduled to close, RFC 3339
+    closes_at: Option<DateTime<Utc>>,
+    // Let voters replace their vote; otherwise a second vote gets `409`
+    #[serde(default)]
+    allow_vote_change: bool,
+    // Start as a draft, seen only by the creator until published
+    #[serde(default)]
+    draft: bool,
+    // How many options one vote selects: at least `min_selections` (1 by default) and
+    // at most `max_selections` (`min_selections` by default)
+    min_selections: Option<i32>,
+    max_selections: Option<i32>,
 }
 
 
@@ -22,83 +48,275 @@ pub struct PollQueryParams {
     creator: Option<Uuid>, // Creator ID, optional
     live: Option<bool>,    // Whether the poll is live, optional
     closed: Option<bool>,  // Whether the poll is closed, optional
+    status: Option<PollState>, // draft, open (or live), closed or archived, optional
+    created_after: Option<DateTime<Utc>>,  // RFC 3339, inclusive
+    created_before: Option<DateTime<Utc>>, // RFC 3339, exclusive
+    q: Option<String>,     // Case-insensitive title search, optional
+    tags: Option<String>,  // Comma-separated tags the poll must all carry
+    #[serde(default)]
+    sort: PollSort,        // newest (default), oldest, most_votes or closing_soonest
+    limit: Option<i64>,    // Page size, 20 by default and at most 100
+    cursor: Option<String>, // The `next_cursor` of the previous page
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct Poll {
-    id: i32,               
-    title: String,
-    creator_id: Uuid,    
-    options: Vec<String>,
-    
+const DEFAULT_PAGE_SIZE: i64 = 20;
+const MAX_PAGE_SIZE: i64 = 100;
+
+impl PollQueryParams {
+    fn limit(&self) -> Result<i64, Error> {
+        let limit = self.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+        if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+            return Err(actix_web::error::ErrorBadRequest(format!("limit must be between 1 and {}", MAX_PAGE_SIZE)));
+        }
+        Ok(limit)
+    }
+
+    fn page(&self) -> Result<PollPage, Error> {
+        let limit = self.limit()?;
+        let after = match self.cursor.as_deref() {
+            Some(cursor) => {
+                let cursor = PollCursor::decode(cursor).ok_or_else(|| actix_web::error::ErrorBadRequest("Invalid cursor"))?;
+                if cursor.sort() != self.sort {
+                    return Err(actix_web::error::ErrorBadRequest("Cursor belongs to a different sort"));
+                }
+                Some(cursor)
+            }
+            None => None,
+        };
+        Ok(PollPage { sort: self.sort, limit: Some(limit), after })
+    }
+
+    // `live`, `closed` and `status` all ask for a state; they must agree. The viewer
+    // also sees their own drafts
+    fn filter(&self, viewer: Option<Uuid>) -> Result<PollFilter, Error> {
+        let states = [
+            self.live.map(|live| if live { PollState::Open } else { PollState::Closed }),
+            self.closed.map(|closed| if closed { PollState::Closed } else { PollState::Open }),
+            self.status,
+        ];
+        let mut filter = PollFilter::new();
+        for state in states.into_iter().flatten() {
+            if filter.state.is_some_and(|s| s != state) {
+                return Err(actix_web::error::ErrorBadRequest("Conflicting live, closed and status filters"));
+            }
+            filter = filter.state(state);
+        }
+        if let Some(viewer) = viewer {
+            filter = filter.viewer(viewer);
+        }
+        if let Some(creator) = self.creator {
+            filter = filter.creator(creator);
+        }
+        if let Some(after) = self.created_after {
+            filter = filter.created_after(after.naive_utc());
+        }
+        if let Some(before) = self.created_before {
+            filter = filter.created_before(before.naive_utc());
+        }
+        if let Some(q) = self.q.as_deref().map(str::trim).filter(|q| !q.is_empty()) {
+            filter = filter.title_contains(q);
+        }
+        for tag in self.tags.iter().flat_map(|tags| tags.split(',')).map(str::trim).filter(|t| !t.is_empty()) {
+            filter = filter.tagged(tag);
+        }
+        Ok(filter)
+    }
+}
+
+// The options a vote selects. Older clients send a single `option_text`
+#[derive(Deserialize,Debug)]
+pub struct OptionSelection {
+    #[serde(default)]
+    option_texts: Vec<String>,
+    option_text: Option<String>,
 }
 
+// The voter is always the session user; a `username` field sent by older clients
+// is ignored.
 #[derive(Deserialize,Debug)]
 pub struct VoteRequest {
-    username: String,
-    option_text: String,
+    #[serde(flatten)]
+    selection: OptionSelection,
+    // Required on polls created with `require_passkey_vote`
+    assertion: Option<PublicKeyCredential>,
 }
 
-#[derive(Serialize, Deserialize , Clone , Debug)]
-pub struct VoteUpdate {
-    poll_id: i32,
-    option_id: i32,
-    votes: i32,
+#[derive(Deserialize)]
+pub struct VoteChallengeRequest {
+    #[serde(flatten)]
+    selection: OptionSelection,
+}
+
+// The challenge a confirmed vote is signed over: SHA-256 of the poll, the sorted
+// option ids and the nonce
+pub(crate) fn vote_challenge(poll_id: i32, option_ids: &[i32], nonce: &str) -> Vec<u8> {
+    let option_ids: Vec<String> = option_ids.iter().map(i32::to_string).collect();
+    Sha256::digest(format!("poll-vote:{}:{}:{}", poll_id, option_ids.join(","), nonce)).to_vec()
+}
+
+// Resolve the selected options to their ids, in id order, checking them against
+// the poll's selection limits
+async fn selected_options(polls: &dyn PollStore, poll: &PollDetails, selection: &OptionSelection) -> Result<Vec<i32>, Error> {
+    let texts: Vec<&String> = selection.option_texts.iter().chain(&selection.option_text).collect();
+    let count = texts.len() as i32;
+    if count < poll.min_selections || count > poll.max_selections {
+        let message = format!("Select between {} and {} options", poll.min_selections, poll.max_selections);
+        let response = HttpResponse::BadRequest().json(json!({
+            "error": "invalid_selection",
+            "min_selections": poll.min_selections,
+            "max_selections": poll.max_selections,
+            "message": message,
+        }));
+        return Err(InternalError::from_response(message, response).into());
+    }
+    let mut option_ids = Vec::with_capacity(texts.len());
+    for text in texts {
+        match polls.get_option_id_by_text_and_poll_id(text, poll.id).await {
+            Ok(Some(id)) => option_ids.push(id),
+            Ok(None) => {
+                println!("Option not found");
+                return Err(actix_web::error::ErrorNotFound("Option not found"))
+            },
+            Err(_) => {
+                println!("Error fetching option");
+                return Err(actix_web::error::ErrorInternalServerError("Error fetching option"))
+            },
+        }
+    }
+    option_ids.sort_unstable();
+    if option_ids.windows(2).any(|pair| pair[0] == pair[1]) {
+        return Err(actix_web::error::ErrorBadRequest("Option selected more than once"));
+    }
+    Ok(option_ids)
 }
 
 pub async fn create_poll(
-    webauthn_users: Data<Mutex<UserData>>,
+    permissions: UserPermissions,
+    polls: Data<dyn PollStore>,
     req: web::Json<CreatePollRequest>,
 ) -> Result<HttpResponse,Error> {
     println!("entereed create_poll");
-    let user_repo = UserRepo {client: &webauthn_users.lock().await.client };
-    let user_id_result = timeout(Duration::from_secs(5), user_repo.find_unique_id_by_username(&req.creator))
-        .await
-        .map_err(|_| actix_web::error::ErrorInternalServerError("Timeout while fetching user"))?.unwrap();
-
-    let id = match user_id_result {
-        Some(id) => id,
-        None => {
-            return Err(actix_web::error::ErrorUnauthorized("User not authenticated"));
-        }
-    };
-    print!("id:  {:?}",id);
-
-    let repo = PollRepo { client: user_repo.client };
+    permissions.user.require_scope(Scope::WritePolls)?;
+    permissions.require(Permission::CreatePoll)?;
+    // Votes name options by their text, so two options may not share one
+    if req.options.iter().enumerate().any(|(i, option)| req.options[..i].contains(option)) {
+        return Err(actix_web::error::ErrorBadRequest("Poll options must be distinct"));
+    }
+    if req.closes_at.is_some_and(|at| at <= Utc::now()) {
+        return Err(actix_web::error::ErrorBadRequest("closes_at must be in the future"));
+    }
+    let min_selections = req.min_selections.unwrap_or(1);
+    let max_selections = req.max_selections.unwrap_or(min_selections);
+    if min_selections < 1 || min_selections > max_selections || max_selections as usize > req.options.len() {
+        return Err(actix_web::error::ErrorBadRequest(
+            "Selections must satisfy 1 <= min_selections <= max_selections <= number of options",
+        ));
+    }
+    // Accept fingerprints as printed by `openssl x509 -fingerprint -sha256`
+    let allowed_attestation_cas: Vec<String> = req.allowed_attestation_cas.iter().map(|ca| ca.replace(':', "").to_lowercase()).collect();
     println!("starting inserting poll");
-    match repo.insert_poll(&req.title, id, &req.options).await {
+    // The poll and its options are inserted together or not at all
+    match polls.create_poll(NewPoll {
+        title: &req.title,
+        creator_id: permissions.user.user_id,
+        options: &req.options,
+        require_passkey_vote: req.require_passkey_vote,
+        allowed_aaguids: &req.allowed_aaguids,
+        allowed_attestation_cas: &allowed_attestation_cas,
+        tags: &req.tags,
+        closes_at: req.closes_at.map(|at| at.naive_utc()),
+        allow_vote_change: req.allow_vote_change,
+        state: if req.draft { PollState::Draft } else { PollState::Open },
+        min_selections,
+        max_selections,
+    }).await {
         Ok(poll_id) => {
             println!("preparing the HttpResponse in json format");
             Ok(HttpResponse::Created().json(poll_id))
         },
+        Err(RepoError::DatabaseUnavailable) => Err(RepoError::DatabaseUnavailable.into()),
         Err(_) => {
             Err(actix_web::error::ErrorInternalServerError(RepoError::DatabaseQueryError))
         }
     }
 }
 
-pub async fn get_all_polls_from_db(webauthn_users: Data<Mutex<UserData>> , query: web::Query<PollQueryParams>) -> Result<HttpResponse,Error> {
-    let repo = PollRepo {client : &webauthn_users.lock().await.client };
-    let PollQueryParams { creator, live, closed } = query.into_inner();
-    match repo.get_polls_filtered(creator, live, closed).await {
-        Ok(polls) =>{ 
-            let response = serde_json::json!(polls);
-            Ok(HttpResponse::Ok().json(response))
+// The same listing from `cursor` on, keeping every other query parameter
+fn next_link(req: &HttpRequest, cursor: &str) -> String {
+    let query: Vec<&str> = req.query_string()
+        .split('&')
+        .filter(|param| !param.is_empty() && !param.starts_with("cursor="))
+        .collect();
+    let mut link = format!("{}?", req.path());
+    for param in query {
+        link.push_str(param);
+        link.push('&');
+    }
+    link.push_str("cursor=");
+    link.push_str(cursor);
+    link
+}
+
+pub async fn get_all_polls_from_db(
+    req: HttpRequest,
+    polls: Data<dyn PollStore>,
+    user: Option<AuthenticatedUser>,
+    query: web::Query<PollQueryParams>,
+) -> Result<HttpResponse,Error> {
+    if let Some(user) = &user {
+        user.require_scope(Scope::ReadPolls)?;
+    }
+    let filter = query.filter(user.as_ref().map(|u| u.user_id))?;
+    let page = query.page()?;
+    let total = polls.count_polls(&filter).await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Error counting polls: {:?}", e)))?;
+    match polls.get_polls_page(&filter, &page).await {
+        Ok(polls) => {
+            // A full page may be followed by more
+            let next_cursor = match polls.last() {
+                Some(last) if polls.len() as i64 == page.limit.unwrap_or(i64::MAX) => Some(PollCursor::after(page.sort, last).encode()),
+                _ => None,
+            };
+            let next = next_cursor.as_deref().map(|cursor| next_link(&req, cursor));
+            Ok(HttpResponse::Ok().json(json!({
+                "polls": polls,
+                "total": total,
+                "next_cursor": next_cursor,
+                "next": next,
+            })))
         },
         Err(e) => Err(actix_web::error::ErrorInternalServerError(format!("Error fetching polls: {:?}", e))),
     }
 }
 
-pub async fn manage_user_polls(webauthn_users: Data<Mutex<UserData>>,session:Session) -> Result<HttpResponse, Error>{
-    let repo = PollRepo { client: &webauthn_users.lock().await.client };
-    let user_id: Uuid = match session.get("user_unique_id").expect("Session get error") {
-        Some(id) => id, 
-        None => {
-            return Err(actix_web::error::ErrorUnauthorized("User not authenticated"));
-        } 
-    };
+// Full-text search over titles and options; `q` is the search, the other filters and
+// `limit` work as for the listing
+pub async fn search_polls(
+    polls: Data<dyn PollStore>,
+    user: Option<AuthenticatedUser>,
+    query: web::Query<PollQueryParams>,
+) -> Result<HttpResponse, Error> {
+    if let Some(user) = &user {
+        user.require_scope(Scope::ReadPolls)?;
+    }
+    let terms = search_terms(query.q.as_deref().unwrap_or_default());
+    if terms.is_empty() {
+        return Err(actix_web::error::ErrorBadRequest("q must contain at least one word"));
+    }
+    let mut filter = query.filter(user.as_ref().map(|u| u.user_id))?;
+    filter.title_search = None;
+    let limit = query.limit()?;
+    match polls.search_polls(&terms, &filter, limit).await {
+        Ok(results) => Ok(HttpResponse::Ok().json(json!({ "terms": terms, "results": results }))),
+        Err(e) => Err(actix_web::error::ErrorInternalServerError(format!("Error searching polls: {:?}", e))),
+    }
+}
+
+pub async fn manage_user_polls(polls: Data<dyn PollStore>, user: AuthenticatedUser) -> Result<HttpResponse, Error>{
+    user.require_scope(Scope::ManagePolls)?;
+    let user_id = user.user_id;
     println!("user id from session:  {:?}",user_id);
-    match repo.get_polls_by_creator(user_id).await {
+    match polls.get_polls_by_creator(user_id).await {
         Ok(polls) => {
             let response_data = serde_json::json!({
                 "user_id": user_id,
@@ -110,73 +328,195 @@ pub async fn manage_user_polls(webauthn_users: Data<Mutex<UserData>>,session:Ses
     }
 }
 
+// A poll as the viewer may see it: drafts are only found by their creator
+async fn visible_poll(polls: &dyn PollStore, poll_id: i32, viewer: Option<Uuid>) -> Result<PollDetails, Error> {
+    match polls.get_poll_by_id(poll_id).await {
+        Ok(Some(poll)) if poll.state != PollState::Draft || viewer == Some(poll.creator_id) => Ok(poll),
+        Ok(_) => Err(actix_web::error::ErrorNotFound("Poll not found")),
+        Err(RepoError::DatabaseUnavailable) => Err(RepoError::DatabaseUnavailable.into()),
+        Err(_) => Err(actix_web::error::ErrorInternalServerError("Error fetching poll")),
+    }
+}
+
+// Refusal of a change the poll's state does not allow
+fn wrong_state(error: &str, state: PollState, message: String) -> Error {
+    let response = HttpResponse::Conflict().json(json!({
+        "error": error,
+        "state": state,
+        "message": message,
+    }));
+    InternalError::from_response(message, response).into()
+}
+
+fn poll_not_open(state: PollState) -> Error {
+    wrong_state("poll_not_open", state, format!("Poll is {}", state))
+}
+
+// Votes go to open polls that have not reached their `closes_at`
+fn require_open(poll: &PollDetails) -> Result<(), Error> {
+    match poll.state_at(Utc::now().naive_utc()) {
+        PollState::Open => Ok(()),
+        state => Err(poll_not_open(state)),
+    }
+}
+
+// Start the passkey assertion that confirms a vote on a poll requiring one
+pub async fn start_vote_confirmation(
+    user: AuthenticatedUser,
+    users: Data<dyn UserStore>,
+    polls: Data<dyn PollStore>,
+    webauthn: Data<Webauthn>,
+    poll_id: web::Path<i32>,
+    req: web::Json<VoteChallengeRequest>,
+    session: Session,
+) -> Result<HttpResponse,Error> {
+    user.require_scope(Scope::Vote)?;
+    let poll = visible_poll(&**polls, *poll_id, Some(user.user_id)).await?;
+    require_open(&poll)?;
+    let option_ids = selected_options(&**polls, &poll, &req.selection).await?;
+
+    let nonce = URL_SAFE_NO_PAD.encode(random_bytes(32));
+    let (mut rcr, auth_state) = begin_passkey_assertion(&**users, &webauthn, &user.user_id).await?;
+    let auth_state = bind_assertion_challenge(&mut rcr, &auth_state, vote_challenge(*poll_id, &option_ids, &nonce))?;
+    session.insert(VOTE_CONFIRM_STATE, (*poll_id, &option_ids, &nonce, user.user_id, &auth_state))?;
+    Ok(HttpResponse::Ok().json(json!({ "nonce": nonce, "challenge": rcr })))
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn vote_on_poll(
-    webauthn_users: Data<Mutex<UserData>>,
+    user: AuthenticatedUser,
+    users: Data<dyn UserStore>,
+    polls: Data<dyn PollStore>,
+    webauthn: Data<Webauthn>,
     poll_id: web::Path<i32>,
     req: web::Json<VoteRequest>,
     chat: web::Data<Chat>,
+    session: Session,
 ) -> Result<HttpResponse,Error> {
+    user.require_scope(Scope::Vote)?;
+    let user_id = user.user_id;
+
+    let poll = visible_poll(&**polls, *poll_id, Some(user_id)).await?;
+    // The state is checked again as the vote is written; this spares a passkey confirmation
+    require_open(&poll)?;
+
+    if !poll.allowed_aaguids.is_empty() || !poll.allowed_attestation_cas.is_empty() {
+        let eligible = users
+            .has_attested_passkey(&user_id, &poll.allowed_aaguids, &poll.allowed_attestation_cas)
+            .await
+            .map_err(|_| actix_web::error::ErrorInternalServerError("Error checking security keys"))?;
+        if !eligible {
+            let response = HttpResponse::Forbidden().json(json!({
+                "error": "attested_key_required",
+                "message": "This poll only accepts votes from users with a registered company-issued security key",
+            }));
+            return Err(InternalError::from_response("Attested security key required", response).into());
+        }
+    }
 
-
-    let user_repo = UserRepo{client: &webauthn_users.lock().await.client};
-    let user_id_result = user_repo.find_unique_id_by_username(&req.username).await;
-    let user_id = match user_id_result {
-        Ok(Some(id)) => id,
-        Ok(None) => {
-            println!("User not found");
-            return Err(actix_web::error::ErrorUnauthorized("User not found"))
-        },
-        Err(_) => {
-            println!("Error fetching user");
-            return Err(actix_web::error::ErrorInternalServerError("Error fetching user"))
+    let option_ids = selected_options(&**polls, &poll, &req.selection).await?;
+
+    // On confirmed polls the assertion must sign H(poll, options, nonce) for exactly
+    // these options, and is stored with the vote as evidence
+    let evidence = if poll.require_passkey_vote {
+        let assertion = req.assertion.as_ref().ok_or_else(|| {
+            let response = HttpResponse::Unauthorized().json(json!({ "error": "vote_confirmation_required" }));
+            InternalError::from_response("Vote confirmation required", response)
+        })?;
+        let (state_poll_id, state_option_ids, nonce, state_user_id, auth_state): (i32, Vec<i32>, String, Uuid, PasskeyAuthentication) = session
+            .get(VOTE_CONFIRM_STATE)?
+            .ok_or_else(|| actix_web::error::ErrorBadRequest("No vote confirmation in progress"))?;
+        if state_poll_id != *poll_id || state_option_ids != option_ids || state_user_id != user_id {
+            return Err(actix_web::error::ErrorBadRequest("Vote confirmation does not match this vote"));
         }
+        session.remove(VOTE_CONFIRM_STATE);
+        complete_passkey_assertion(&**users, &webauthn, &user_id, assertion, &auth_state).await?;
+        Some(json!({
+            "nonce": nonce,
+            "challenge": URL_SAFE_NO_PAD.encode(vote_challenge(*poll_id, &option_ids, &nonce)),
+            "credential": assertion,
+        }))
+    } else {
+        None
     };
 
-    let repo = PollRepo {client : &user_repo.client};
-
-    let option_id_result = repo.get_option_id_by_text_and_poll_id(req.option_text.clone(), *poll_id).await;
-    let option_id = match option_id_result {
-        Ok(Some(id)) => id,
-        Ok(None) => {
-            println!("Option not found");
-            return Err(actix_web::error::ErrorNotFound("Option not found"))
-        },
-        Err(_) => {
-            println!("Error fetching option");
-            return Err(actix_web::error::ErrorInternalServerError("Error fetching option"))
-        },
+    // The counts and the vote record are written together or not at all
+    let voted_at = Utc::now().to_string();
+    let outcome = match polls.record_vote(user_id, *poll_id, &option_ids, voted_at, evidence.as_ref(), poll.allow_vote_change).await {
+        Ok(outcome) => outcome,
+        Err(RepoError::AlreadyVoted) => {
+            let response = HttpResponse::Conflict().json(json!({
+                "error": "already_voted",
+                "message": "You have already voted on this poll",
+            }));
+            return Err(InternalError::from_response("Already voted", response).into());
+        }
+        Err(RepoError::PollNotOpen(state)) => return Err(poll_not_open(state)),
+        Err(e) => {
+            println!("Error recording vote: {:?}", e);
+            return Err(actix_web::error::ErrorInternalServerError("Error recording vote"));
+        }
     };
-
-    if let Err(_) = repo.increment_vote_count(option_id).await {
-        println!("Error incrementing vote_count");
-        return Err(actix_web::error::ErrorInternalServerError("Error updating/incrementing vote count"));
+    match outcome {
+        VoteOutcome::Recorded(tally) => {
+            broadcast_tally(&chat, *poll_id, &tally).await;
+            Ok(HttpResponse::Ok().json("Vote recorded successfully"))
+        }
+        VoteOutcome::Changed(tally) => {
+            broadcast_tally(&chat, *poll_id, &tally).await;
+            Ok(HttpResponse::Ok().json("Vote changed successfully"))
+        }
+        VoteOutcome::Unchanged => Ok(HttpResponse::Ok().json("Vote unchanged")),
     }
+}
 
-    let voted_at = Utc::now().to_string();
-    if let Err(_) = repo.insert_vote_details(user_id, *poll_id, option_id,voted_at ).await {
-        return Err(actix_web::error::ErrorInternalServerError("Error inserting vote record"));
+// Tell the poll's watchers the new count of each option a vote changed, one
+// message per option, along with the poll's distinct voters
+async fn broadcast_tally(chat: &Chat, poll_id: i32, tally: &VoteTally) {
+    for option in &tally.options {
+        let updated_message = json!({
+            "poll_id": poll_id,
+            "option_text": option.option_text,
+            "vote_count": option.votes,
+            "voters": tally.voters,
+        }).to_string();
+        println!("updated_message : {:?}",updated_message);
+        chat.send(updated_message).await;
     }
+}
 
-    let updated_vote_count = repo.get_vote_count(option_id).await.unwrap_or(0);
-    let updated_message = format!("{{\"poll_id\": {}, \"option_text\": \"{}\", \"vote_count\": {}}}",poll_id, &req.option_text, updated_vote_count);
-    println!("updated_message : {:?}",updated_message);
-    chat.send(updated_message).await;
-    println!("msg sent ig");
-    Ok(HttpResponse::Ok().json("Vote recorded successfully"))
+// Withdraw the session user's vote while the poll is open
+pub async fn retract_vote(
+    user: AuthenticatedUser,
+    polls: Data<dyn PollStore>,
+    poll_id: web::Path<i32>,
+    chat: web::Data<Chat>,
+) -> Result<HttpResponse, Error> {
+    user.require_scope(Scope::Vote)?;
+    let poll = visible_poll(&**polls, *poll_id, Some(user.user_id)).await?;
+    require_open(&poll)?;
+    match polls.retract_vote(user.user_id, *poll_id).await {
+        Ok(Some(tally)) => {
+            broadcast_tally(&chat, *poll_id, &tally).await;
+            Ok(HttpResponse::Ok().json("Vote retracted"))
+        }
+        Ok(None) => Err(actix_web::error::ErrorNotFound("No vote to retract")),
+        Err(RepoError::PollNotOpen(state)) => Err(poll_not_open(state)),
+        Err(e) => Err(actix_web::error::ErrorInternalServerError(format!("Error retracting vote: {:?}", e))),
+    }
 }
 
 pub async fn get_poll_details(
-    webauthn_users: Data<Mutex<UserData>>,
+    polls: Data<dyn PollStore>,
+    user: Option<AuthenticatedUser>,
     poll_id: web::Path<i32>
 ) -> Result<HttpResponse,Error> {
-    let repo = PollRepo { client: &webauthn_users.lock().await.client };
-    let poll_details = match repo.get_poll_by_id(*poll_id).await {
-        Ok(Some(poll)) => poll,
-        Ok(None) => return Err(actix_web::error::ErrorNotFound("Poll not found")),
-        Err(_) => return Err(actix_web::error::ErrorInternalServerError("Error fetching poll details")),
-    };
+    if let Some(user) = &user {
+        user.require_scope(Scope::ReadPolls)?;
+    }
+    let poll_details = visible_poll(&**polls, *poll_id, user.as_ref().map(|u| u.user_id)).await?;
 
-    let poll_options = match repo.get_poll_options_with_votes(*poll_id).await {
+    let poll_options = match polls.get_poll_options_with_votes(*poll_id).await {
         Ok(options) => options,
         Err(_) => return Err(actix_web::error::ErrorInternalServerError("Error fetching poll options")),
     };
@@ -185,8 +525,17 @@ pub async fn get_poll_details(
         id: poll_details.id,
         title: poll_details.title,
         creator_id: poll_details.creator_id,
-        closed : poll_details.closed,
+        state: poll_details.state,
+        require_passkey_vote: poll_details.require_passkey_vote,
+        allowed_aaguids: poll_details.allowed_aaguids,
+        allowed_attestation_cas: poll_details.allowed_attestation_cas,
+        tags: poll_details.tags,
         created_at:poll_details.created_at,
+        closes_at: poll_details.closes_at,
+        allow_vote_change: poll_details.allow_vote_change,
+        min_selections: poll_details.min_selections,
+        max_selections: poll_details.max_selections,
+        voters: poll_details.voters,
         options: poll_options.into_iter()
             .map(|opt| PollOptions {
                 option_text: opt.option_text,
@@ -198,61 +547,183 @@ pub async fn get_poll_details(
     Ok(HttpResponse::Ok().json(response))
 }
 
-pub async fn close_poll(
-    webauthn_users: Data<Mutex<UserData>>,
-    session:Session,
-    poll_id: web::Path<i32>,
-    chat: web::Data<Chat>
-) -> Result<HttpResponse ,Error> {
-    let user_id: Uuid = match session.get("user_unique_id").expect("Session get error") {
-        Some(id) => id, 
-        None => {
-            return Err(actix_web::error::ErrorUnauthorized("User not authenticated"));
-        } 
-    };
-
+// Why a poll changes state, passed on to everybody watching it; the body is optional
+#[derive(Deserialize)]
+pub struct TransitionRequest {
+    reason: Option<String>,
+}
 
-    let repo = PollRepo { client: &webauthn_users.lock().await.client };
+// Move a poll through `transition` on behalf of its creator, and broadcast the change
+// with its reason and actor. Holders of `CloseAnyPoll` may also close other users' polls.
+async fn change_poll_state(
+    polls: Data<dyn PollStore>,
+    permissions: UserPermissions,
+    recent: RecentAuth,
+    poll_id: i32,
+    req: Option<web::Json<TransitionRequest>>,
+    chat: web::Data<Chat>,
+    transition: PollTransition,
+) -> Result<HttpResponse, Error> {
+    recent.user.require_scope(Scope::ManagePolls)?;
+    let user_id = recent.user.user_id;
 
-    match repo.is_poll_creator(user_id, *poll_id).await {
+    match polls.is_poll_creator(user_id, poll_id).await {
         Ok(true) => {}, // User is the creator, proceed
+        Ok(false) if transition == PollTransition::Close && permissions.has(Permission::CloseAnyPoll) => {},
         Ok(false) => return Ok(HttpResponse::Forbidden().body("You are not the creator of this poll")),
         Err(_) => return Ok(HttpResponse::InternalServerError().body("Error verifying poll creator")),
     }
 
-    if repo.close_poll(*poll_id).await.is_err(){
-        return Ok(HttpResponse::InternalServerError().body("Error closing the poll"));
-    }
+    let from = match polls.transition_poll(poll_id, transition).await {
+        Ok(Some(from)) => from,
+        Ok(None) => return Err(actix_web::error::ErrorNotFound("Poll not found")),
+        Err(e @ RepoError::InvalidTransition { from, .. }) => return Err(wrong_state("invalid_transition", from, e.to_string())),
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(format!("Error changing the poll state: {:?}", e))),
+    };
 
-    let close_message = format!("{{\"poll_id\": {}, \"closed\": {}}}",poll_id, true);
-    println!("updated_message : {:?}",close_message);
+    let reason = req.and_then(|req| req.into_inner().reason);
+    let state_message = json!({
+        "poll_id": poll_id,
+        "transition": transition,
+        "from": from,
+        "to": transition.to(),
+        "reason": reason,
+        "actor": user_id,
+    }).to_string();
+    println!("updated_message : {:?}",state_message);
+    chat.send(state_message).await;
+
+    Ok(HttpResponse::Ok().json(json!({ "poll_id": poll_id, "state": transition.to() })))
+}
 
-    chat.send(close_message).await;
+// Draft to open
+pub async fn publish_poll(
+    polls: Data<dyn PollStore>,
+    permissions: UserPermissions,
+    recent: RecentAuth,
+    poll_id: web::Path<i32>,
+    req: Option<web::Json<TransitionRequest>>,
+    chat: web::Data<Chat>,
+) -> Result<HttpResponse, Error> {
+    change_poll_state(polls, permissions, recent, *poll_id, req, chat, PollTransition::Publish).await
+}
 
-    Ok(HttpResponse::Ok().body("Poll closed successfully"))
+// Open to closed
+pub async fn close_poll(
+    polls: Data<dyn PollStore>,
+    permissions: UserPermissions,
+    recent: RecentAuth,
+    poll_id: web::Path<i32>,
+    req: Option<web::Json<TransitionRequest>>,
+    chat: web::Data<Chat>,
+) -> Result<HttpResponse, Error> {
+    change_poll_state(polls, permissions, recent, *poll_id, req, chat, PollTransition::Close).await
+}
+
+// Closed to open
+pub async fn reopen_poll(
+    polls: Data<dyn PollStore>,
+    permissions: UserPermissions,
+    recent: RecentAuth,
+    poll_id: web::Path<i32>,
+    req: Option<web::Json<TransitionRequest>>,
+    chat: web::Data<Chat>,
+) -> Result<HttpResponse, Error> {
+    change_poll_state(polls, permissions, recent, *poll_id, req, chat, PollTransition::Reopen).await
+}
+
+// Closed to archived, for good
+pub async fn archive_poll(
+    polls: Data<dyn PollStore>,
+    permissions: UserPermissions,
+    recent: RecentAuth,
+    poll_id: web::Path<i32>,
+    req: Option<web::Json<TransitionRequest>>,
+    chat: web::Data<Chat>,
+) -> Result<HttpResponse, Error> {
+    change_poll_state(polls, permissions, recent, *poll_id, req, chat, PollTransition::Archive).await
 }
 
 pub async fn reset_poll_votes(
     poll_id: web::Path<i32>,
-    session:Session,
-    webauthn_users: web::Data<Mutex<UserData>>,
+    permissions: UserPermissions,
+    recent: RecentAuth,
+    polls: web::Data<dyn PollStore>,
 ) -> Result<HttpResponse, Error> {
-    let repo = PollRepo { client: &webauthn_users.lock().await.client };
-    let user_id: Uuid = match session.get("user_unique_id").expect("Session get error") {
-        Some(id) => id, 
-        None => {
-            return Err(actix_web::error::ErrorUnauthorized("User not authenticated"));
-        } 
-    };
-    match repo.is_poll_creator(user_id, *poll_id).await {
-        Ok(true) => {
-            // Reset votes
-            match repo.reset_votes(*poll_id).await {
+    recent.user.require_scope(Scope::ManagePolls)?;
+    let user_id = recent.user.user_id;
+    let can_reset_any = permissions.has(Permission::ResetAnyPoll);
+    match polls.is_poll_creator(user_id, *poll_id).await {
+        Ok(is_creator) if is_creator || can_reset_any => {
+            visible_poll(&**polls, *poll_id, Some(user_id)).await?;
+            // The store checks the state as it resets, so a concurrent archive cannot slip in between
+            match polls.reset_votes(*poll_id).await {
                 Ok(_) => Ok(HttpResponse::Ok().json("Poll votes reset successfully")),
+                Err(e @ RepoError::NotResettable(state)) => Err(wrong_state("invalid_poll_state", state, e.to_string())),
                 Err(e) => Err(actix_web::error::ErrorInternalServerError(format!("Error resetting poll votes: {:?}", e))),
             }
         },
-        Ok(false) => Err(actix_web::error::ErrorUnauthorized("You are not the creator of this poll")),
+        Ok(_) => Err(actix_web::error::ErrorUnauthorized("You are not the creator of this poll")),
         Err(e) => Err(actix_web::error::ErrorInternalServerError(format!("Error: {:?}", e))),
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db_operations_repo::poll_repo::Poll;
+
+    fn params(query: &str) -> PollQueryParams {
+        web::Query::<PollQueryParams>::from_query(query).unwrap().into_inner()
+    }
+
+    fn state(query: &str) -> Option<PollState> {
+        params(query).filter(None).unwrap().state
+    }
+
+    #[test]
+    fn reads_live_and_closed_as_a_state() {
+        assert_eq!(state(""), None);
+        assert_eq!(state("live=true"), Some(PollState::Open));
+        assert_eq!(state("live=false"), Some(PollState::Closed));
+        assert_eq!(state("closed=true"), Some(PollState::Closed));
+        assert_eq!(state("closed=false"), Some(PollState::Open));
+        assert_eq!(state("live=false&closed=true&status=closed"), Some(PollState::Closed));
+        assert_eq!(state("status=archived"), Some(PollState::Archived));
+    }
+
+    #[test]
+    fn refuses_conflicting_states() {
+        for query in ["live=true&closed=true", "live=false&closed=false", "closed=false&status=draft"] {
+            assert!(params(query).filter(None).is_err(), "{}", query);
+        }
+    }
+
+    #[test]
+    fn splits_tags_and_trims_the_search() {
+        let filter = params("tags=food,%20team,,&q=%20%20").filter(None).unwrap();
+        assert_eq!(filter.tags, ["food", "team"]);
+        assert_eq!(filter.title_search, None);
+    }
+
+    #[test]
+    fn checks_the_page_size_and_cursor() {
+        assert_eq!(params("").page().unwrap().limit, Some(DEFAULT_PAGE_SIZE));
+        assert!(params("limit=0").page().is_err());
+        assert!(params("limit=101").page().is_err());
+        assert!(params("cursor=garbage").page().is_err());
+
+        let poll = Poll {
+            id: 7,
+            title: "Lunch".to_string(),
+            creator_id: Uuid::nil(),
+            state: PollState::Open,
+            tags: Vec::new(),
+            created_at: "2024-01-01 10:00:00".to_string(),
+            closes_at: None,
+            options: Vec::new(),
+        };
+        let cursor = PollCursor::after(PollSort::Oldest, &poll).encode();
+        assert!(params(&format!("sort=oldest&cursor={}", cursor)).page().unwrap().after.is_some());
+        assert!(params(&format!("sort=newest&cursor={}", cursor)).page().is_err());
+    }
+}
@@ -0,0 +1,183 @@
+use std::env;
+
+use actix_session::Session;
+use actix_web::{
+    http::header,
+    web::{self, Data, Json},
+    Error, HttpRequest, HttpResponse,
+};
+use chrono::{Duration, Utc};
+use serde_json::json;
+use uuid::Uuid;
+use webauthn_rs::prelude::*;
+
+use crate::{
+    db_operations_repo::{qr_login_repo::QrLoginRepo, store::UserStore},
+    handlers::handlers::{begin_passkey_assertion, complete_passkey_assertion},
+    identity::{AuthMethod, AuthenticatedUser, LAST_AUTH_AT},
+    startup::UserData,
+    tokens::{generate_opaque_token, hash_token},
+    web_socket_handlers::start_connection::Chat,
+};
+
+const QR_LOGIN_TTL_SECS: i64 = 2 * 60;
+// (request id, browser secret) of the login this desktop session is waiting for
+const QR_LOGIN: &str = "qr_login";
+// (request id, user id, assertion state) of the phone confirming a request
+const QR_CONFIRM_STATE: &str = "qr_confirm_state";
+
+fn require_browser_session(user: &AuthenticatedUser) -> Result<(), Error> {
+    if user.method != AuthMethod::Session {
+        return Err(actix_web::error::ErrorForbidden("QR logins are confirmed from a passkey login session"));
+    }
+    Ok(())
+}
+
+// Desktop: create a login request to show as a QR code
+pub async fn start_qr_login(
+    req: HttpRequest,
+    session: Session,
+    webauthn_users: Data<UserData>,
+) -> Result<HttpResponse, Error> {
+    let request_id = Uuid::new_v4();
+    let browser_secret = generate_opaque_token();
+    let expires_at = Utc::now() + Duration::seconds(QR_LOGIN_TTL_SECS);
+    let user_agent = req.headers().get(header::USER_AGENT).and_then(|v| v.to_str().ok());
+
+    let repo = QrLoginRepo { client: &*webauthn_users.client().await? };
+    repo.insert(&request_id, &hash_token(&browser_secret), user_agent, &expires_at)
+        .await
+        .map_err(|_| actix_web::error::ErrorInternalServerError("Error creating login request"))?;
+    session.insert(QR_LOGIN, (request_id, browser_secret))?;
+
+    let base = env::var("QR_LOGIN_URL").unwrap_or_else(|_| "http://localhost:3000/qr-login".to_string());
+    let mut qr_url = Url::parse(&base).map_err(|_| actix_web::error::ErrorInternalServerError("Invalid QR_LOGIN_URL"))?;
+    qr_url.query_pairs_mut().append_pair("request", &request_id.to_string());
+
+    Ok(HttpResponse::Created().json(json!({
+        "request_id": request_id,
+        "qr_url": qr_url.as_str(),
+        "expires_in": QR_LOGIN_TTL_SECS,
+    })))
+}
+
+// Phone: show what is about to be approved
+pub async fn get_qr_login(
+    user: AuthenticatedUser,
+    request_id: web::Path<Uuid>,
+    webauthn_users: Data<UserData>,
+) -> Result<HttpResponse, Error> {
+    require_browser_session(&user)?;
+    let repo = QrLoginRepo { client: &*webauthn_users.client().await? };
+    let request = repo
+        .find(&request_id)
+        .await
+        .map_err(|_| actix_web::error::ErrorInternalServerError("Error fetching login request"))?
+        .ok_or_else(|| actix_web::error::ErrorNotFound("Login request not found"))?;
+    Ok(HttpResponse::Ok().json(json!({
+        "request_id": *request_id,
+        "status": request.status,
+        "requester_user_agent": request.requester_user_agent,
+        "created_at": request.created_at,
+        "expires_at": request.expires_at,
+    })))
+}
+
+// Phone: begin the fresh passkey assertion that confirms the request
+pub async fn start_qr_confirmation(
+    user: AuthenticatedUser,
+    request_id: web::Path<Uuid>,
+    session: Session,
+    webauthn_users: Data<UserData>,
+    users: Data<dyn UserStore>,
+    webauthn: Data<Webauthn>,
+) -> Result<Json<RequestChallengeResponse>, Error> {
+    require_browser_session(&user)?;
+    let pending = QrLoginRepo { client: &*webauthn_users.client().await? }
+        .find(&request_id)
+        .await
+        .map_err(|_| actix_web::error::ErrorInternalServerError("Error fetching login request"))?
+        .is_some_and(|r| r.status == "pending" && r.expires_at > Utc::now());
+    if !pending {
+        return Err(actix_web::error::ErrorNotFound("Login request not found or expired"));
+    }
+
+    let (rcr, auth_state) = begin_passkey_assertion(&**users, &webauthn, &user.user_id).await?;
+    session.insert(QR_CONFIRM_STATE, (*request_id, user.user_id, &auth_state))?;
+    Ok(Json(rcr))
+}
+
+// Phone: verify the assertion and approve the request
+#[allow(clippy::too_many_arguments)]
+pub async fn finish_qr_confirmation(
+    user: AuthenticatedUser,
+    request_id: web::Path<Uuid>,
+    auth: Json<PublicKeyCredential>,
+    session: Session,
+    webauthn_users: Data<UserData>,
+    users: Data<dyn UserStore>,
+    webauthn: Data<Webauthn>,
+    chat: Data<Chat>,
+) -> Result<HttpResponse, Error> {
+    require_browser_session(&user)?;
+    let (state_request_id, user_id, auth_state): (Uuid, Uuid, PasskeyAuthentication) = session
+        .get(QR_CONFIRM_STATE)?
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("No confirmation in progress"))?;
+    if state_request_id != *request_id || user_id != user.user_id {
+        return Err(actix_web::error::ErrorBadRequest("Confirmation does not match this request"));
+    }
+
+    complete_passkey_assertion(&**users, &webauthn, &user_id, &auth, &auth_state).await?;
+    session.remove(QR_CONFIRM_STATE);
+
+    let approved = QrLoginRepo { client: &*webauthn_users.client().await? }
+        .approve(&request_id, &user_id)
+        .await
+        .map_err(|_| actix_web::error::ErrorInternalServerError("Error approving login request"))?;
+    if !approved {
+        return Err(actix_web::error::ErrorNotFound("Login request not found or expired"));
+    }
+
+    // Lets a waiting desktop know it can claim its login now instead of on its next poll
+    let message = json!({ "type": "qr_login", "request_id": *request_id, "status": "approved" });
+    chat.send(message.to_string()).await;
+    Ok(HttpResponse::Ok().json("Login approved"))
+}
+
+// Desktop: poll the request and, once approved, promote this session to a login
+pub async fn poll_qr_login(
+    session: Session,
+    webauthn_users: Data<UserData>,
+) -> Result<HttpResponse, Error> {
+    let (request_id, browser_secret): (Uuid, String) = session
+        .get(QR_LOGIN)?
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("No QR login in progress"))?;
+
+    let repo = QrLoginRepo { client: &*webauthn_users.client().await? };
+    let claimed = repo
+        .claim(&request_id, &hash_token(&browser_secret))
+        .await
+        .map_err(|_| actix_web::error::ErrorInternalServerError("Error reading login request"))?;
+    let user_id = match claimed {
+        Some(claimed) => claimed,
+        None => {
+            let status = match repo.find(&request_id).await {
+                Ok(Some(r)) if r.expires_at <= Utc::now() => "expired".to_string(),
+                Ok(Some(r)) => r.status,
+                _ => "expired".to_string(),
+            };
+            if status != "pending" {
+                session.remove(QR_LOGIN);
+            }
+            return Ok(HttpResponse::Ok().json(json!({ "status": status })));
+        }
+    };
+
+    session.remove(QR_LOGIN);
+    session.renew();
+    session.insert("user_unique_id", user_id)?;
+    // The passkey assertion happened on the phone, not here: sensitive operations
+    // on this browser still go through `/reauth`
+    session.remove(LAST_AUTH_AT);
+    Ok(HttpResponse::Ok().json(json!({ "status": "logged_in", "user_id": user_id })))
+}
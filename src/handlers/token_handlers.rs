@@ -0,0 +1,194 @@
+use actix_web::{web::{self, Data}, Error, HttpResponse};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    db_operations_repo::{pat_repo::PatRepo, token_repo::{RefreshOutcome, TokenRepo}},
+    identity::{AuthMethod, AuthenticatedUser, RecentAuth, Scope},
+    startup::UserData,
+    tokens::{generate_opaque_token, hash_token, TokenSigner, API_AUDIENCE, PAT_PREFIX},
+};
+
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    refresh_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct CreatePatRequest {
+    name: String,
+    scopes: Vec<String>,
+    expires_in_days: Option<i64>,
+}
+
+const MAX_PAT_LIFETIME_DAYS: i64 = 365;
+
+#[derive(Serialize)]
+pub struct TokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: i64,
+    refresh_token: String,
+    refresh_expires_in: i64,
+}
+
+// Issue an access token plus the next refresh token of a family; a scope restricts
+// both and is carried over every rotation
+pub(crate) async fn issue_token_pair(
+    repo: &TokenRepo<'_>,
+    signer: &TokenSigner,
+    family_id: &Uuid,
+    user_id: Uuid,
+    auth_time: DateTime<Utc>,
+    scope: Option<Vec<String>>,
+) -> Result<TokenResponse, Error> {
+    let refresh_token = generate_opaque_token();
+    let expires_at = Utc::now() + Duration::seconds(signer.refresh_ttl_secs);
+    repo.insert_refresh_token(family_id, &user_id, &hash_token(&refresh_token), &auth_time, scope.as_deref(), &expires_at)
+        .await
+        .map_err(|_| actix_web::error::ErrorInternalServerError("Error storing refresh token"))?;
+
+    Ok(TokenResponse {
+        access_token: signer.issue_access_token(user_id, API_AUDIENCE, auth_time.timestamp(), scope),
+        token_type: "Bearer",
+        expires_in: signer.access_ttl_secs,
+        refresh_token,
+        refresh_expires_in: signer.refresh_ttl_secs,
+    })
+}
+
+// Exchange a freshly logged-in browser session for a new token family
+pub async fn issue_tokens(
+    recent: RecentAuth,
+    webauthn_users: Data<UserData>,
+    signer: Data<TokenSigner>,
+) -> Result<HttpResponse, Error> {
+    if recent.user.method != AuthMethod::Session {
+        return Err(actix_web::error::ErrorBadRequest("Tokens are issued from a passkey login session"));
+    }
+    let auth_time = recent
+        .user
+        .auth_time
+        .and_then(|t| DateTime::from_timestamp(t, 0))
+        .unwrap_or_else(Utc::now);
+
+    let repo = TokenRepo { client: &*webauthn_users.client().await? };
+    let tokens = issue_token_pair(&repo, &signer, &Uuid::new_v4(), recent.user.user_id, auth_time, None).await?;
+    Ok(HttpResponse::Ok().json(tokens))
+}
+
+pub async fn refresh_tokens(
+    webauthn_users: Data<UserData>,
+    signer: Data<TokenSigner>,
+    req: web::Json<RefreshRequest>,
+) -> Result<HttpResponse, Error> {
+    let repo = TokenRepo { client: &*webauthn_users.client().await? };
+    let outcome = repo
+        .consume_refresh_token(&hash_token(&req.refresh_token))
+        .await
+        .map_err(|_| actix_web::error::ErrorInternalServerError("Error reading refresh token"))?;
+
+    match outcome {
+        RefreshOutcome::Rotated { family_id, user_id, auth_time, scope } => {
+            let tokens = issue_token_pair(&repo, &signer, &family_id, user_id, auth_time, scope).await?;
+            Ok(HttpResponse::Ok().json(tokens))
+        }
+        RefreshOutcome::Reused { family_id } => {
+            log::warn!("Refresh token reuse detected, revoking family {:?}", family_id);
+            repo.revoke_family(&family_id)
+                .await
+                .map_err(|_| actix_web::error::ErrorInternalServerError("Error revoking token family"))?;
+            Err(actix_web::error::ErrorUnauthorized("Refresh token reuse detected"))
+        }
+        RefreshOutcome::Invalid => Err(actix_web::error::ErrorUnauthorized("Invalid refresh token")),
+    }
+}
+
+// Log a client out by revoking the whole family of the given refresh token
+pub async fn revoke_tokens(
+    webauthn_users: Data<UserData>,
+    req: web::Json<RefreshRequest>,
+) -> Result<HttpResponse, Error> {
+    let repo = TokenRepo { client: &*webauthn_users.client().await? };
+    if let Ok(Some(family_id)) = repo.family_of(&hash_token(&req.refresh_token)).await {
+        repo.revoke_family(&family_id)
+            .await
+            .map_err(|_| actix_web::error::ErrorInternalServerError("Error revoking token family"))?;
+    }
+    Ok(HttpResponse::Ok().json("Tokens revoked"))
+}
+
+// Personal access tokens can only be managed from a browser session that just
+// completed a passkey assertion
+fn require_fresh_session(recent: &RecentAuth) -> Result<(), Error> {
+    if recent.user.method != AuthMethod::Session {
+        return Err(actix_web::error::ErrorForbidden("Personal access tokens are managed from a passkey login session"));
+    }
+    Ok(())
+}
+
+pub async fn create_personal_token(
+    recent: RecentAuth,
+    webauthn_users: Data<UserData>,
+    req: web::Json<CreatePatRequest>,
+) -> Result<HttpResponse, Error> {
+    require_fresh_session(&recent)?;
+    if req.name.trim().is_empty() {
+        return Err(actix_web::error::ErrorBadRequest("Token name is required"));
+    }
+    if req.scopes.is_empty() {
+        return Err(actix_web::error::ErrorBadRequest("At least one scope is required"));
+    }
+    if let Some(unknown) = req.scopes.iter().find(|s| s.parse::<Scope>().is_err()) {
+        return Err(actix_web::error::ErrorBadRequest(format!("Unknown scope: {}", unknown)));
+    }
+    let days = req.expires_in_days.unwrap_or(30);
+    if !(1..=MAX_PAT_LIFETIME_DAYS).contains(&days) {
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "expires_in_days must be between 1 and {}", MAX_PAT_LIFETIME_DAYS
+        )));
+    }
+
+    let token = format!("{}{}", PAT_PREFIX, generate_opaque_token());
+    let expires_at = Utc::now() + Duration::days(days);
+    let repo = PatRepo { client: &*webauthn_users.client().await? };
+    let created = repo
+        .insert_token(&recent.user.user_id, req.name.trim(), &hash_token(&token), &req.scopes, &expires_at)
+        .await
+        .map_err(|_| actix_web::error::ErrorInternalServerError("Error creating access token"))?;
+
+    // The token itself is only ever shown in this response
+    Ok(HttpResponse::Created().json(serde_json::json!({
+        "token": token,
+        "details": created,
+    })))
+}
+
+pub async fn list_personal_tokens(
+    user: AuthenticatedUser,
+    webauthn_users: Data<UserData>,
+) -> Result<HttpResponse, Error> {
+    if user.scopes.is_some() {
+        return Err(actix_web::error::ErrorForbidden("Scoped tokens cannot list personal access tokens"));
+    }
+    let repo = PatRepo { client: &*webauthn_users.client().await? };
+    match repo.list_for_user(&user.user_id).await {
+        Ok(tokens) => Ok(HttpResponse::Ok().json(tokens)),
+        Err(_) => Err(actix_web::error::ErrorInternalServerError("Error fetching access tokens")),
+    }
+}
+
+pub async fn revoke_personal_token(
+    recent: RecentAuth,
+    webauthn_users: Data<UserData>,
+    token_id: web::Path<Uuid>,
+) -> Result<HttpResponse, Error> {
+    require_fresh_session(&recent)?;
+    let repo = PatRepo { client: &*webauthn_users.client().await? };
+    match repo.revoke(&recent.user.user_id, &token_id).await {
+        Ok(true) => Ok(HttpResponse::Ok().json("Access token revoked")),
+        Ok(false) => Err(actix_web::error::ErrorNotFound("Access token not found")),
+        Err(_) => Err(actix_web::error::ErrorInternalServerError("Error revoking access token")),
+    }
+}
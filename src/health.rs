@@ -0,0 +1,48 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use actix_web::{dev::ServerHandle, web::Data};
+
+/**
+Whether the server is still taking traffic. Once a shutdown signal arrives `/readyz`
+answers `503`, and the server keeps serving for `SHUTDOWN_DRAIN_SECS` (default 5) so
+that load balancers notice and stop routing to it before connections are closed.
+*/
+#[derive(Default)]
+pub(crate) struct Readiness {
+    shutting_down: AtomicBool,
+}
+
+impl Readiness {
+    pub(crate) fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+}
+
+// Wait for SIGTERM or Ctrl-C, then drain and stop the server gracefully
+pub(crate) async fn drain_on_shutdown(server: ServerHandle, readiness: Data<Readiness>) {
+    shutdown_signal().await;
+    readiness.shutting_down.store(true, Ordering::SeqCst);
+    let drain = std::env::var("SHUTDOWN_DRAIN_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(5);
+    log::info!("Shutting down; draining traffic for {} seconds", drain);
+    tokio::time::sleep(Duration::from_secs(drain)).await;
+    server.stop(true).await;
+}
+
+#[cfg(unix)]
+async fn shutdown_signal() {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
@@ -0,0 +1,258 @@
+use std::env;
+use std::str::FromStr;
+
+use actix_session::SessionExt;
+use actix_web::{
+    dev::Payload,
+    error::{ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized, InternalError},
+    http::header,
+    web::Data,
+    Error, FromRequest, HttpRequest, HttpResponse,
+};
+use chrono::Utc;
+use futures::future::LocalBoxFuture;
+use once_cell::sync::Lazy;
+use uuid::Uuid;
+use webauthn_rs::prelude::Webauthn;
+
+use crate::{
+    db_operations_repo::{pat_repo::PatRepo, store::UserStore},
+    handlers::handlers::begin_passkey_assertion,
+    startup::UserData,
+    tokens::{hash_token, TokenSigner, API_AUDIENCE, PAT_PREFIX},
+};
+
+// Unix timestamp of the last passkey assertion made on this session
+pub(crate) const LAST_AUTH_AT: &str = "last_auth_at";
+// Pending step-up ceremony, completed through `/reauth/finish`
+pub(crate) const REAUTH_STATE: &str = "reauth_state";
+
+static REAUTH_WINDOW_SECS: Lazy<i64> = Lazy::new(|| {
+    env::var("REAUTH_WINDOW_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(300)
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AuthMethod {
+    Session,
+    Bearer,
+    PersonalAccessToken,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Scope {
+    ReadPolls,
+    WritePolls,
+    Vote,
+    ManagePolls,
+    // Never granted to a token, so only unrestricted credentials (a session or an
+    // unscoped access token) pass `require_scope(Scope::Admin)`
+    Admin,
+}
+
+impl Scope {
+    // The scopes a token may be granted
+    pub(crate) const GRANTABLE: [Scope; 4] = [Scope::ReadPolls, Scope::WritePolls, Scope::Vote, Scope::ManagePolls];
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Scope::ReadPolls => "polls:read",
+            Scope::WritePolls => "polls:write",
+            Scope::Vote => "polls:vote",
+            Scope::ManagePolls => "polls:manage",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Scope::GRANTABLE.into_iter().find(|scope| scope.as_str() == s).ok_or(())
+    }
+}
+
+/**
+The user behind a request. An `Authorization: Bearer` credential takes precedence:
+either a personal access token (`pat_...`) or a signed access token. Otherwise the
+`user_unique_id` stored in the session by `finish_authentication` is used. Extraction
+fails with 401 when nothing identifies a user, so handlers never have to trust a
+username sent in the request body.
+*/
+#[derive(Debug, Clone)]
+pub(crate) struct AuthenticatedUser {
+    pub(crate) user_id: Uuid,
+    pub(crate) method: AuthMethod,
+    // Unix timestamp of the passkey assertion backing this identity
+    pub(crate) auth_time: Option<i64>,
+    // Scopes granted to a personal access token or a restricted access token;
+    // `None` means unrestricted
+    pub(crate) scopes: Option<Vec<Scope>>,
+}
+
+impl AuthenticatedUser {
+    pub(crate) fn require_scope(&self, scope: Scope) -> Result<(), Error> {
+        match &self.scopes {
+            Some(scopes) if !scopes.contains(&scope) => {
+                Err(ErrorForbidden(format!("Token is missing scope {}", scope.as_str())))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// Scopes stored with a token; ones this build does not know are dropped rather
+// than widening the token
+fn parse_scopes(scopes: &[String]) -> Vec<Scope> {
+    scopes.iter().filter_map(|s| s.parse().ok()).collect()
+}
+
+pub(crate) fn bearer_token(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|v| v.trim().to_string())
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let token = bearer_token(req);
+        let session = req.get_session();
+        let signer = req.app_data::<Data<TokenSigner>>().cloned();
+        let users = req.app_data::<Data<UserData>>().cloned();
+        Box::pin(async move {
+            match token {
+                Some(token) if token.starts_with(PAT_PREFIX) => {
+                    let users = users.ok_or_else(|| ErrorInternalServerError("User store not configured"))?;
+                    let repo = PatRepo { client: &*users.client().await? };
+                    let (user_id, scopes) = repo
+                        .authenticate(&hash_token(&token))
+                        .await
+                        .map_err(|_| ErrorInternalServerError("Error checking access token"))?
+                        .ok_or_else(|| ErrorUnauthorized("Invalid or expired access token"))?;
+                    Ok(AuthenticatedUser {
+                        user_id,
+                        method: AuthMethod::PersonalAccessToken,
+                        auth_time: None,
+                        scopes: Some(parse_scopes(&scopes)),
+                    })
+                }
+                Some(token) => {
+                    let signer = signer.ok_or_else(|| ErrorInternalServerError("Token signing not configured"))?;
+                    let claims = signer
+                        .verify_access_token(&token, API_AUDIENCE)
+                        .map_err(|e| ErrorUnauthorized(e.to_string()))?;
+                    Ok(AuthenticatedUser {
+                        user_id: claims.sub,
+                        method: AuthMethod::Bearer,
+                        auth_time: Some(claims.auth_time),
+                        scopes: claims.scope.as_deref().map(parse_scopes),
+                    })
+                }
+                None => match session.get::<Uuid>("user_unique_id") {
+                    Ok(Some(user_id)) => Ok(AuthenticatedUser {
+                        user_id,
+                        method: AuthMethod::Session,
+                        auth_time: session.get::<i64>(LAST_AUTH_AT).ok().flatten(),
+                        scopes: None,
+                    }),
+                    _ => Err(ErrorUnauthorized("User not authenticated")),
+                },
+            }
+        })
+    }
+}
+
+/**
+An [AuthenticatedUser] who completed a passkey assertion within the last
+`REAUTH_WINDOW_SECS` (5 minutes by default). Otherwise extraction fails with 401 and
+`{"error": "reauth_required", "challenge": ...}`, where the challenge is to be answered
+at `/reauth/finish` before retrying the request. Bearer clients get no challenge and
+must obtain new tokens from a fresh login instead; personal access tokens never qualify.
+*/
+pub(crate) struct RecentAuth {
+    pub(crate) user: AuthenticatedUser,
+}
+
+impl FromRequest for RecentAuth {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let user = AuthenticatedUser::from_request(req, payload);
+        let session = req.get_session();
+        let users = req.app_data::<Data<dyn UserStore>>().cloned();
+        let webauthn = req.app_data::<Data<Webauthn>>().cloned();
+        Box::pin(async move {
+            let user = user.await?;
+            if Utc::now().timestamp() - user.auth_time.unwrap_or(0) <= *REAUTH_WINDOW_SECS {
+                return Ok(RecentAuth { user });
+            }
+            if user.method != AuthMethod::Session {
+                let response = HttpResponse::Unauthorized().json(serde_json::json!({
+                    "error": "reauth_required",
+                }));
+                return Err(InternalError::from_response("Re-authentication required", response).into());
+            }
+
+            let (users, webauthn) = users
+                .zip(webauthn)
+                .ok_or_else(|| ErrorInternalServerError("Authentication not configured"))?;
+            let (challenge, auth_state) = begin_passkey_assertion(&**users, &webauthn, &user.user_id).await?;
+            session.insert(REAUTH_STATE, (user.user_id, &auth_state))?;
+
+            let response = HttpResponse::Unauthorized().json(serde_json::json!({
+                "error": "reauth_required",
+                "challenge": challenge,
+            }));
+            Err(InternalError::from_response("Re-authentication required", response).into())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(scopes: Option<Vec<Scope>>) -> AuthenticatedUser {
+        AuthenticatedUser { user_id: Uuid::nil(), method: AuthMethod::PersonalAccessToken, auth_time: None, scopes }
+    }
+
+    #[test]
+    fn scopes_round_trip_through_their_names() {
+        for scope in Scope::GRANTABLE {
+            assert_eq!(scope.as_str().parse::<Scope>(), Ok(scope));
+        }
+        assert!(Scope::Admin.as_str().parse::<Scope>().is_err());
+        assert!("polls:admin".parse::<Scope>().is_err());
+        assert!("POLLS:READ".parse::<Scope>().is_err());
+        assert!("".parse::<Scope>().is_err());
+    }
+
+    #[test]
+    fn parse_scopes_drops_unknown_names() {
+        let stored = vec!["polls:read".to_string(), "users:manage".to_string(), "polls:vote".to_string()];
+        assert_eq!(parse_scopes(&stored), vec![Scope::ReadPolls, Scope::Vote]);
+        assert!(parse_scopes(&["openid".to_string()]).is_empty());
+    }
+
+    #[test]
+    fn require_scope_checks_restricted_tokens_only() {
+        assert!(user(None).require_scope(Scope::ManagePolls).is_ok());
+        let restricted = user(Some(vec![Scope::ReadPolls]));
+        assert!(restricted.require_scope(Scope::ReadPolls).is_ok());
+        assert!(restricted.require_scope(Scope::Vote).is_err());
+        // A token whose scopes were all unknown is allowed nothing
+        assert!(user(Some(Vec::new())).require_scope(Scope::ReadPolls).is_err());
+    }
+
+    #[test]
+    fn admin_scope_needs_an_unrestricted_credential() {
+        assert!(user(None).require_scope(Scope::Admin).is_ok());
+        assert!(user(Some(Scope::GRANTABLE.to_vec())).require_scope(Scope::Admin).is_err());
+    }
+}
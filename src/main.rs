@@ -1,33 +1,111 @@
 use actix_cors::Cors;
 use actix_session::SessionMiddleware;
 use actix_web::{cookie::{Key, SameSite}, http, middleware, web, App, HttpServer};
-use handlers::{handlers::{finish_authentication, register_finish, register_start, start_authentication}, polls_handlers::{close_poll, create_poll, get_all_polls_from_db, get_poll_details, manage_user_polls, reset_poll_votes, vote_on_poll}};
+use handlers::{health_handlers::{healthz, readyz}, admin_handlers::{assign_user_role, get_user_roles, register_oidc_client, revoke_user_role}, handlers::{finish_authentication, pow_challenge, reauth_finish, reauth_start, register_finish, register_start, security_key_register_finish, security_key_register_start, start_authentication}, device_handlers::{decide_device_request, device_authorization, get_device_request}, oidc_handlers::{authorize, jwks, openid_configuration, token, userinfo}, qr_login_handlers::{finish_qr_confirmation, get_qr_login, poll_qr_login, start_qr_confirmation, start_qr_login}, token_handlers::{create_personal_token, issue_tokens, list_personal_tokens, refresh_tokens, revoke_personal_token, revoke_tokens}, polls_handlers::{archive_poll, close_poll, create_poll, get_all_polls_from_db, get_poll_details, manage_user_polls, publish_poll, reopen_poll, reset_poll_votes, retract_vote, search_polls, start_vote_confirmation, vote_on_poll}};
+use attestation::AttestationTrust;
+use health::{drain_on_shutdown, Readiness};
 use log::info;
-use session::MemorySession;
-use startup::startup;
+use oidc::OidcProvider;
+use pow::ProofOfWork;
+use startup::{run_migrations, startup};
+use tokens::TokenSigner;
 use web_socket_handlers::{start_connection::Chat, start_connection::ws};
 
+mod attestation;
 mod db;
 mod db_operations_repo;
 mod startup;
+mod tokens;
 mod handlers;
+mod health;
+mod identity;
+mod oidc;
+mod pow;
+mod rbac;
 mod session;
 mod web_socket_handlers;
+#[cfg(test)]
+mod api_tests;
 
+// The API routes, shared by the server and the in-process tests
+pub(crate) fn routes(cfg: &mut web::ServiceConfig) {
+    cfg
+        .route("/healthz", web::get().to(healthz))
+        .route("/readyz", web::get().to(readyz))
+        .route("/pow/challenge", web::get().to(pow_challenge))
+        .route("/register/start/{username}", web::post().to(register_start))
+        .route("/register/finish", web::post().to(register_finish))
+        .route("/register/security-key/start", web::post().to(security_key_register_start))
+        .route("/register/security-key/finish", web::post().to(security_key_register_finish))
+        .route("/login/start/{username}", web::post().to(start_authentication))
+        .route("/login/finish",web::post().to(finish_authentication))
+        .route("/login/qr/start", web::post().to(start_qr_login))
+        .route("/login/qr/poll", web::post().to(poll_qr_login))
+        .route("/login/qr/{request_id}", web::get().to(get_qr_login))
+        .route("/login/qr/{request_id}/confirm/start", web::post().to(start_qr_confirmation))
+        .route("/login/qr/{request_id}/confirm/finish", web::post().to(finish_qr_confirmation))
+        .route("/auth/token", web::post().to(issue_tokens))
+        .route("/auth/token/refresh", web::post().to(refresh_tokens))
+        .route("/auth/token/revoke", web::post().to(revoke_tokens))
+        .route("/tokens/personal", web::get().to(list_personal_tokens))
+        .route("/tokens/personal", web::post().to(create_personal_token))
+        .route("/tokens/personal/{token_id}", web::delete().to(revoke_personal_token))
+        .route("/reauth/start", web::post().to(reauth_start))
+        .route("/reauth/finish", web::post().to(reauth_finish))
+        .route("/poll/new", web::post().to(create_poll))
+        .route("/polls",web::post().to(get_all_polls_from_db))
+        .route("/polls/{poll_id}/vote",web::post().to(vote_on_poll))
+        .route("/polls/{poll_id}/vote", web::delete().to(retract_vote))
+        .route("/polls/{poll_id}/vote/challenge", web::post().to(start_vote_confirmation))
+        .route("/polls/search", web::get().to(search_polls))
+        .route("/polls/{poll_id}",web::get().to(get_poll_details))
+        .route("/ws", web::get().to(ws))
+        .route("/polls/manage", web::post().to(manage_user_polls))
+        .route("/polls/{poll_id}/publish", web::post().to(publish_poll))
+        .route("/polls/{poll_id}/close" , web::post().to(close_poll))
+        .route("/polls/{poll_id}/reopen", web::post().to(reopen_poll))
+        .route("/polls/{poll_id}/archive", web::post().to(archive_poll))
+        .route("/polls/{poll_id}/reset" , web::post().to(reset_poll_votes))
+        .route("/admin/users/{username}/roles", web::get().to(get_user_roles))
+        .route("/admin/users/{username}/roles", web::post().to(assign_user_role))
+        .route("/admin/users/{username}/roles/{role}", web::delete().to(revoke_user_role))
+        .route("/admin/oidc/clients", web::post().to(register_oidc_client))
+        .route("/.well-known/openid-configuration", web::get().to(openid_configuration))
+        .route("/oauth/jwks", web::get().to(jwks))
+        .route("/oauth/authorize", web::get().to(authorize))
+        .route("/oauth/token", web::post().to(token))
+        .route("/oauth/userinfo", web::get().to(userinfo))
+        .route("/oauth/device_authorization", web::post().to(device_authorization))
+        .route("/oauth/device/{user_code}", web::get().to(get_device_request))
+        .route("/oauth/device/{user_code}", web::post().to(decide_device_request))
+        .route("/oauth/userinfo", web::post().to(userinfo));
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let (webauthn, webauthn_users) = startup().await;
+    if std::env::args().nth(1).as_deref() == Some("migrate") {
+        run_migrations().await;
+        return Ok(());
+    }
+    let (webauthn, webauthn_users, stores) = startup().await;
    
     let chat = Chat::new();
+    let pow = web::Data::new(ProofOfWork::from_env());
+    let token_signer = web::Data::new(TokenSigner::from_env());
+    let oidc = web::Data::new(OidcProvider::from_env());
+    let attestation = web::Data::new(AttestationTrust::from_env());
+    let readiness = web::Data::new(Readiness::default());
+    let session_backend = web::Data::new(stores.session.clone());
     info!("Listening on: http://127.0.0.1:5500");
     let key = Key::generate();
 
-    HttpServer::new(move || {
+    let draining = readiness.clone();
+
+    let server = HttpServer::new(move || {
         
         let cors = Cors::default()
             .allowed_origin("http://localhost:3000") 
-            .allowed_methods(vec!["GET", "POST", "OPTIONS"]) 
+            .allowed_methods(vec!["GET", "POST", "DELETE", "OPTIONS"]) 
             .allowed_headers(vec![http::header::AUTHORIZATION, http::header::ACCEPT, http::header::CONTENT_TYPE])
             .allow_any_header() 
             .supports_credentials();
@@ -36,7 +114,7 @@ async fn main() -> std::io::Result<()> {
         App::new()
         .wrap(middleware::Logger::default())
         .wrap(
-            SessionMiddleware::builder(MemorySession, key.clone())
+            SessionMiddleware::builder(stores.session.clone(), key.clone())
             
                 .cookie_name("webauthnrs".to_string())
                 .cookie_http_only(true)
@@ -48,22 +126,23 @@ async fn main() -> std::io::Result<()> {
         .wrap(cors)
             .app_data(webauthn.clone())
             .app_data(webauthn_users.clone()) 
+            .app_data(stores.users.clone())
+            .app_data(stores.polls.clone())
+            .app_data(stores.roles.clone())
+            .app_data(stores.health.clone())
+            .app_data(session_backend.clone())
+            .app_data(readiness.clone())
             .app_data(web::Data::new(chat.clone()))
-            .route("/register/start/{username}", web::post().to(register_start))
-            .route("/register/finish", web::post().to(register_finish))
-            .route("/login/start/{username}", web::post().to(start_authentication))
-            .route("/login/finish",web::post().to(finish_authentication))
-            .route("/poll/new", web::post().to(create_poll))
-            .route("/polls",web::post().to(get_all_polls_from_db))
-            .route("/polls/{poll_id}/vote",web::post().to(vote_on_poll))
-            .route("/polls/{poll_id}",web::get().to(get_poll_details))
-            .route("/ws", web::get().to(ws))
-            .route("/polls/manage", web::post().to(manage_user_polls))
-            .route("/polls/{poll_id}/close" , web::post().to(close_poll))
-            .route("/polls/{poll_id}/reset" , web::post().to(reset_poll_votes))
+            .app_data(pow.clone())
+            .app_data(token_signer.clone())
+            .app_data(oidc.clone())
+            .app_data(attestation.clone())
+            .configure(routes)
         
     })
+    .disable_signals()
     .bind("127.0.0.1:5500")?
-    .run()
-    .await
+    .run();
+    actix_web::rt::spawn(drain_on_shutdown(server.handle(), draining));
+    server.await
 }
@@ -0,0 +1,120 @@
+use std::env;
+use std::fs;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use openssl::{hash::MessageDigest, pkey::{PKey, Private}, rsa::Rsa, sign::Signer};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+#[derive(Serialize)]
+pub(crate) struct IdTokenClaims<'a> {
+    pub(crate) iss: &'a str,
+    pub(crate) sub: Uuid,
+    pub(crate) aud: &'a str,
+    pub(crate) iat: i64,
+    pub(crate) exp: i64,
+    pub(crate) auth_time: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) nonce: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) preferred_username: Option<&'a str>,
+}
+
+/**
+Settings and signing key for acting as an OpenID Connect provider. ID tokens are
+signed RS256 with the PEM key at `OIDC_SIGNING_KEY_PATH`; without it a key is
+generated at startup, so relying parties must refetch the JWKS after a restart.
+Unauthenticated authorization requests are sent to `OIDC_LOGIN_URL` with a
+`return_to` parameter, where the frontend runs the usual passkey login.
+*/
+pub(crate) struct OidcProvider {
+    pub(crate) issuer: String,
+    pub(crate) login_url: String,
+    // Page where users enter the code shown by a device-flow client
+    pub(crate) device_verification_url: String,
+    pub(crate) code_ttl_secs: i64,
+    pub(crate) id_token_ttl_secs: i64,
+    key: PKey<Private>,
+    kid: String,
+}
+
+impl OidcProvider {
+    pub(crate) fn from_env() -> Self {
+        let rsa = match env::var("OIDC_SIGNING_KEY_PATH") {
+            Ok(path) => {
+                let pem = fs::read(&path).expect("Failed to read OIDC signing key");
+                Rsa::private_key_from_pem(&pem).expect("Invalid OIDC signing key")
+            }
+            Err(_) => Rsa::generate(2048).expect("Failed to generate OIDC signing key"),
+        };
+        let kid = URL_SAFE_NO_PAD.encode(&Sha256::digest(rsa.n().to_vec())[..16]);
+        OidcProvider {
+            issuer: env::var("OIDC_ISSUER").unwrap_or_else(|_| "http://localhost:5500".to_string()),
+            login_url: env::var("OIDC_LOGIN_URL").unwrap_or_else(|_| "http://localhost:3000/login".to_string()),
+            device_verification_url: env::var("DEVICE_VERIFICATION_URL")
+                .unwrap_or_else(|_| "http://localhost:3000/device".to_string()),
+            code_ttl_secs: 60,
+            id_token_ttl_secs: 10 * 60,
+            key: PKey::from_rsa(rsa).expect("Invalid OIDC signing key"),
+            kid,
+        }
+    }
+
+    fn endpoint(&self, path: &str) -> String {
+        format!("{}{}", self.issuer.trim_end_matches('/'), path)
+    }
+
+    pub(crate) fn discovery_document(&self) -> Value {
+        json!({
+            "issuer": self.issuer,
+            "authorization_endpoint": self.endpoint("/oauth/authorize"),
+            "token_endpoint": self.endpoint("/oauth/token"),
+            "device_authorization_endpoint": self.endpoint("/oauth/device_authorization"),
+            "userinfo_endpoint": self.endpoint("/oauth/userinfo"),
+            "jwks_uri": self.endpoint("/oauth/jwks"),
+            "response_types_supported": ["code"],
+            "grant_types_supported": ["authorization_code", "urn:ietf:params:oauth:grant-type:device_code"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": ["RS256"],
+            "scopes_supported": ["openid", "profile"],
+            "claims_supported": ["sub", "iss", "aud", "exp", "iat", "auth_time", "nonce", "preferred_username"],
+            "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "none"],
+            "code_challenge_methods_supported": ["S256"],
+        })
+    }
+
+    pub(crate) fn jwks(&self) -> Value {
+        let rsa = self.key.rsa().expect("OIDC key is RSA");
+        json!({
+            "keys": [{
+                "kty": "RSA",
+                "use": "sig",
+                "alg": "RS256",
+                "kid": self.kid,
+                "n": URL_SAFE_NO_PAD.encode(rsa.n().to_vec()),
+                "e": URL_SAFE_NO_PAD.encode(rsa.e().to_vec()),
+            }]
+        })
+    }
+
+    pub(crate) fn sign_id_token(&self, claims: &IdTokenClaims) -> String {
+        let header = json!({ "alg": "RS256", "typ": "JWT", "kid": self.kid });
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header).unwrap()),
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).unwrap())
+        );
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key).expect("RS256 signer");
+        signer.update(signing_input.as_bytes()).expect("RS256 signer");
+        let signature = signer.sign_to_vec().expect("RS256 signer");
+        format!("{}.{}", signing_input, URL_SAFE_NO_PAD.encode(signature))
+    }
+}
+
+// PKCE S256: BASE64URL(SHA256(code_verifier)) must equal the code_challenge
+pub(crate) fn verify_pkce(code_verifier: &str, code_challenge: &str) -> bool {
+    let valid_length = (43..=128).contains(&code_verifier.len());
+    valid_length && URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes())) == code_challenge
+}
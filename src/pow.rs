@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+use std::env;
+use std::future::{ready, Ready};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::{dev::Payload, web::Data, FromRequest, HttpRequest, HttpResponse, ResponseError};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub(crate) const CHALLENGE_HEADER: &str = "X-PoW-Challenge";
+pub(crate) const SOLUTION_HEADER: &str = "X-PoW-Solution";
+
+#[derive(Debug, Error)]
+pub enum PowError {
+    #[error("Proof of work required")]
+    Missing,
+    #[error("Malformed proof of work challenge")]
+    Malformed,
+    #[error("Proof of work challenge expired")]
+    Expired,
+    #[error("Invalid proof of work solution")]
+    InvalidSolution,
+    #[error("Proof of work challenge already used")]
+    Replayed,
+}
+
+impl ResponseError for PowError {
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            PowError::Missing => HttpResponse::PreconditionRequired().body(self.to_string()),
+            PowError::Malformed => HttpResponse::BadRequest().body(self.to_string()),
+            PowError::Expired | PowError::InvalidSolution | PowError::Replayed => {
+                HttpResponse::Forbidden().body(self.to_string())
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct PowChallenge {
+    pub challenge: String,
+    pub difficulty: u8,
+    pub expires_at: i64,
+    pub algorithm: &'static str,
+}
+
+struct LoadWindow {
+    started: Instant,
+    issued: u32,
+}
+
+/**
+Stateless hashcash-style challenges. A challenge is `payload.mac` where the payload
+carries the difficulty, expiry and a random nonce, so nothing is kept server side
+until a valid solution has been presented. Only then is the nonce remembered (until
+it expires) to stop the same solution being replayed.
+
+A solution is any string `s` such that `sha256(challenge + ":" + s)` has at least
+`difficulty` leading zero bits.
+*/
+pub(crate) struct ProofOfWork {
+    enabled: bool,
+    key: Vec<u8>,
+    base_difficulty: u8,
+    max_difficulty: u8,
+    load_threshold: u32,
+    ttl: Duration,
+    window: Mutex<LoadWindow>,
+    spent: Mutex<HashMap<String, i64>>,
+}
+
+const LOAD_WINDOW: Duration = Duration::from_secs(60);
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+impl ProofOfWork {
+    pub(crate) fn from_env() -> Self {
+        let key = match env::var("POW_SECRET") {
+            Ok(secret) => secret.into_bytes(),
+            Err(_) => {
+                let mut key = vec![0u8; 32];
+                rand::thread_rng().fill_bytes(&mut key);
+                key
+            }
+        };
+        let base_difficulty = env_or("POW_BASE_DIFFICULTY", 16u8);
+        ProofOfWork {
+            enabled: env_or("POW_ENABLED", false),
+            key,
+            base_difficulty,
+            max_difficulty: env_or("POW_MAX_DIFFICULTY", 24u8).max(base_difficulty),
+            load_threshold: env_or("POW_LOAD_THRESHOLD", 30u32).max(1),
+            ttl: Duration::from_secs(env_or("POW_CHALLENGE_TTL_SECS", 120u64)),
+            window: Mutex::new(LoadWindow { started: Instant::now(), issued: 0 }),
+            spent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // Every doubling of the issue rate above the threshold adds one bit of difficulty.
+    fn current_difficulty(&self) -> u8 {
+        let mut window = self.window.lock().unwrap();
+        if window.started.elapsed() > LOAD_WINDOW {
+            window.started = Instant::now();
+            window.issued = 0;
+        }
+        window.issued = window.issued.saturating_add(1);
+
+        let mut difficulty = self.base_difficulty;
+        let mut load = window.issued / self.load_threshold;
+        while load > 0 && difficulty < self.max_difficulty {
+            difficulty += 1;
+            load /= 2;
+        }
+        difficulty
+    }
+
+    fn sign(&self, payload: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    pub(crate) fn issue(&self) -> PowChallenge {
+        let difficulty = self.current_difficulty();
+        let expires_at = Utc::now().timestamp() + self.ttl.as_secs() as i64;
+        let mut nonce = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let payload = format!("{}:{}:{}", difficulty, expires_at, hex::encode(nonce));
+        let mac = self.sign(&payload).finalize().into_bytes();
+        PowChallenge {
+            challenge: format!(
+                "{}.{}",
+                URL_SAFE_NO_PAD.encode(payload.as_bytes()),
+                URL_SAFE_NO_PAD.encode(mac)
+            ),
+            difficulty,
+            expires_at,
+            algorithm: "sha256",
+        }
+    }
+
+    pub(crate) fn verify(&self, challenge: &str, solution: &str) -> Result<(), PowError> {
+        let (payload_b64, mac_b64) = challenge.split_once('.').ok_or(PowError::Malformed)?;
+        let payload = URL_SAFE_NO_PAD
+            .decode(payload_b64)
+            .ok()
+            .and_then(|p| String::from_utf8(p).ok())
+            .ok_or(PowError::Malformed)?;
+        let mac = URL_SAFE_NO_PAD.decode(mac_b64).map_err(|_| PowError::Malformed)?;
+        self.sign(&payload).verify_slice(&mac).map_err(|_| PowError::Malformed)?;
+
+        let mut parts = payload.splitn(3, ':');
+        let difficulty: u8 = parts.next().and_then(|d| d.parse().ok()).ok_or(PowError::Malformed)?;
+        let expires_at: i64 = parts.next().and_then(|e| e.parse().ok()).ok_or(PowError::Malformed)?;
+        let nonce = parts.next().ok_or(PowError::Malformed)?;
+
+        let now = Utc::now().timestamp();
+        if expires_at < now {
+            return Err(PowError::Expired);
+        }
+
+        let digest = Sha256::digest(format!("{}:{}", challenge, solution).as_bytes());
+        if leading_zero_bits(&digest) < u32::from(difficulty) {
+            return Err(PowError::InvalidSolution);
+        }
+
+        let mut spent = self.spent.lock().unwrap();
+        spent.retain(|_, expiry| *expiry >= now);
+        if spent.insert(nonce.to_string(), expires_at).is_some() {
+            return Err(PowError::Replayed);
+        }
+        Ok(())
+    }
+}
+
+fn leading_zero_bits(digest: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in digest {
+        if *byte == 0 {
+            bits += 8;
+        } else {
+            bits += byte.leading_zeros();
+            break;
+        }
+    }
+    bits
+}
+
+/**
+Extractor guarding a handler with a solved challenge from the `X-PoW-Challenge` and
+`X-PoW-Solution` headers. It is a no-op while proof of work is disabled.
+*/
+pub(crate) struct PowVerified;
+
+impl FromRequest for PowVerified {
+    type Error = PowError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let pow = match req.app_data::<Data<ProofOfWork>>() {
+            Some(pow) if pow.enabled() => pow,
+            _ => return ready(Ok(PowVerified)),
+        };
+        let header = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+        let result = match (header(CHALLENGE_HEADER), header(SOLUTION_HEADER)) {
+            (Some(challenge), Some(solution)) => pow.verify(challenge, solution).map(|_| PowVerified),
+            _ => Err(PowError::Missing),
+        };
+        ready(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_key(key: &[u8]) -> ProofOfWork {
+        ProofOfWork {
+            enabled: true,
+            key: key.to_vec(),
+            base_difficulty: 8,
+            max_difficulty: 8,
+            load_threshold: 30,
+            ttl: Duration::from_secs(120),
+            window: Mutex::new(LoadWindow { started: Instant::now(), issued: 0 }),
+            spent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // A challenge signed by `pow` with the given difficulty and expiry
+    fn challenge(pow: &ProofOfWork, difficulty: u8, expires_at: i64) -> String {
+        let payload = format!("{}:{}:{}", difficulty, expires_at, "00112233445566778899aabbccddeeff");
+        let mac = pow.sign(&payload).finalize().into_bytes();
+        format!("{}.{}", URL_SAFE_NO_PAD.encode(payload.as_bytes()), URL_SAFE_NO_PAD.encode(mac))
+    }
+
+    // The first counter whose hash has `enough` leading zero bits, or too few when `enough` is false
+    fn solve(challenge: &str, difficulty: u8, enough: bool) -> String {
+        (0u64..)
+            .map(|n| n.to_string())
+            .find(|s| {
+                let bits = leading_zero_bits(&Sha256::digest(format!("{}:{}", challenge, s).as_bytes()));
+                (bits >= u32::from(difficulty)) == enough
+            })
+            .unwrap()
+    }
+
+    fn in_a_minute() -> i64 {
+        Utc::now().timestamp() + 60
+    }
+
+    #[test]
+    fn accepts_an_issued_challenge_once_solved() {
+        let pow = with_key(b"key");
+        let issued = pow.issue();
+        let solution = solve(&issued.challenge, issued.difficulty, true);
+        assert!(pow.verify(&issued.challenge, &solution).is_ok());
+    }
+
+    #[test]
+    fn rejects_a_challenge_signed_with_another_key() {
+        let pow = with_key(b"key");
+        let forged = challenge(&with_key(b"other key"), 8, in_a_minute());
+        let solution = solve(&forged, 8, true);
+        assert!(matches!(pow.verify(&forged, &solution), Err(PowError::Malformed)));
+    }
+
+    #[test]
+    fn rejects_a_lowered_difficulty() {
+        let pow = with_key(b"key");
+        let signed = challenge(&pow, 8, in_a_minute());
+        let (_, mac) = signed.split_once('.').unwrap();
+        let lowered = format!("{}.{}", URL_SAFE_NO_PAD.encode(format!("0:{}:{}", in_a_minute(), "00112233445566778899aabbccddeeff")), mac);
+        assert!(matches!(pow.verify(&lowered, "0"), Err(PowError::Malformed)));
+    }
+
+    #[test]
+    fn rejects_malformed_challenges() {
+        let pow = with_key(b"key");
+        assert!(matches!(pow.verify("no-dot", "0"), Err(PowError::Malformed)));
+        assert!(matches!(pow.verify("!!.!!", "0"), Err(PowError::Malformed)));
+    }
+
+    #[test]
+    fn rejects_an_expired_challenge() {
+        let pow = with_key(b"key");
+        let expired = challenge(&pow, 8, Utc::now().timestamp() - 1);
+        let solution = solve(&expired, 8, true);
+        assert!(matches!(pow.verify(&expired, &solution), Err(PowError::Expired)));
+    }
+
+    #[test]
+    fn rejects_insufficient_difficulty() {
+        let pow = with_key(b"key");
+        let signed = challenge(&pow, 8, in_a_minute());
+        let solution = solve(&signed, 8, false);
+        assert!(matches!(pow.verify(&signed, &solution), Err(PowError::InvalidSolution)));
+    }
+
+    #[test]
+    fn rejects_a_spent_nonce() {
+        let pow = with_key(b"key");
+        let signed = challenge(&pow, 8, in_a_minute());
+        let solution = solve(&signed, 8, true);
+        assert!(pow.verify(&signed, &solution).is_ok());
+        assert!(matches!(pow.verify(&signed, &solution), Err(PowError::Replayed)));
+        // A different solution to the same challenge spends the same nonce
+        let other = (0u64..)
+            .map(|n| format!("x{}", n))
+            .find(|s| leading_zero_bits(&Sha256::digest(format!("{}:{}", signed, s).as_bytes())) >= 8)
+            .unwrap();
+        assert!(matches!(pow.verify(&signed, &other), Err(PowError::Replayed)));
+    }
+
+    #[test]
+    fn counts_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0, 0, 0xff]), 16);
+        assert_eq!(leading_zero_bits(&[0, 0x10]), 11);
+        assert_eq!(leading_zero_bits(&[0x80]), 0);
+    }
+}
@@ -0,0 +1,135 @@
+use std::str::FromStr;
+
+use actix_web::{
+    dev::Payload,
+    error::{ErrorForbidden, ErrorInternalServerError},
+    web::Data,
+    Error, FromRequest, HttpRequest,
+};
+use futures::future::LocalBoxFuture;
+
+use crate::{db_operations_repo::store::RoleStore, identity::AuthenticatedUser};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Role {
+    User,
+    Moderator,
+    Admin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Permission {
+    CreatePoll,
+    CloseAnyPoll,
+    ResetAnyPoll,
+    ManageUsers,
+}
+
+impl Role {
+    pub(crate) const ALL: [Role; 3] = [Role::User, Role::Moderator, Role::Admin];
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+
+    // Permissions seeded into `role_permissions`; operators may grant more in the database
+    pub(crate) fn default_permissions(&self) -> &'static [Permission] {
+        match self {
+            Role::User => &[Permission::CreatePoll],
+            Role::Moderator => &[Permission::CreatePoll, Permission::CloseAnyPoll, Permission::ResetAnyPoll],
+            Role::Admin => &[
+                Permission::CreatePoll,
+                Permission::CloseAnyPoll,
+                Permission::ResetAnyPoll,
+                Permission::ManageUsers,
+            ],
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Role::ALL.into_iter().find(|r| r.as_str() == s).ok_or(())
+    }
+}
+
+impl Permission {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Permission::CreatePoll => "poll:create",
+            Permission::CloseAnyPoll => "poll:close_any",
+            Permission::ResetAnyPoll => "poll:reset_any",
+            Permission::ManageUsers => "users:manage",
+        }
+    }
+}
+
+impl FromStr for Permission {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            Permission::CreatePoll,
+            Permission::CloseAnyPoll,
+            Permission::ResetAnyPoll,
+            Permission::ManageUsers,
+        ]
+        .into_iter()
+        .find(|p| p.as_str() == s)
+        .ok_or(())
+    }
+}
+
+/**
+The authenticated user together with the permissions granted by their roles.
+Handlers call [UserPermissions::require] for hard requirements or
+[UserPermissions::has] when the permission only widens what the user may do.
+*/
+pub(crate) struct UserPermissions {
+    pub(crate) user: AuthenticatedUser,
+    granted: Vec<Permission>,
+}
+
+impl UserPermissions {
+    pub(crate) fn has(&self, permission: Permission) -> bool {
+        self.granted.contains(&permission)
+    }
+
+    pub(crate) fn require(&self, permission: Permission) -> Result<(), Error> {
+        // No token scope covers user management: personal access tokens, device
+        // grants and anything else restricted to scopes are refused
+        if permission == Permission::ManageUsers && self.user.scopes.is_some() {
+            return Err(ErrorForbidden("User management is not available to scoped tokens"));
+        }
+        if self.has(permission) {
+            Ok(())
+        } else {
+            Err(ErrorForbidden(format!("Missing permission {}", permission.as_str())))
+        }
+    }
+}
+
+impl FromRequest for UserPermissions {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let user = AuthenticatedUser::from_request(req, payload);
+        let roles = req.app_data::<Data<dyn RoleStore>>().cloned();
+        Box::pin(async move {
+            let user = user.await?;
+            let roles = roles.ok_or_else(|| ErrorInternalServerError("Role store not configured"))?;
+            let granted = roles
+                .permissions_for_user(&user.user_id)
+                .await
+                .map_err(|_| ErrorInternalServerError("Error loading permissions"))?;
+            Ok(UserPermissions { user, granted })
+        })
+    }
+}
@@ -8,6 +8,13 @@ use anyhow::anyhow;
 use chrono::Utc;
 use once_cell::sync::Lazy;
 use rand::distributions::{Alphanumeric, DistString};
+#[cfg(feature = "sqlite")]
+use rusqlite::OptionalExtension;
+#[cfg(feature = "sqlite")]
+use std::sync::Arc;
+
+#[cfg(feature = "sqlite")]
+use crate::db_operations_repo::sqlite_store::blocking;
 
 /**
 Static map where session states are stored
@@ -23,7 +30,7 @@ pub(crate) struct State {
 /**
 Implementation of the [SessionStore] trait of [actix_session].
 */
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub(crate) struct MemorySession;
 
 impl SessionStore for MemorySession {
@@ -124,4 +131,205 @@ impl SessionStore for MemorySession {
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/**
+[SessionStore] on the `sessions` table of the SQLite backend, so that sessions survive
+a restart.
+*/
+#[cfg(feature = "sqlite")]
+#[derive(Clone)]
+pub(crate) struct SqliteSession {
+    pub(crate) conn: Arc<std::sync::Mutex<rusqlite::Connection>>,
+}
+
+#[cfg(feature = "sqlite")]
+fn valid_until(ttl: &Duration) -> i64 {
+    Utc::now().timestamp() + ttl.whole_seconds()
+}
+
+#[cfg(feature = "sqlite")]
+impl SessionStore for SqliteSession {
+    async fn load(
+        &self,
+        session_key: &SessionKey,
+    ) -> Result<Option<HashMap<String, String>>, LoadError> {
+        let session_key = session_key.as_ref().to_string();
+        let state: Option<String> = blocking(&self.conn, move |conn| {
+            conn.query_row(
+                "SELECT session_state FROM sessions WHERE session_key = ?1 AND valid_until >= ?2",
+                rusqlite::params![session_key, Utc::now().timestamp()],
+                |r| r.get(0),
+            )
+            .optional()
+        })
+        .await
+        .map_err(|e| LoadError::Other(e.into()))?;
+        state
+            .map(|s| serde_json::from_str(&s).map_err(|e| LoadError::Deserialization(e.into())))
+            .transpose()
+    }
+
+    async fn save(
+        &self,
+        session_state: HashMap<String, String>,
+        ttl: &Duration,
+    ) -> Result<SessionKey, SaveError> {
+        let state = serde_json::to_string(&session_state).map_err(|e| SaveError::Serialization(e.into()))?;
+        let valid_until = valid_until(ttl);
+        let session_key = blocking(&self.conn, move |conn| loop {
+            let session_key = Alphanumeric.sample_string(&mut rand::thread_rng(), 512);
+            let inserted = conn.execute(
+                "INSERT OR IGNORE INTO sessions (session_key, session_state, valid_until) VALUES (?1, ?2, ?3)",
+                rusqlite::params![session_key, state, valid_until],
+            )?;
+            if inserted == 1 {
+                return Ok::<_, rusqlite::Error>(session_key);
+            }
+        })
+        .await
+        .map_err(|e| SaveError::Other(e.into()))?;
+
+        SessionKey::try_from(session_key)
+            .map_err(|_| SaveError::Serialization(anyhow!("Invalid Session Key Error")))
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: HashMap<String, String>,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        let state = serde_json::to_string(&session_state).map_err(|e| UpdateError::Serialization(e.into()))?;
+        let (key, valid_until) = (session_key.as_ref().to_string(), valid_until(ttl));
+        let updated = blocking(&self.conn, move |conn| {
+            conn.execute(
+                "UPDATE sessions SET session_state = ?1, valid_until = ?2 WHERE session_key = ?3",
+                rusqlite::params![state, valid_until, key],
+            )
+        })
+        .await
+        .map_err(|e| UpdateError::Other(e.into()))?;
+        if updated == 1 {
+            Ok(session_key)
+        } else {
+            Err(UpdateError::Other(anyhow!(
+                "Didn't found session with that key"
+            )))
+        }
+    }
+
+    async fn update_ttl(
+        &self,
+        session_key: &SessionKey,
+        ttl: &Duration,
+    ) -> Result<(), anyhow::Error> {
+        let (key, valid_until) = (session_key.as_ref().to_string(), valid_until(ttl));
+        blocking(&self.conn, move |conn| {
+            conn.execute(
+                "UPDATE sessions SET valid_until = ?1 WHERE session_key = ?2",
+                rusqlite::params![valid_until, key],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        let key = session_key.as_ref().to_string();
+        blocking(&self.conn, move |conn| {
+            // Expired sessions are swept along with the one being removed
+            conn.execute(
+                "DELETE FROM sessions WHERE session_key = ?1 OR valid_until < ?2",
+                rusqlite::params![key, Utc::now().timestamp()],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+}
+
+/**
+The session store the server was configured with: [MemorySession], or [SqliteSession]
+when the SQLite backend is in use.
+*/
+#[derive(Clone)]
+pub(crate) enum SessionBackend {
+    Memory(MemorySession),
+    #[cfg(feature = "sqlite")]
+    Sqlite(SqliteSession),
+}
+
+impl SessionBackend {
+    // Whether the store can currently be read, for the readiness probe
+    pub(crate) async fn ping(&self) -> Result<(), anyhow::Error> {
+        match self {
+            SessionBackend::Memory(_) => {
+                let _states = SESSION_STATES.lock().map_err(|_| anyhow!("Poison Error"))?;
+            }
+            #[cfg(feature = "sqlite")]
+            SessionBackend::Sqlite(store) => {
+                blocking(&store.conn, |conn| conn.query_row("SELECT COUNT(*) FROM sessions", [], |_| Ok(()))).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SessionStore for SessionBackend {
+    async fn load(
+        &self,
+        session_key: &SessionKey,
+    ) -> Result<Option<HashMap<String, String>>, LoadError> {
+        match self {
+            SessionBackend::Memory(store) => store.load(session_key).await,
+            #[cfg(feature = "sqlite")]
+            SessionBackend::Sqlite(store) => store.load(session_key).await,
+        }
+    }
+
+    async fn save(
+        &self,
+        session_state: HashMap<String, String>,
+        ttl: &Duration,
+    ) -> Result<SessionKey, SaveError> {
+        match self {
+            SessionBackend::Memory(store) => store.save(session_state, ttl).await,
+            #[cfg(feature = "sqlite")]
+            SessionBackend::Sqlite(store) => store.save(session_state, ttl).await,
+        }
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: HashMap<String, String>,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        match self {
+            SessionBackend::Memory(store) => store.update(session_key, session_state, ttl).await,
+            #[cfg(feature = "sqlite")]
+            SessionBackend::Sqlite(store) => store.update(session_key, session_state, ttl).await,
+        }
+    }
+
+    async fn update_ttl(
+        &self,
+        session_key: &SessionKey,
+        ttl: &Duration,
+    ) -> Result<(), anyhow::Error> {
+        match self {
+            SessionBackend::Memory(store) => store.update_ttl(session_key, ttl).await,
+            #[cfg(feature = "sqlite")]
+            SessionBackend::Sqlite(store) => store.update_ttl(session_key, ttl).await,
+        }
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        match self {
+            SessionBackend::Memory(store) => store.delete(session_key).await,
+            #[cfg(feature = "sqlite")]
+            SessionBackend::Sqlite(store) => store.delete(session_key).await,
+        }
+    }
+}
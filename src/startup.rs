@@ -1,26 +1,217 @@
 
+use std::sync::Arc;
+use std::time::Duration;
+
 use actix_web::web::Data;
-use tokio::sync::Mutex;
-use tokio_postgres::Client;
+use deadpool_postgres::{Pool, Timeouts};
 use webauthn_rs::prelude::*;
 
-use crate::db::db::connect_db;
+use crate::db::db::{create_pool, DatabaseUnavailable, PooledClient, ReconnectBackoff};
+use crate::db::migrations::{migrate, Migration, MigrationError};
+#[cfg(feature = "sqlite")]
+use crate::db_operations_repo::sqlite_store::SqliteStore;
+use crate::db_operations_repo::{
+    memory_store::MemoryStore,
+    pg_store::PgStore,
+    role_repo::RoleRepo,
+    store::{PollStore, RoleStore, StoreHealth, UserStore},
+    transaction::RepoTransaction,
+};
+#[cfg(feature = "sqlite")]
+use crate::session::SqliteSession;
+use crate::session::{MemorySession, SessionBackend};
 
+// How long the readiness probe waits for a database connection
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
 
+/**
+The Postgres pool. It is absent when the server runs on the SQLite or memory backend,
+in which case the endpoints that only exist in Postgres (tokens, OIDC, device and QR
+logins) answer `503`.
+*/
 pub(crate) struct UserData {
-    pub(crate) client: Client,
+    pub(crate) pool: Option<Pool>,
+    pub(crate) reconnect: ReconnectBackoff,
+}
+
+impl UserData {
+    // Check out a connection for the current operation
+    pub(crate) async fn client(&self) -> Result<PooledClient, DatabaseUnavailable> {
+        let pool = self.pool.as_ref().ok_or(DatabaseUnavailable)?;
+        self.reconnect.get(pool).await.map(PooledClient).map_err(|e| {
+            log::error!("Database pool error: {}", e);
+            DatabaseUnavailable
+        })
+    }
+
+    // A single, quick checkout for the readiness probe, which must not sit through the
+    // reconnect backoff while the database is down
+    pub(crate) async fn probe_client(&self) -> Result<PooledClient, DatabaseUnavailable> {
+        let pool = self.pool.as_ref().ok_or(DatabaseUnavailable)?;
+        let timeouts = Timeouts { wait: Some(PROBE_TIMEOUT), create: Some(PROBE_TIMEOUT), recycle: Some(PROBE_TIMEOUT) };
+        pool.timeout_get(&timeouts).await.map(PooledClient).map_err(|e| {
+            log::warn!("Database readiness check failed: {}", e);
+            DatabaseUnavailable
+        })
+    }
+}
+
+
+// The stores and session store handlers are given
+pub(crate) struct Stores {
+    pub(crate) users: Data<dyn UserStore>,
+    pub(crate) polls: Data<dyn PollStore>,
+    pub(crate) roles: Data<dyn RoleStore>,
+    pub(crate) session: SessionBackend,
+    // The database readiness is judged by
+    pub(crate) health: Data<dyn StoreHealth>,
+}
+
+impl Stores {
+    fn of<S: UserStore + PollStore + RoleStore + 'static>(
+        store: Arc<S>,
+        session: SessionBackend,
+        health: Arc<dyn StoreHealth>,
+    ) -> Self {
+        Stores {
+            users: Data::from(store.clone() as Arc<dyn UserStore>),
+            polls: Data::from(store.clone() as Arc<dyn PollStore>),
+            roles: Data::from(store as Arc<dyn RoleStore>),
+            session,
+            health: Data::from(health),
+        }
+    }
+}
+
+/**
+Users, passkeys, polls and roles in process memory, with no database at all. Used
+for demos with `STORAGE_BACKEND=memory` and by the in-process API tests.
+*/
+pub(crate) fn memory_stores() -> Stores {
+    let store = Arc::new(MemoryStore::default());
+    Stores::of(store.clone(), SessionBackend::Memory(MemorySession), store)
+}
+
+// The file of a `sqlite://path` or `sqlite:path` DATABASE_URL
+fn sqlite_path(database_url: &str) -> Option<&str> {
+    database_url.strip_prefix("sqlite://").or_else(|| database_url.strip_prefix("sqlite:"))
+}
+
+// Log the applied migrations, refusing to go on if any are left pending
+fn check_migrations(result: Result<Vec<&Migration>, MigrationError>, auto_migrate: bool) {
+    match result {
+        Ok(pending) if !auto_migrate && !pending.is_empty() => panic!("{}", MigrationError::Pending(pending.len())),
+        Ok(applied) => {
+            for migration in applied {
+                log::info!("Applied migration {} ({})", migration.version, migration.name);
+            }
+        }
+        Err(e) => panic!("{}", e),
+    }
 }
 
+#[cfg(feature = "sqlite")]
+fn sqlite_stores(path: &str, auto_migrate: bool) -> Stores {
+    let store = SqliteStore::open(path).expect("Failed to open the SQLite database");
+    check_migrations(store.migrate(auto_migrate), auto_migrate);
+    store.seed_default_permissions().expect("Failed to seed role permissions");
+    let session = SessionBackend::Sqlite(SqliteSession { conn: store.connection() });
+    let store = Arc::new(store);
+    Stores::of(store.clone(), session, store)
+}
 
-pub(crate) async fn startup() -> (Data<Webauthn>, Data<Mutex<UserData>>){
+#[cfg(not(feature = "sqlite"))]
+fn sqlite_stores(_path: &str, _auto_migrate: bool) -> Stores {
+    panic!("DATABASE_URL points at SQLite but this binary was built without the `sqlite` feature");
+}
+
+// The relying party passkeys are registered and asserted against
+pub(crate) fn webauthn() -> Data<Webauthn> {
     let rp_id = "localhost";
     let rp_origin = Url::parse("http://localhost:3000").expect("Invalid URL");
     let builder = WebauthnBuilder::new(rp_id, &rp_origin).expect("Invalid configuration");
     let builder = builder.rp_name("Actix-web webauthn-rs");
-    let webauthn = Data::new(builder.build().expect("Invalid configuration"));
-    let client = connect_db().await.unwrap();
-    let webauthn_users = Data::new(Mutex::new(UserData {
-        client,
-    }));
-    (webauthn, webauthn_users)
-}
\ No newline at end of file
+    Data::new(builder.build().expect("Invalid configuration"))
+}
+
+pub(crate) async fn startup() -> (Data<Webauthn>, Data<UserData>, Stores){
+    let webauthn = webauthn();
+    if std::env::var("STORAGE_BACKEND").as_deref() == Ok("memory") {
+        let user_data = Data::new(UserData { pool: None, reconnect: ReconnectBackoff::from_env() });
+        return (webauthn, user_data, memory_stores());
+    }
+    let auto_migrate = std::env::var("DB_AUTO_MIGRATE").map_or(true, |v| v != "false");
+    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+
+    if let Some(path) = sqlite_path(&database_url) {
+        let stores = sqlite_stores(path, auto_migrate);
+        bootstrap_admin(&stores).await;
+        let user_data = Data::new(UserData { pool: None, reconnect: ReconnectBackoff::from_env() });
+        return (webauthn, user_data, stores);
+    }
+
+    let user_data = UserData { pool: Some(create_pool()), reconnect: ReconnectBackoff::from_env() };
+    let mut client = user_data.client().await.expect("Failed to connect to the database");
+    check_migrations(migrate(&mut client, auto_migrate).await, auto_migrate);
+    RoleRepo { client: &client }.seed_default_permissions().await.expect("Failed to seed role permissions");
+    let tx = RepoTransaction::begin(&mut client).await.expect("Failed to start transaction");
+    let backfilled = tx.users().backfill_credential_columns().await
+        .expect("Failed to backfill passkey credential columns");
+    tx.commit().await.expect("Failed to backfill passkey credential columns");
+    if backfilled > 0 {
+        log::info!("Backfilled credential columns for {} passkeys", backfilled);
+    }
+    drop(client);
+    let user_data = Data::new(user_data);
+    let pg = Arc::new(PgStore { db: user_data.clone() });
+    let stores = Stores::of(pg.clone(), SessionBackend::Memory(MemorySession), pg);
+    bootstrap_admin(&stores).await;
+    (webauthn, user_data, stores)
+}
+
+// The `migrate` command: bring the schema up to date and exit
+pub(crate) async fn run_migrations() {
+    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let result = match sqlite_path(&database_url) {
+        Some(path) => migrate_sqlite_file(path),
+        None => {
+            let pool = create_pool();
+            let mut client = ReconnectBackoff::from_env().get(&pool).await.expect("Failed to connect to the database");
+            migrate(&mut client, true).await
+        }
+    };
+    match result {
+        Ok(applied) if applied.is_empty() => println!("Database schema is up to date"),
+        Ok(applied) => {
+            for migration in applied {
+                println!("Applied migration {} ({})", migration.version, migration.name);
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn migrate_sqlite_file(path: &str) -> Result<Vec<&'static Migration>, MigrationError> {
+    SqliteStore::open(path)?.migrate(true)
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn migrate_sqlite_file(_path: &str) -> Result<Vec<&'static Migration>, MigrationError> {
+    panic!("DATABASE_URL points at SQLite but this binary was built without the `sqlite` feature");
+}
+
+// Only an account that already exists at startup is promoted: granting it on
+// registration would hand admin to whoever claims the username first
+async fn bootstrap_admin(stores: &Stores) {
+    if let Ok(username) = std::env::var("BOOTSTRAP_ADMIN_USERNAME") {
+        if let Ok(Some(user_id)) = stores.users.find_unique_id_by_username(&username).await {
+            if stores.roles.bootstrap_admin(&user_id, &username).await.unwrap_or(false) {
+                log::info!("Granted admin role to bootstrap user {}", username);
+            }
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::env;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Error)]
+pub enum TokenError {
+    #[error("Malformed token")]
+    Malformed,
+    #[error("Invalid token signature")]
+    InvalidSignature,
+    #[error("Token expired")]
+    Expired,
+    #[error("Token is not valid for this resource")]
+    WrongAudience,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    alg: String,
+    typ: String,
+}
+
+// Access tokens for this API. Tokens handed to OIDC relying parties only reach
+// `/oauth/userinfo`, so a third party never holds a credential for the API itself
+pub(crate) const API_AUDIENCE: &str = "api";
+pub(crate) const USERINFO_AUDIENCE: &str = "userinfo";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct AccessClaims {
+    pub(crate) sub: Uuid,
+    pub(crate) aud: String,
+    pub(crate) iat: i64,
+    pub(crate) exp: i64,
+    // When the user last completed a passkey assertion, carried over refreshes
+    pub(crate) auth_time: i64,
+    pub(crate) jti: Uuid,
+    // Restricts the token to these scopes, e.g. for tokens issued to OIDC clients
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) scope: Option<Vec<String>>,
+}
+
+/**
+Issues and verifies the short-lived HS256 access tokens handed to non-browser
+clients. The key comes from `TOKEN_SIGNING_KEY`; without it a random key is
+generated, which invalidates outstanding tokens on every restart.
+*/
+pub(crate) struct TokenSigner {
+    key: Vec<u8>,
+    pub(crate) access_ttl_secs: i64,
+    pub(crate) refresh_ttl_secs: i64,
+}
+
+impl TokenSigner {
+    pub(crate) fn from_env() -> Self {
+        let key = match env::var("TOKEN_SIGNING_KEY") {
+            Ok(key) => key.into_bytes(),
+            Err(_) => random_bytes(32),
+        };
+        let ttl = |name: &str, default: i64| {
+            env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+        };
+        TokenSigner {
+            key,
+            access_ttl_secs: ttl("ACCESS_TOKEN_TTL_SECS", 15 * 60),
+            refresh_ttl_secs: ttl("REFRESH_TOKEN_TTL_SECS", 30 * 24 * 60 * 60),
+        }
+    }
+
+    fn mac(&self, signing_input: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(signing_input.as_bytes());
+        mac
+    }
+
+    pub(crate) fn issue_access_token(&self, user_id: Uuid, audience: &str, auth_time: i64, scope: Option<Vec<String>>) -> String {
+        let now = Utc::now().timestamp();
+        let claims = AccessClaims {
+            sub: user_id,
+            aud: audience.to_string(),
+            iat: now,
+            exp: now + self.access_ttl_secs,
+            auth_time,
+            jti: Uuid::new_v4(),
+            scope,
+        };
+        let header = Header { alg: "HS256".to_string(), typ: "JWT".to_string() };
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header).unwrap()),
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap())
+        );
+        let signature = self.mac(&signing_input).finalize().into_bytes();
+        format!("{}.{}", signing_input, URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    pub(crate) fn verify_access_token(&self, token: &str, audience: &str) -> Result<AccessClaims, TokenError> {
+        let (signing_input, signature) = token.rsplit_once('.').ok_or(TokenError::Malformed)?;
+        let (header, claims) = signing_input.split_once('.').ok_or(TokenError::Malformed)?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| TokenError::Malformed)?;
+
+        let header: Header = decode_json(header)?;
+        if header.alg != "HS256" {
+            return Err(TokenError::Malformed);
+        }
+        self.mac(signing_input)
+            .verify_slice(&signature)
+            .map_err(|_| TokenError::InvalidSignature)?;
+
+        let claims: AccessClaims = decode_json(claims)?;
+        if claims.exp < Utc::now().timestamp() {
+            return Err(TokenError::Expired);
+        }
+        if claims.aud != audience {
+            return Err(TokenError::WrongAudience);
+        }
+        Ok(claims)
+    }
+}
+
+fn decode_json<T: for<'de> Deserialize<'de>>(segment: &str) -> Result<T, TokenError> {
+    let bytes = URL_SAFE_NO_PAD.decode(segment).map_err(|_| TokenError::Malformed)?;
+    serde_json::from_slice(&bytes).map_err(|_| TokenError::Malformed)
+}
+
+pub(crate) fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+// Personal access tokens are told apart from signed access tokens by this prefix
+pub(crate) const PAT_PREFIX: &str = "pat_";
+
+// Opaque secret handed to the client; only its hash is stored
+pub(crate) fn generate_opaque_token() -> String {
+    URL_SAFE_NO_PAD.encode(random_bytes(32))
+}
+
+pub(crate) fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signer(key: &[u8]) -> TokenSigner {
+        TokenSigner { key: key.to_vec(), access_ttl_secs: 60, refresh_ttl_secs: 120 }
+    }
+
+    fn encode_json<T: Serialize>(value: &T) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(value).unwrap())
+    }
+
+    #[test]
+    fn hash_token_is_a_stable_sha256_hex_digest() {
+        assert_eq!(hash_token("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(hash_token("pat_x"), hash_token("pat_x"));
+        assert_ne!(hash_token("pat_x"), hash_token("pat_y"));
+    }
+
+    #[test]
+    fn opaque_tokens_are_random_and_url_safe() {
+        let (a, b) = (generate_opaque_token(), generate_opaque_token());
+        assert_ne!(a, b);
+        assert_eq!(a.len(), 43);
+        assert!(a.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+    }
+
+    #[test]
+    fn access_tokens_verify_with_their_claims() {
+        let signer = signer(b"key");
+        let user_id = Uuid::new_v4();
+        let token = signer.issue_access_token(user_id, API_AUDIENCE, 1_700_000_000, Some(vec!["polls:read".to_string()]));
+        let claims = signer.verify_access_token(&token, API_AUDIENCE).unwrap();
+        assert_eq!(claims.sub, user_id);
+        assert_eq!(claims.auth_time, 1_700_000_000);
+        assert_eq!(claims.scope, Some(vec!["polls:read".to_string()]));
+        assert_eq!(claims.exp - claims.iat, 60);
+    }
+
+    #[test]
+    fn access_tokens_from_another_key_are_rejected() {
+        let token = signer(b"other key").issue_access_token(Uuid::new_v4(), API_AUDIENCE, 0, None);
+        assert!(matches!(signer(b"key").verify_access_token(&token, API_AUDIENCE), Err(TokenError::InvalidSignature)));
+    }
+
+    #[test]
+    fn tampered_claims_are_rejected() {
+        let signer = signer(b"key");
+        let token = signer.issue_access_token(Uuid::new_v4(), API_AUDIENCE, 0, Some(vec!["polls:read".to_string()]));
+        let parts: Vec<&str> = token.split('.').collect();
+        let mut claims: AccessClaims = decode_json(parts[1]).unwrap();
+        claims.scope = None;
+        let widened = format!("{}.{}.{}", parts[0], encode_json(&claims), parts[2]);
+        assert!(matches!(signer.verify_access_token(&widened, API_AUDIENCE), Err(TokenError::InvalidSignature)));
+    }
+
+    #[test]
+    fn unsigned_and_malformed_tokens_are_rejected() {
+        let signer = signer(b"key");
+        let token = signer.issue_access_token(Uuid::new_v4(), API_AUDIENCE, 0, None);
+        let parts: Vec<&str> = token.split('.').collect();
+        let none = encode_json(&Header { alg: "none".to_string(), typ: "JWT".to_string() });
+        assert!(matches!(signer.verify_access_token(&format!("{}.{}.", none, parts[1]), API_AUDIENCE), Err(TokenError::Malformed)));
+        assert!(matches!(signer.verify_access_token("not a token", API_AUDIENCE), Err(TokenError::Malformed)));
+        assert!(matches!(signer.verify_access_token("a.b.c", API_AUDIENCE), Err(TokenError::Malformed)));
+    }
+
+    #[test]
+    fn access_tokens_only_verify_for_their_audience() {
+        let signer = signer(b"key");
+        let token = signer.issue_access_token(Uuid::new_v4(), USERINFO_AUDIENCE, 0, Some(vec!["openid".to_string()]));
+        assert!(matches!(signer.verify_access_token(&token, API_AUDIENCE), Err(TokenError::WrongAudience)));
+        assert!(signer.verify_access_token(&token, USERINFO_AUDIENCE).is_ok());
+    }
+
+    #[test]
+    fn expired_access_tokens_are_rejected() {
+        let signer = TokenSigner { access_ttl_secs: -1, ..signer(b"key") };
+        let token = signer.issue_access_token(Uuid::new_v4(), API_AUDIENCE, 0, None);
+        assert!(matches!(signer.verify_access_token(&token, API_AUDIENCE), Err(TokenError::Expired)));
+    }
+}
@@ -1,17 +1,13 @@
 use std::{
-    io,
     sync::Arc,
     time::{Duration, Instant},
 };
 
-use actix_web::{
-    middleware::Logger, web, web::Html, App, HttpRequest, HttpResponse, HttpServer, Responder,
-};
+use actix_web::{web, HttpRequest, HttpResponse};
 use actix_ws::{AggregatedMessage, Session};
 use bytestring::ByteString;
 use futures_util::{stream::FuturesUnordered, StreamExt as _};
 use tokio::sync::Mutex;
-use webauthn_rs_core::proto;
 
 #[derive(Clone)]
 pub struct Chat {
@@ -95,10 +91,8 @@ pub async fn ws(
     actix_web::rt::spawn(async move {
         while let Some(Ok(msg)) = stream.recv().await {
             match msg {
-                AggregatedMessage::Ping(bytes) => {
-                    if session.pong(&bytes).await.is_err() {
-                        return;
-                    }
+                AggregatedMessage::Ping(bytes) if session.pong(&bytes).await.is_err() => {
+                    return;
                 }
 
                 AggregatedMessage::Text(string) => {